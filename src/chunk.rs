@@ -0,0 +1,371 @@
+use std::{
+    collections::HashMap,
+    fmt::{self, Write},
+    rc::Rc,
+};
+
+use crate::lex::Span;
+
+/// Instructions of the virtual machine. Operands are stored right after the opcode,
+/// 16-bit ones in big-endian.
+#[derive(Debug, Clone, Copy, PartialEq)]
+#[repr(u8)]
+pub enum OpCode {
+    /// Push the constant at the 16-bit index.
+    Constant,
+    Nil,
+    True,
+    False,
+    Pop,
+    /// Push the local at the 8-bit slot, relative to the frame.
+    GetLocal,
+    SetLocal,
+    /// Operand: the 16-bit index of the name in the constants.
+    GetGlobal,
+    DefineGlobal,
+    SetGlobal,
+    /// Operand: the 8-bit index of the upvalue in the closure.
+    GetUpvalue,
+    SetUpvalue,
+    /// Operand: the 16-bit index of the property name in the constants.
+    GetProperty,
+    SetProperty,
+    /// Pop the superclass and bind its method to the instance below it.
+    GetSuper,
+    Equal,
+    Greater,
+    GreaterEqual,
+    Less,
+    LessEqual,
+    Add,
+    Subtract,
+    Multiply,
+    Divide,
+    Not,
+    Negate,
+    Print,
+    /// Jump forward by the 16-bit offset.
+    Jump,
+    JumpIfFalse,
+    /// Jump backward by the 16-bit offset.
+    Loop,
+    /// Operand: the 8-bit number of arguments.
+    Call,
+    /// Operands: the 16-bit index of the function, then for each of its upvalues one
+    /// byte telling if it captures a local of the enclosing function, and its 8-bit index.
+    Closure,
+    /// Move the local on top of the stack to the heap, then pop it.
+    CloseUpvalue,
+    Return,
+    Class,
+    /// Copy the methods of the superclass into the class on top of the stack.
+    Inherit,
+    Method,
+}
+
+impl TryFrom<u8> for OpCode {
+    /// The byte, when it isn't an opcode.
+    type Error = u8;
+
+    fn try_from(byte: u8) -> Result<Self, Self::Error> {
+        let op = match byte {
+            0 => OpCode::Constant,
+            1 => OpCode::Nil,
+            2 => OpCode::True,
+            3 => OpCode::False,
+            4 => OpCode::Pop,
+            5 => OpCode::GetLocal,
+            6 => OpCode::SetLocal,
+            7 => OpCode::GetGlobal,
+            8 => OpCode::DefineGlobal,
+            9 => OpCode::SetGlobal,
+            10 => OpCode::GetUpvalue,
+            11 => OpCode::SetUpvalue,
+            12 => OpCode::GetProperty,
+            13 => OpCode::SetProperty,
+            14 => OpCode::GetSuper,
+            15 => OpCode::Equal,
+            16 => OpCode::Greater,
+            17 => OpCode::GreaterEqual,
+            18 => OpCode::Less,
+            19 => OpCode::LessEqual,
+            20 => OpCode::Add,
+            21 => OpCode::Subtract,
+            22 => OpCode::Multiply,
+            23 => OpCode::Divide,
+            24 => OpCode::Not,
+            25 => OpCode::Negate,
+            26 => OpCode::Print,
+            27 => OpCode::Jump,
+            28 => OpCode::JumpIfFalse,
+            29 => OpCode::Loop,
+            30 => OpCode::Call,
+            31 => OpCode::Closure,
+            32 => OpCode::CloseUpvalue,
+            33 => OpCode::Return,
+            34 => OpCode::Class,
+            35 => OpCode::Inherit,
+            36 => OpCode::Method,
+            _ => return Err(byte),
+        };
+        debug_assert_eq!(op as u8, byte, "the opcodes are numbered in order");
+        Ok(op)
+    }
+}
+
+impl OpCode {
+    /// Name of the instruction in the disassembly, as in clox.
+    pub fn name(self) -> &'static str {
+        match self {
+            OpCode::Constant => "OP_CONSTANT",
+            OpCode::Nil => "OP_NIL",
+            OpCode::True => "OP_TRUE",
+            OpCode::False => "OP_FALSE",
+            OpCode::Pop => "OP_POP",
+            OpCode::GetLocal => "OP_GET_LOCAL",
+            OpCode::SetLocal => "OP_SET_LOCAL",
+            OpCode::GetGlobal => "OP_GET_GLOBAL",
+            OpCode::DefineGlobal => "OP_DEFINE_GLOBAL",
+            OpCode::SetGlobal => "OP_SET_GLOBAL",
+            OpCode::GetUpvalue => "OP_GET_UPVALUE",
+            OpCode::SetUpvalue => "OP_SET_UPVALUE",
+            OpCode::GetProperty => "OP_GET_PROPERTY",
+            OpCode::SetProperty => "OP_SET_PROPERTY",
+            OpCode::GetSuper => "OP_GET_SUPER",
+            OpCode::Equal => "OP_EQUAL",
+            OpCode::Greater => "OP_GREATER",
+            OpCode::GreaterEqual => "OP_GREATER_EQUAL",
+            OpCode::Less => "OP_LESS",
+            OpCode::LessEqual => "OP_LESS_EQUAL",
+            OpCode::Add => "OP_ADD",
+            OpCode::Subtract => "OP_SUBTRACT",
+            OpCode::Multiply => "OP_MULTIPLY",
+            OpCode::Divide => "OP_DIVIDE",
+            OpCode::Not => "OP_NOT",
+            OpCode::Negate => "OP_NEGATE",
+            OpCode::Print => "OP_PRINT",
+            OpCode::Jump => "OP_JUMP",
+            OpCode::JumpIfFalse => "OP_JUMP_IF_FALSE",
+            OpCode::Loop => "OP_LOOP",
+            OpCode::Call => "OP_CALL",
+            OpCode::Closure => "OP_CLOSURE",
+            OpCode::CloseUpvalue => "OP_CLOSE_UPVALUE",
+            OpCode::Return => "OP_RETURN",
+            OpCode::Class => "OP_CLASS",
+            OpCode::Inherit => "OP_INHERIT",
+            OpCode::Method => "OP_METHOD",
+        }
+    }
+}
+
+/// A value known at compile time.
+#[derive(Debug, Clone)]
+pub enum Constant {
+    Number(f64),
+    String(Rc<str>),
+    Function(Rc<Function>),
+}
+
+impl fmt::Display for Constant {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Constant::Number(number) => write!(f, "{number}"),
+            Constant::String(string) => write!(f, "{string}"),
+            Constant::Function(function) if function.name.is_empty() => write!(f, "<script>"),
+            Constant::Function(function) => write!(f, "<fn {}>", function.name),
+        }
+    }
+}
+
+/// A compiled function. The top-level code of a program is a function without name.
+#[derive(Debug, Default)]
+pub struct Function {
+    pub name: Rc<str>,
+    pub arity: usize,
+    pub upvalue_count: usize,
+    pub chunk: Chunk,
+}
+
+impl Function {
+    /// Human-readable listing of the instructions of the function, followed by the ones
+    /// of the functions it declares.
+    pub fn disassemble(&self) -> String {
+        let mut output = String::new();
+        self.disassemble_into(&mut output)
+            .expect("writing to a string can't fail");
+        output
+    }
+
+    fn disassemble_into(&self, output: &mut String) -> fmt::Result {
+        let name = if self.name.is_empty() {
+            "<script>"
+        } else {
+            &self.name
+        };
+        writeln!(output, "== {name} ==")?;
+        let mut offset = 0;
+        while offset < self.chunk.code.len() {
+            offset = self.chunk.disassemble_instruction(offset, output)?;
+        }
+        for constant in &self.chunk.constants {
+            if let Constant::Function(function) = constant {
+                writeln!(output)?;
+                function.disassemble_into(output)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+/// A sequence of instructions along with the constants they use.
+#[derive(Debug, Default)]
+pub struct Chunk {
+    pub code: Vec<u8>,
+    pub constants: Vec<Constant>,
+    /// The location of the code each instruction comes from, stored only when it
+    /// changes: the span applies from its offset to the next entry.
+    spans: Vec<(usize, Span)>,
+    /// Index of the numbers and strings in the constants, to reuse them.
+    constant_indices: HashMap<ConstantKey, usize>,
+}
+
+/// The constants which are deduplicated, numbers compared by their bits.
+#[derive(Debug, PartialEq, Eq, Hash)]
+enum ConstantKey {
+    Number(u64),
+    String(Rc<str>),
+}
+
+impl Chunk {
+    pub fn write(&mut self, byte: u8, span: Span) {
+        if self.spans.last().map_or(true, |(_, last)| *last != span) {
+            self.spans.push((self.code.len(), span));
+        }
+        self.code.push(byte);
+    }
+
+    pub fn write_op(&mut self, op: OpCode, span: Span) {
+        self.write(op as u8, span);
+    }
+
+    pub fn write_u16(&mut self, value: u16, span: Span) {
+        for byte in value.to_be_bytes() {
+            self.write(byte, span);
+        }
+    }
+
+    pub fn read_u16(&self, offset: usize) -> u16 {
+        u16::from_be_bytes([self.code[offset], self.code[offset + 1]])
+    }
+
+    /// Add a constant, reusing an identical one if there is one, and return its index.
+    pub fn add_constant(&mut self, constant: Constant) -> usize {
+        let key = match &constant {
+            Constant::Number(number) => ConstantKey::Number(number.to_bits()),
+            Constant::String(string) => ConstantKey::String(Rc::clone(string)),
+            Constant::Function(_) => return self.push_constant(constant),
+        };
+        if let Some(index) = self.constant_indices.get(&key) {
+            return *index;
+        }
+        let index = self.push_constant(constant);
+        self.constant_indices.insert(key, index);
+        index
+    }
+
+    fn push_constant(&mut self, constant: Constant) -> usize {
+        self.constants.push(constant);
+        self.constants.len() - 1
+    }
+
+    /// Location of the code of the instruction at `offset`.
+    pub fn span_at(&self, offset: usize) -> Span {
+        let index = self.spans.partition_point(|(start, _)| *start <= offset);
+        index
+            .checked_sub(1)
+            .map_or(Span::default(), |index| self.spans[index].1)
+    }
+
+    /// Write the instruction at `offset` like clox's `disassembleInstruction`, and return
+    /// the offset of the next one.
+    pub fn disassemble_instruction(
+        &self,
+        offset: usize,
+        output: &mut String,
+    ) -> Result<usize, fmt::Error> {
+        write!(output, "{offset:04} ")?;
+        let line = self.span_at(offset).line;
+        if offset > 0 && self.span_at(offset - 1).line == line {
+            write!(output, "   | ")?;
+        } else {
+            write!(output, "{line:4} ")?;
+        }
+
+        let Ok(op) = OpCode::try_from(self.code[offset]) else {
+            writeln!(output, "Unknown opcode {}", self.code[offset])?;
+            return Ok(offset + 1);
+        };
+        let name = op.name();
+        match op {
+            OpCode::Constant
+            | OpCode::GetGlobal
+            | OpCode::DefineGlobal
+            | OpCode::SetGlobal
+            | OpCode::GetProperty
+            | OpCode::SetProperty
+            | OpCode::GetSuper
+            | OpCode::Class
+            | OpCode::Method => {
+                let index = self.read_u16(offset + 1);
+                let constant = &self.constants[usize::from(index)];
+                writeln!(output, "{name:<16} {index:4} '{constant}'")?;
+                Ok(offset + 3)
+            }
+            OpCode::GetLocal
+            | OpCode::SetLocal
+            | OpCode::GetUpvalue
+            | OpCode::SetUpvalue
+            | OpCode::Call => {
+                let operand = self.code[offset + 1];
+                writeln!(output, "{name:<16} {operand:4}")?;
+                Ok(offset + 2)
+            }
+            OpCode::Jump | OpCode::JumpIfFalse | OpCode::Loop => {
+                let jump = usize::from(self.read_u16(offset + 1));
+                let target = if op == OpCode::Loop {
+                    offset + 3 - jump
+                } else {
+                    offset + 3 + jump
+                };
+                writeln!(output, "{name:<16} {offset:4} -> {target}")?;
+                Ok(offset + 3)
+            }
+            OpCode::Closure => {
+                let index = self.read_u16(offset + 1);
+                let constant = &self.constants[usize::from(index)];
+                writeln!(output, "{name:<16} {index:4} {constant}")?;
+                let mut offset = offset + 3;
+                if let Constant::Function(function) = constant {
+                    for _ in 0..function.upvalue_count {
+                        let kind = if self.code[offset] == 1 {
+                            "local"
+                        } else {
+                            "upvalue"
+                        };
+                        let index = self.code[offset + 1];
+                        writeln!(
+                            output,
+                            "{offset:04}    |                     {kind} {index}"
+                        )?;
+                        offset += 2;
+                    }
+                }
+                Ok(offset)
+            }
+            _ => {
+                writeln!(output, "{name}")?;
+                Ok(offset + 1)
+            }
+        }
+    }
+}
@@ -0,0 +1,655 @@
+use std::{fmt, rc::Rc};
+
+use crate::{
+    chunk::{self, Chunk, Constant, OpCode},
+    diagnostic::Diagnostic,
+    intern::intern,
+    interpret::{parse_source, RunError},
+    lex::{Span, Spanned},
+    parse::{
+        Comparison, Equality, ExpressionTree, Factor, Function, Logical, Primary, StatementTree,
+        Term, Unary,
+    },
+};
+
+/// Lex, parse, resolve and compile a whole program.
+pub fn compile_source(source: &str) -> Result<Rc<chunk::Function>, RunError> {
+    let statements = parse_source(source)?;
+    compile(&statements).map_err(RunError::Compile)
+}
+
+/// Compile a resolved program into the function executed by the virtual machine.
+///
+/// Local variables live in the slots of the stack of the virtual machine, and the ones
+/// captured by closures are moved to the heap when they go out of scope (upvalues), like
+/// in clox. The resolver must have run first, the compiler relies on it for the errors
+/// it can detect.
+pub fn compile(statements: &[Spanned<StatementTree>]) -> Result<Rc<chunk::Function>, CompileError> {
+    let mut compiler = Compiler {
+        functions: vec![FunctionCompiler::new(intern(""), FunctionKind::Script)],
+    };
+    for statement in statements {
+        compiler.statement(statement)?;
+    }
+    let span = statements
+        .last()
+        .map_or(Span::default(), |statement| statement.span);
+    Ok(Rc::new(compiler.end_function(span)?.0))
+}
+
+struct Compiler {
+    /// The functions being compiled, from the script to the innermost one.
+    functions: Vec<FunctionCompiler>,
+}
+
+struct FunctionCompiler {
+    function: chunk::Function,
+    kind: FunctionKind,
+    /// The local variables in scope, by stack slot.
+    locals: Vec<Local>,
+    upvalues: Vec<Upvalue>,
+    scope_depth: usize,
+}
+
+impl FunctionCompiler {
+    fn new(name: Rc<str>, kind: FunctionKind) -> Self {
+        // The first slot holds the function being called, or the instance in a method.
+        let receiver = match kind {
+            FunctionKind::Method | FunctionKind::Initializer => intern("this"),
+            FunctionKind::Script | FunctionKind::Function => intern(""),
+        };
+        Self {
+            function: chunk::Function {
+                name,
+                ..Default::default()
+            },
+            kind,
+            locals: vec![Local {
+                name: receiver,
+                depth: Some(0),
+                is_captured: false,
+            }],
+            upvalues: Vec::new(),
+            scope_depth: 0,
+        }
+    }
+}
+
+#[derive(Clone, Copy, PartialEq)]
+enum FunctionKind {
+    Script,
+    Function,
+    Method,
+    Initializer,
+}
+
+struct Local {
+    name: Rc<str>,
+    /// Depth of the scope of the variable, `None` while its initializer is compiled.
+    depth: Option<usize>,
+    /// Whether a closure refers to it, so it must be moved to the heap at the end of
+    /// its scope.
+    is_captured: bool,
+}
+
+#[derive(Clone, Copy, PartialEq)]
+struct Upvalue {
+    /// Slot of the local in the enclosing function, or index of its upvalue.
+    index: u8,
+    is_local: bool,
+}
+
+/// How a variable is accessed.
+enum Target {
+    Local(u8),
+    Upvalue(u8),
+    Global(u16),
+}
+
+impl Compiler {
+    fn current(&mut self) -> &mut FunctionCompiler {
+        self.functions
+            .last_mut()
+            .expect("the script function is never removed")
+    }
+
+    fn chunk(&mut self) -> &mut Chunk {
+        &mut self.current().function.chunk
+    }
+
+    fn emit(&mut self, op: OpCode, span: Span) {
+        self.chunk().write_op(op, span);
+    }
+
+    fn emit_byte(&mut self, op: OpCode, operand: u8, span: Span) {
+        self.emit(op, span);
+        self.chunk().write(operand, span);
+    }
+
+    fn emit_u16(&mut self, op: OpCode, operand: u16, span: Span) {
+        self.emit(op, span);
+        self.chunk().write_u16(operand, span);
+    }
+
+    fn constant(&mut self, constant: Constant, span: Span) -> Result<u16, CompileError> {
+        let index = self.chunk().add_constant(constant);
+        u16::try_from(index)
+            .map_err(|_| CompileError::new(CompileErrorKind::TooManyConstants, span))
+    }
+
+    fn name_constant(&mut self, name: &Rc<str>, span: Span) -> Result<u16, CompileError> {
+        self.constant(Constant::String(Rc::clone(name)), span)
+    }
+
+    /// Emit a jump with a placeholder offset, to be patched once the target is known.
+    fn emit_jump(&mut self, op: OpCode, span: Span) -> usize {
+        self.emit_u16(op, u16::MAX, span);
+        self.chunk().code.len() - 2
+    }
+
+    /// Make the jump at `offset` land on the next instruction.
+    fn patch_jump(&mut self, offset: usize, span: Span) -> Result<(), CompileError> {
+        let chunk = self.chunk();
+        let jump = u16::try_from(chunk.code.len() - offset - 2)
+            .map_err(|_| CompileError::new(CompileErrorKind::JumpTooLarge, span))?;
+        chunk.code[offset..offset + 2].copy_from_slice(&jump.to_be_bytes());
+        Ok(())
+    }
+
+    fn emit_loop(&mut self, loop_start: usize, span: Span) -> Result<(), CompileError> {
+        // The offset also skips the operand of the loop instruction.
+        let offset = u16::try_from(self.chunk().code.len() - loop_start + 3)
+            .map_err(|_| CompileError::new(CompileErrorKind::LoopTooLarge, span))?;
+        self.emit_u16(OpCode::Loop, offset, span);
+        Ok(())
+    }
+
+    fn begin_scope(&mut self) {
+        self.current().scope_depth += 1;
+    }
+
+    fn end_scope(&mut self, span: Span) {
+        let function = self.current();
+        function.scope_depth -= 1;
+        let depth = function.scope_depth;
+        while function
+            .locals
+            .last()
+            .is_some_and(|local| local.depth.map_or(true, |local_depth| local_depth > depth))
+        {
+            let Some(local) = function.locals.pop() else {
+                break;
+            };
+            let op = if local.is_captured {
+                OpCode::CloseUpvalue
+            } else {
+                OpCode::Pop
+            };
+            function.function.chunk.write_op(op, span);
+        }
+    }
+
+    fn add_local(&mut self, name: &Rc<str>, span: Span) -> Result<(), CompileError> {
+        let function = self.current();
+        if function.locals.len() > usize::from(u8::MAX) {
+            return Err(CompileError::new(CompileErrorKind::TooManyLocals, span));
+        }
+        function.locals.push(Local {
+            name: Rc::clone(name),
+            depth: None,
+            is_captured: false,
+        });
+        Ok(())
+    }
+
+    /// Mark the last local as usable, now that its value is computed.
+    fn initialize_local(&mut self) {
+        let function = self.current();
+        if let Some(local) = function.locals.last_mut() {
+            local.depth = Some(function.scope_depth);
+        }
+    }
+
+    /// Declare a variable whose value is about to be pushed. Locals are ready to be
+    /// initialized, globals return the index of their name.
+    fn declare_variable(
+        &mut self,
+        name: &Rc<str>,
+        span: Span,
+    ) -> Result<Option<u16>, CompileError> {
+        if self.current().scope_depth > 0 {
+            self.add_local(name, span)?;
+            Ok(None)
+        } else {
+            self.name_constant(name, span).map(Some)
+        }
+    }
+
+    /// The value of the variable is on top of the stack.
+    fn define_variable(&mut self, global: Option<u16>, span: Span) {
+        match global {
+            Some(global) => self.emit_u16(OpCode::DefineGlobal, global, span),
+            // The value stays on the stack, in the slot of the local.
+            None => self.initialize_local(),
+        }
+    }
+
+    fn resolve_local(&self, function: usize, name: &str) -> Option<u8> {
+        self.functions[function]
+            .locals
+            .iter()
+            .rposition(|local| &*local.name == name)
+            .map(|slot| slot as u8)
+    }
+
+    /// Look for the variable in the enclosing functions, capturing it along the way.
+    fn resolve_upvalue(
+        &mut self,
+        function: usize,
+        name: &str,
+        span: Span,
+    ) -> Result<Option<u8>, CompileError> {
+        let Some(enclosing) = function.checked_sub(1) else {
+            return Ok(None);
+        };
+        if let Some(slot) = self.resolve_local(enclosing, name) {
+            self.functions[enclosing].locals[usize::from(slot)].is_captured = true;
+            return self.add_upvalue(function, slot, true, span).map(Some);
+        }
+        match self.resolve_upvalue(enclosing, name, span)? {
+            Some(index) => self.add_upvalue(function, index, false, span).map(Some),
+            None => Ok(None),
+        }
+    }
+
+    fn add_upvalue(
+        &mut self,
+        function: usize,
+        index: u8,
+        is_local: bool,
+        span: Span,
+    ) -> Result<u8, CompileError> {
+        let upvalue = Upvalue { index, is_local };
+        let compiler = &mut self.functions[function];
+        let upvalues = &mut compiler.upvalues;
+        if let Some(existing) = upvalues.iter().position(|other| *other == upvalue) {
+            return Ok(existing as u8);
+        }
+        if upvalues.len() > usize::from(u8::MAX) {
+            return Err(CompileError::new(CompileErrorKind::TooManyUpvalues, span));
+        }
+        upvalues.push(upvalue);
+        compiler.function.upvalue_count = upvalues.len();
+        Ok((upvalues.len() - 1) as u8)
+    }
+
+    fn resolve_variable(&mut self, name: &Rc<str>, span: Span) -> Result<Target, CompileError> {
+        let current = self.functions.len() - 1;
+        if let Some(slot) = self.resolve_local(current, name) {
+            return Ok(Target::Local(slot));
+        }
+        if let Some(index) = self.resolve_upvalue(current, name, span)? {
+            return Ok(Target::Upvalue(index));
+        }
+        self.name_constant(name, span).map(Target::Global)
+    }
+
+    fn get_variable(&mut self, name: &Rc<str>, span: Span) -> Result<(), CompileError> {
+        match self.resolve_variable(name, span)? {
+            Target::Local(slot) => self.emit_byte(OpCode::GetLocal, slot, span),
+            Target::Upvalue(index) => self.emit_byte(OpCode::GetUpvalue, index, span),
+            Target::Global(name) => self.emit_u16(OpCode::GetGlobal, name, span),
+        }
+        Ok(())
+    }
+
+    fn set_variable(&mut self, name: &Rc<str>, span: Span) -> Result<(), CompileError> {
+        match self.resolve_variable(name, span)? {
+            Target::Local(slot) => self.emit_byte(OpCode::SetLocal, slot, span),
+            Target::Upvalue(index) => self.emit_byte(OpCode::SetUpvalue, index, span),
+            Target::Global(name) => self.emit_u16(OpCode::SetGlobal, name, span),
+        }
+        Ok(())
+    }
+
+    /// Finish the current function, returning it with the upvalues it captures.
+    fn end_function(
+        &mut self,
+        span: Span,
+    ) -> Result<(chunk::Function, Vec<Upvalue>), CompileError> {
+        self.emit_implicit_return(span);
+        let function = self.functions.pop().expect("a function is being compiled");
+        Ok((function.function, function.upvalues))
+    }
+
+    fn emit_implicit_return(&mut self, span: Span) {
+        if self.current().kind == FunctionKind::Initializer {
+            self.emit_byte(OpCode::GetLocal, 0, span);
+        } else {
+            self.emit(OpCode::Nil, span);
+        }
+        self.emit(OpCode::Return, span);
+    }
+
+    fn statement(&mut self, statement: &Spanned<StatementTree>) -> Result<(), CompileError> {
+        let span = statement.span;
+        match &statement.node {
+            StatementTree::Print(expr) => {
+                self.expr(expr)?;
+                self.emit(OpCode::Print, span);
+            }
+            StatementTree::Expr(expr) => {
+                self.expr(expr)?;
+                self.emit(OpCode::Pop, span);
+            }
+            StatementTree::VarDeclaration { ident, expr } => {
+                let global = self.declare_variable(ident, span)?;
+                match expr {
+                    Some(expr) => self.expr(expr)?,
+                    None => self.emit(OpCode::Nil, span),
+                }
+                self.define_variable(global, span);
+            }
+            StatementTree::Block(statements) => {
+                self.begin_scope();
+                for statement in statements {
+                    self.statement(statement)?;
+                }
+                self.end_scope(span);
+            }
+            StatementTree::If {
+                condition,
+                then_branch,
+                else_branch,
+            } => {
+                self.expr(condition)?;
+                let then_jump = self.emit_jump(OpCode::JumpIfFalse, span);
+                self.emit(OpCode::Pop, span);
+                self.statement(then_branch)?;
+                let else_jump = self.emit_jump(OpCode::Jump, span);
+                self.patch_jump(then_jump, span)?;
+                self.emit(OpCode::Pop, span);
+                if let Some(else_branch) = else_branch {
+                    self.statement(else_branch)?;
+                }
+                self.patch_jump(else_jump, span)?;
+            }
+            StatementTree::While { condition, body } => {
+                let loop_start = self.chunk().code.len();
+                self.expr(condition)?;
+                let exit_jump = self.emit_jump(OpCode::JumpIfFalse, span);
+                self.emit(OpCode::Pop, span);
+                self.statement(body)?;
+                self.emit_loop(loop_start, span)?;
+                self.patch_jump(exit_jump, span)?;
+                self.emit(OpCode::Pop, span);
+            }
+            StatementTree::Function(function) => {
+                let global = self.declare_variable(&function.name, span)?;
+                // The function can refer to itself in its body.
+                if global.is_none() {
+                    self.initialize_local();
+                }
+                self.function(function, FunctionKind::Function)?;
+                self.define_variable(global, span);
+            }
+            StatementTree::Return(expr) => {
+                match expr {
+                    // An initializer always returns the instance, the resolver makes sure
+                    // no other value is returned.
+                    None => self.emit_implicit_return(span),
+                    Some(expr) => {
+                        self.expr(expr)?;
+                        self.emit(OpCode::Return, span);
+                    }
+                }
+            }
+            StatementTree::Class {
+                name,
+                superclass,
+                methods,
+            } => {
+                let name_constant = self.name_constant(name, span)?;
+                let global = self.declare_variable(name, span)?;
+                self.emit_u16(OpCode::Class, name_constant, span);
+                self.define_variable(global, span);
+
+                // The methods of a subclass capture the superclass in a local named `super`.
+                if let Some(superclass) = superclass {
+                    self.expr(superclass)?;
+                    self.begin_scope();
+                    self.add_local(&intern("super"), span)?;
+                    self.initialize_local();
+                    self.get_variable(name, span)?;
+                    self.emit(OpCode::Inherit, superclass.span);
+                }
+
+                self.get_variable(name, span)?;
+                for method in methods {
+                    let kind = if &*method.name == "init" {
+                        FunctionKind::Initializer
+                    } else {
+                        FunctionKind::Method
+                    };
+                    let method_name = self.name_constant(&method.name, method.span)?;
+                    self.function(method, kind)?;
+                    self.emit_u16(OpCode::Method, method_name, method.span);
+                }
+                self.emit(OpCode::Pop, span);
+
+                if superclass.is_some() {
+                    self.end_scope(span);
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Compile the function, and emit the instruction creating its closure.
+    fn function(&mut self, function: &Function, kind: FunctionKind) -> Result<(), CompileError> {
+        let span = function.span;
+        self.functions
+            .push(FunctionCompiler::new(Rc::clone(&function.name), kind));
+        // The parameters and the body share the scope of the function.
+        self.begin_scope();
+        self.current().function.arity = function.params.len();
+        for param in &function.params {
+            self.add_local(&param.node, param.span)?;
+            self.initialize_local();
+        }
+        for statement in &function.body {
+            self.statement(statement)?;
+        }
+        // No need to end the scope, the frame is discarded when returning.
+        let (compiled, upvalues) = self.end_function(span)?;
+
+        let index = self.constant(Constant::Function(Rc::new(compiled)), span)?;
+        self.emit_u16(OpCode::Closure, index, span);
+        for upvalue in upvalues {
+            self.chunk().write(u8::from(upvalue.is_local), span);
+            self.chunk().write(upvalue.index, span);
+        }
+        Ok(())
+    }
+
+    fn expr(&mut self, expr: &Spanned<ExpressionTree>) -> Result<(), CompileError> {
+        let span = expr.span;
+        match &expr.node {
+            ExpressionTree::Primary(primary) => match primary {
+                Primary::String(string) => {
+                    let index = self.constant(Constant::String(Rc::clone(string)), span)?;
+                    self.emit_u16(OpCode::Constant, index, span);
+                }
+                Primary::Number(number) => {
+                    let index = self.constant(Constant::Number(*number), span)?;
+                    self.emit_u16(OpCode::Constant, index, span);
+                }
+                Primary::True => self.emit(OpCode::True, span),
+                Primary::False => self.emit(OpCode::False, span),
+                Primary::Nil => self.emit(OpCode::Nil, span),
+                Primary::Group(expr) => self.expr(expr)?,
+                Primary::Identifier(variable) | Primary::This(variable) => {
+                    self.get_variable(&variable.name, span)?
+                }
+                Primary::Super(_, method) => {
+                    let method = self.name_constant(method, span)?;
+                    self.get_variable(&intern("this"), span)?;
+                    self.get_variable(&intern("super"), span)?;
+                    self.emit_u16(OpCode::GetSuper, method, span);
+                }
+            },
+            ExpressionTree::Unary(unary) => match unary {
+                Unary::Bang(operand) => {
+                    self.expr(operand)?;
+                    self.emit(OpCode::Not, span);
+                }
+                Unary::Minus(operand) => {
+                    self.expr(operand)?;
+                    self.emit(OpCode::Negate, span);
+                }
+            },
+            ExpressionTree::Factor(Factor::Slash(lhs, rhs)) => {
+                self.binary(lhs, rhs, OpCode::Divide, span)?
+            }
+            ExpressionTree::Factor(Factor::Star(lhs, rhs)) => {
+                self.binary(lhs, rhs, OpCode::Multiply, span)?
+            }
+            ExpressionTree::Term(Term::Minus(lhs, rhs)) => {
+                self.binary(lhs, rhs, OpCode::Subtract, span)?
+            }
+            ExpressionTree::Term(Term::Plus(lhs, rhs)) => {
+                self.binary(lhs, rhs, OpCode::Add, span)?
+            }
+            ExpressionTree::Comparison(comparison) => match comparison {
+                Comparison::Less(lhs, rhs) => self.binary(lhs, rhs, OpCode::Less, span)?,
+                Comparison::LessEqual(lhs, rhs) => {
+                    self.binary(lhs, rhs, OpCode::LessEqual, span)?
+                }
+                Comparison::Greater(lhs, rhs) => self.binary(lhs, rhs, OpCode::Greater, span)?,
+                Comparison::GreaterEqual(lhs, rhs) => {
+                    self.binary(lhs, rhs, OpCode::GreaterEqual, span)?
+                }
+            },
+            ExpressionTree::Equality(Equality::EqualEqual(lhs, rhs)) => {
+                self.binary(lhs, rhs, OpCode::Equal, span)?
+            }
+            ExpressionTree::Equality(Equality::BangEqual(lhs, rhs)) => {
+                self.binary(lhs, rhs, OpCode::Equal, span)?;
+                self.emit(OpCode::Not, span);
+            }
+            ExpressionTree::Logical(Logical::And(lhs, rhs)) => {
+                self.expr(lhs)?;
+                let end_jump = self.emit_jump(OpCode::JumpIfFalse, span);
+                self.emit(OpCode::Pop, span);
+                self.expr(rhs)?;
+                self.patch_jump(end_jump, span)?;
+            }
+            ExpressionTree::Logical(Logical::Or(lhs, rhs)) => {
+                self.expr(lhs)?;
+                let else_jump = self.emit_jump(OpCode::JumpIfFalse, span);
+                let end_jump = self.emit_jump(OpCode::Jump, span);
+                self.patch_jump(else_jump, span)?;
+                self.emit(OpCode::Pop, span);
+                self.expr(rhs)?;
+                self.patch_jump(end_jump, span)?;
+            }
+            ExpressionTree::Assignment(variable, value) => {
+                self.expr(value)?;
+                self.set_variable(&variable.name, span)?;
+            }
+            ExpressionTree::Call { callee, arguments } => {
+                self.expr(callee)?;
+                for argument in arguments {
+                    self.expr(argument)?;
+                }
+                let count = u8::try_from(arguments.len()).expect("the parser limits the arguments");
+                self.emit_byte(OpCode::Call, count, span);
+            }
+            ExpressionTree::Get { object, name } => {
+                self.expr(object)?;
+                let name = self.name_constant(name, span)?;
+                self.emit_u16(OpCode::GetProperty, name, span);
+            }
+            ExpressionTree::Set {
+                object,
+                name,
+                value,
+            } => {
+                self.expr(object)?;
+                self.expr(value)?;
+                let name = self.name_constant(name, span)?;
+                self.emit_u16(OpCode::SetProperty, name, span);
+            }
+        }
+        Ok(())
+    }
+
+    fn binary(
+        &mut self,
+        lhs: &Spanned<ExpressionTree>,
+        rhs: &Spanned<ExpressionTree>,
+        op: OpCode,
+        span: Span,
+    ) -> Result<(), CompileError> {
+        self.expr(lhs)?;
+        self.expr(rhs)?;
+        self.emit(op, span);
+        Ok(())
+    }
+}
+
+#[derive(Debug)]
+pub struct CompileError {
+    kind: CompileErrorKind,
+    span: Span,
+}
+
+impl CompileError {
+    fn new(kind: CompileErrorKind, span: Span) -> Self {
+        Self { kind, span }
+    }
+
+    pub fn diagnostic(&self) -> Diagnostic {
+        Diagnostic::new(self.kind.code(), self.to_string(), self.span)
+    }
+}
+
+/// The limits of the bytecode format.
+#[derive(Debug)]
+pub enum CompileErrorKind {
+    TooManyConstants,
+    TooManyLocals,
+    TooManyUpvalues,
+    JumpTooLarge,
+    LoopTooLarge,
+}
+
+impl CompileErrorKind {
+    fn code(&self) -> &'static str {
+        match self {
+            CompileErrorKind::TooManyConstants => "E0501",
+            CompileErrorKind::TooManyLocals => "E0502",
+            CompileErrorKind::TooManyUpvalues => "E0503",
+            CompileErrorKind::JumpTooLarge => "E0506",
+            CompileErrorKind::LoopTooLarge => "E0507",
+        }
+    }
+}
+
+impl std::error::Error for CompileError {}
+
+impl fmt::Display for CompileError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let line = self.span.line;
+        write!(f, "[line {line}] Error: ")?;
+        match self.kind {
+            CompileErrorKind::TooManyConstants => write!(f, "Too many constants in one chunk."),
+            CompileErrorKind::TooManyLocals => write!(f, "Too many local variables in function."),
+            CompileErrorKind::TooManyUpvalues => {
+                write!(f, "Too many closure variables in function.")
+            }
+            CompileErrorKind::JumpTooLarge => write!(f, "Too much code to jump over."),
+            CompileErrorKind::LoopTooLarge => write!(f, "Loop body too large."),
+        }
+    }
+}
@@ -0,0 +1,581 @@
+//! Lossless concrete syntax tree, for the tools that need to preserve the formatting of
+//! the source: formatters, refactorings, language servers...
+//!
+//! Unlike the [`StatementTree`], the tree keeps every token, whitespace and comments
+//! included, so its text is exactly the source it was parsed from. Syntax errors don't
+//! stop the parsing either: the unexpected tokens are kept in [`SyntaxKind::Error`]
+//! nodes.
+//!
+//! Like in Roslyn or rust-analyzer, the tree has two layers. The green tree is
+//! immutable and doesn't know its position, so identical tokens are shared. The red
+//! tree ([`SyntaxNode`]) is built on demand on top of it, and knows the offsets and the
+//! parents.
+//!
+//! ```
+//! let parse = lox::cst::parse("var a = 1; // one\n");
+//! assert_eq!(parse.syntax().to_string(), "var a = 1; // one\n");
+//! assert_eq!(parse.statements().unwrap().len(), 1);
+//! ```
+
+mod lower;
+mod parser;
+
+use std::{collections::HashMap, fmt, ops::Range, rc::Rc};
+
+use crate::{
+    interpret::RunError,
+    lex::{Lexer, LexingError, Span, Spanned, Token},
+    parse::{ParseExpressionError, StatementTree},
+};
+
+/// The kinds of the tokens and of the nodes of the tree.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum SyntaxKind {
+    LeftParen,
+    RightParen,
+    LeftBrace,
+    RightBrace,
+    Comma,
+    Dot,
+    Minus,
+    Plus,
+    Semicolon,
+    Star,
+    EqualEqual,
+    Equal,
+    BangEqual,
+    Bang,
+    LessEqual,
+    Less,
+    GreaterEqual,
+    Greater,
+    Slash,
+    String,
+    Number,
+    Identifier,
+    And,
+    Class,
+    Else,
+    False,
+    For,
+    Fun,
+    If,
+    Nil,
+    Or,
+    Return,
+    Super,
+    This,
+    True,
+    Var,
+    While,
+    Print,
+    Eof,
+    Whitespace,
+    Comment,
+    DocComment,
+    /// Source that couldn't be lexed, reported as a [`LexingError`].
+    Unknown,
+
+    /// The whole source.
+    Program,
+    VarDecl,
+    /// `fun` followed by a [`SyntaxKind::Function`].
+    FunDecl,
+    /// The name, parameters and body of a function or a method.
+    Function,
+    ClassDecl,
+    Block,
+    PrintStmt,
+    ExprStmt,
+    IfStmt,
+    WhileStmt,
+    ForStmt,
+    ReturnStmt,
+    Literal,
+    NameExpr,
+    ThisExpr,
+    SuperExpr,
+    GroupExpr,
+    UnaryExpr,
+    /// Arithmetic, comparison and logical operators.
+    BinaryExpr,
+    AssignExpr,
+    CallExpr,
+    /// Property access, the target of an [`SyntaxKind::AssignExpr`] for a property
+    /// assignment.
+    GetExpr,
+    /// Tokens skipped by the parser after a syntax error.
+    Error,
+}
+
+impl SyntaxKind {
+    /// Whether the parser ignores this kind of token. The source that couldn't be lexed
+    /// is ignored too, its errors are reported by the lexer.
+    pub fn is_trivia(self) -> bool {
+        matches!(
+            self,
+            SyntaxKind::Whitespace
+                | SyntaxKind::Comment
+                | SyntaxKind::DocComment
+                | SyntaxKind::Unknown
+        )
+    }
+}
+
+impl From<&Token<'_>> for SyntaxKind {
+    fn from(token: &Token<'_>) -> Self {
+        match token {
+            Token::LeftParen => SyntaxKind::LeftParen,
+            Token::RightParen => SyntaxKind::RightParen,
+            Token::LeftBrace => SyntaxKind::LeftBrace,
+            Token::RightBrace => SyntaxKind::RightBrace,
+            Token::Comma => SyntaxKind::Comma,
+            Token::Dot => SyntaxKind::Dot,
+            Token::Minus => SyntaxKind::Minus,
+            Token::Plus => SyntaxKind::Plus,
+            Token::Semicolon => SyntaxKind::Semicolon,
+            Token::Star => SyntaxKind::Star,
+            Token::EqualEqual => SyntaxKind::EqualEqual,
+            Token::Equal => SyntaxKind::Equal,
+            Token::BangEqual => SyntaxKind::BangEqual,
+            Token::Bang => SyntaxKind::Bang,
+            Token::LessEqual => SyntaxKind::LessEqual,
+            Token::Less => SyntaxKind::Less,
+            Token::GreaterEqual => SyntaxKind::GreaterEqual,
+            Token::Greater => SyntaxKind::Greater,
+            Token::Slash => SyntaxKind::Slash,
+            Token::String(..) => SyntaxKind::String,
+            Token::Number(..) => SyntaxKind::Number,
+            Token::Identifier(_) => SyntaxKind::Identifier,
+            Token::And => SyntaxKind::And,
+            Token::Class => SyntaxKind::Class,
+            Token::Else => SyntaxKind::Else,
+            Token::False => SyntaxKind::False,
+            Token::For => SyntaxKind::For,
+            Token::Fun => SyntaxKind::Fun,
+            Token::If => SyntaxKind::If,
+            Token::Nil => SyntaxKind::Nil,
+            Token::Or => SyntaxKind::Or,
+            Token::Return => SyntaxKind::Return,
+            Token::Super => SyntaxKind::Super,
+            Token::This => SyntaxKind::This,
+            Token::True => SyntaxKind::True,
+            Token::Var => SyntaxKind::Var,
+            Token::While => SyntaxKind::While,
+            Token::Print => SyntaxKind::Print,
+            Token::DocComment(_) => SyntaxKind::DocComment,
+            Token::Whitespace(_) => SyntaxKind::Whitespace,
+            Token::Comment(_) => SyntaxKind::Comment,
+            Token::Eof => SyntaxKind::Eof,
+        }
+    }
+}
+
+/// A token of the green tree: its kind and its text, without position.
+#[derive(Debug, PartialEq, Eq, Hash)]
+pub struct GreenToken {
+    kind: SyntaxKind,
+    text: Rc<str>,
+}
+
+/// A node of the green tree. It only knows the length of its text, so identical
+/// subtrees can be shared.
+#[derive(Debug, PartialEq, Eq)]
+pub struct GreenNode {
+    kind: SyntaxKind,
+    len: usize,
+    children: Vec<GreenElement>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum GreenElement {
+    Node(Rc<GreenNode>),
+    Token(Rc<GreenToken>),
+}
+
+impl GreenElement {
+    fn len(&self) -> usize {
+        match self {
+            GreenElement::Node(node) => node.len,
+            GreenElement::Token(token) => token.text.len(),
+        }
+    }
+}
+
+impl GreenNode {
+    pub fn kind(&self) -> SyntaxKind {
+        self.kind
+    }
+
+    pub fn children(&self) -> &[GreenElement] {
+        &self.children
+    }
+}
+
+impl GreenToken {
+    pub fn kind(&self) -> SyntaxKind {
+        self.kind
+    }
+
+    pub fn text(&self) -> &str {
+        &self.text
+    }
+}
+
+/// Builds a green tree from the tokens, in the order of the source.
+#[derive(Default)]
+struct GreenBuilder {
+    /// The nodes being built, with the index of their first child in `children`.
+    parents: Vec<(SyntaxKind, usize)>,
+    children: Vec<GreenElement>,
+    /// The tokens already created, to share the identical ones.
+    tokens: HashMap<(SyntaxKind, Rc<str>), Rc<GreenToken>>,
+}
+
+/// Position in the children of the current node, to wrap the ones added after it in a
+/// node once we know its kind, for instance for the operands of a binary expression.
+#[derive(Clone, Copy)]
+struct Checkpoint(usize);
+
+impl GreenBuilder {
+    fn start_node(&mut self, kind: SyntaxKind) {
+        self.parents.push((kind, self.children.len()));
+    }
+
+    fn start_node_at(&mut self, checkpoint: Checkpoint, kind: SyntaxKind) {
+        self.parents.push((kind, checkpoint.0));
+    }
+
+    fn checkpoint(&self) -> Checkpoint {
+        Checkpoint(self.children.len())
+    }
+
+    /// Number of nodes being built.
+    fn depth(&self) -> usize {
+        self.parents.len()
+    }
+
+    fn token(&mut self, kind: SyntaxKind, text: &str) {
+        let token = self
+            .tokens
+            .entry((kind, Rc::from(text)))
+            .or_insert_with_key(|(kind, text)| {
+                Rc::new(GreenToken {
+                    kind: *kind,
+                    text: Rc::clone(text),
+                })
+            });
+        self.children.push(GreenElement::Token(Rc::clone(token)));
+    }
+
+    fn finish_node(&mut self) {
+        let (kind, first_child) = self.parents.pop().expect("a node is being built");
+        let children: Vec<_> = self.children.drain(first_child..).collect();
+        let len = children.iter().map(GreenElement::len).sum();
+        self.children.push(GreenElement::Node(Rc::new(GreenNode {
+            kind,
+            len,
+            children,
+        })));
+    }
+
+    fn finish(mut self) -> Rc<GreenNode> {
+        assert!(self.parents.is_empty(), "every node is finished");
+        match self.children.pop() {
+            Some(GreenElement::Node(root)) if self.children.is_empty() => root,
+            _ => unreachable!("the root node contains everything"),
+        }
+    }
+}
+
+/// A node of the red tree: a green node along with its position and its parent.
+/// Cloning it is cheap.
+#[derive(Clone)]
+pub struct SyntaxNode(Rc<NodeData>);
+
+struct NodeData {
+    green: Rc<GreenNode>,
+    parent: Option<SyntaxNode>,
+    offset: usize,
+}
+
+#[derive(Clone)]
+pub struct SyntaxToken {
+    green: Rc<GreenToken>,
+    parent: SyntaxNode,
+    offset: usize,
+}
+
+#[derive(Clone)]
+pub enum SyntaxElement {
+    Node(SyntaxNode),
+    Token(SyntaxToken),
+}
+
+impl SyntaxNode {
+    pub fn new_root(green: Rc<GreenNode>) -> Self {
+        Self(Rc::new(NodeData {
+            green,
+            parent: None,
+            offset: 0,
+        }))
+    }
+
+    pub fn kind(&self) -> SyntaxKind {
+        self.0.green.kind
+    }
+
+    pub fn green(&self) -> &Rc<GreenNode> {
+        &self.0.green
+    }
+
+    pub fn parent(&self) -> Option<&SyntaxNode> {
+        self.0.parent.as_ref()
+    }
+
+    /// Byte range of the node in the source, trivia included.
+    pub fn text_range(&self) -> Range<usize> {
+        self.0.offset..self.0.offset + self.0.green.len
+    }
+
+    pub fn children_with_tokens(&self) -> impl Iterator<Item = SyntaxElement> + '_ {
+        let mut offset = self.0.offset;
+        self.0.green.children.iter().map(move |child| {
+            let child_offset = offset;
+            offset += child.len();
+            match child {
+                GreenElement::Node(green) => SyntaxElement::Node(SyntaxNode(Rc::new(NodeData {
+                    green: Rc::clone(green),
+                    parent: Some(self.clone()),
+                    offset: child_offset,
+                }))),
+                GreenElement::Token(green) => SyntaxElement::Token(SyntaxToken {
+                    green: Rc::clone(green),
+                    parent: self.clone(),
+                    offset: child_offset,
+                }),
+            }
+        })
+    }
+
+    pub fn children(&self) -> impl Iterator<Item = SyntaxNode> + '_ {
+        self.children_with_tokens().filter_map(|child| match child {
+            SyntaxElement::Node(node) => Some(node),
+            SyntaxElement::Token(_) => None,
+        })
+    }
+
+    /// The tokens that are direct children of the node, trivia excluded.
+    pub fn tokens(&self) -> impl Iterator<Item = SyntaxToken> + '_ {
+        self.children_with_tokens().filter_map(|child| match child {
+            SyntaxElement::Token(token) if !token.kind().is_trivia() => Some(token),
+            _ => None,
+        })
+    }
+
+    /// Every token of the subtree, in the order of the source, trivia included.
+    pub fn descendant_tokens(&self) -> Vec<SyntaxToken> {
+        let mut tokens = Vec::new();
+        for child in self.children_with_tokens() {
+            match child {
+                SyntaxElement::Node(node) => tokens.extend(node.descendant_tokens()),
+                SyntaxElement::Token(token) => tokens.push(token),
+            }
+        }
+        tokens
+    }
+
+    /// The first token of the node that is not trivia.
+    pub fn first_token(&self) -> Option<SyntaxToken> {
+        self.children_with_tokens().find_map(|child| match child {
+            SyntaxElement::Node(node) => node.first_token(),
+            SyntaxElement::Token(token) => (!token.kind().is_trivia()).then_some(token),
+        })
+    }
+
+    /// The text of the `///` comments preceding the node, which are part of it.
+    pub fn doc_comments(&self) -> Vec<String> {
+        self.children_with_tokens()
+            .map_while(|child| match child {
+                SyntaxElement::Token(token) if token.kind().is_trivia() => Some(token),
+                _ => None,
+            })
+            .filter(|token| token.kind() == SyntaxKind::DocComment)
+            .map(|token| token.text()["///".len()..].to_string())
+            .collect()
+    }
+}
+
+impl fmt::Display for SyntaxNode {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for token in self.descendant_tokens() {
+            write!(f, "{}", token.text())?;
+        }
+        Ok(())
+    }
+}
+
+/// One line per node and token, indented by depth, with their kind and range.
+impl fmt::Debug for SyntaxNode {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let depth = std::iter::successors(self.parent(), |node| node.parent()).count();
+        let range = self.text_range();
+        writeln!(
+            f,
+            "{:indent$}{:?}@{}..{}",
+            "",
+            self.kind(),
+            range.start,
+            range.end,
+            indent = depth * 2
+        )?;
+        for child in self.children_with_tokens() {
+            match child {
+                SyntaxElement::Node(node) => write!(f, "{node:?}")?,
+                SyntaxElement::Token(token) => {
+                    let range = token.text_range();
+                    writeln!(
+                        f,
+                        "{:indent$}{:?}@{}..{} {:?}",
+                        "",
+                        token.kind(),
+                        range.start,
+                        range.end,
+                        token.text(),
+                        indent = (depth + 1) * 2
+                    )?
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+impl SyntaxToken {
+    pub fn kind(&self) -> SyntaxKind {
+        self.green.kind
+    }
+
+    pub fn text(&self) -> &str {
+        &self.green.text
+    }
+
+    pub fn text_range(&self) -> Range<usize> {
+        self.offset..self.offset + self.green.text.len()
+    }
+
+    pub fn parent(&self) -> &SyntaxNode {
+        &self.parent
+    }
+}
+
+/// The result of [`parse`]: the tree and the errors found while building it.
+pub struct Parse {
+    green: Rc<GreenNode>,
+    source: Rc<str>,
+    lexing: Vec<LexingError>,
+    parsing: Vec<ParseExpressionError>,
+}
+
+impl Parse {
+    pub fn syntax(&self) -> SyntaxNode {
+        SyntaxNode::new_root(Rc::clone(&self.green))
+    }
+
+    pub fn lexing_errors(&self) -> &[LexingError] {
+        &self.lexing
+    }
+
+    pub fn parsing_errors(&self) -> &[ParseExpressionError] {
+        &self.parsing
+    }
+
+    /// Derive the syntax trees interpreted by the backends, the same ones as
+    /// [`parse_statements`](crate::parse_statements) produces. Only possible when the
+    /// source has no syntax error, a node lacking a part is reported as one.
+    pub fn statements(self) -> Result<Vec<Spanned<StatementTree>>, RunError> {
+        if !self.lexing.is_empty() || !self.parsing.is_empty() {
+            return Err(RunError::Syntax {
+                lexing: self.lexing,
+                parsing: self.parsing,
+            });
+        }
+        let root = SyntaxNode::new_root(self.green);
+        lower::lower_program(&root, &LineIndex::new(&self.source)).map_err(|err| RunError::Syntax {
+            lexing: Vec::new(),
+            parsing: vec![err],
+        })
+    }
+}
+
+/// Build the concrete syntax tree of a source, reporting its errors along the way.
+pub fn parse(source: &str) -> Parse {
+    let mut lexing = Vec::new();
+    let mut tokens = Vec::new();
+    // The lexer skips the source it reports errors on, it is kept as unknown tokens
+    // so the tree covers all of it.
+    let line_index = LineIndex::new(source);
+    let mut offset = 0;
+    for token in Lexer::new(source).with_trivia() {
+        let token = match token {
+            Ok(token) => token,
+            Err(err) => {
+                lexing.push(err);
+                continue;
+            }
+        };
+        if token.span.start > offset {
+            tokens.push(Spanned {
+                node: (SyntaxKind::Unknown, &source[offset..token.span.start]),
+                span: line_index.span(offset..token.span.start),
+            });
+        }
+        offset = token.span.end;
+        tokens.push(Spanned {
+            node: (
+                SyntaxKind::from(&token.node),
+                &source[token.span.start..token.span.end],
+            ),
+            span: token.span,
+        });
+    }
+    let (green, parsing) = parser::parse_program(&tokens);
+    Parse {
+        green,
+        source: Rc::from(source),
+        lexing,
+        parsing,
+    }
+}
+
+/// Converts the offsets of the tree into locations, with lines and columns.
+struct LineIndex<'a> {
+    source: &'a str,
+    /// Byte offset of the beginning of every line.
+    line_starts: Vec<usize>,
+}
+
+impl<'a> LineIndex<'a> {
+    fn new(source: &'a str) -> Self {
+        let line_starts = std::iter::once(0)
+            .chain(source.match_indices('\n').map(|(i, _)| i + 1))
+            .collect();
+        Self {
+            source,
+            line_starts,
+        }
+    }
+
+    fn span(&self, range: Range<usize>) -> Span {
+        let line = self
+            .line_starts
+            .partition_point(|start| *start <= range.start);
+        let line_start = self.line_starts[line - 1];
+        Span {
+            start: range.start,
+            end: range.end,
+            line,
+            column: self.source[line_start..range.start].chars().count() + 1,
+        }
+    }
+}
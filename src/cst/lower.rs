@@ -0,0 +1,406 @@
+//! Derives the syntax trees of the [`parse`](crate::parse) module from a concrete syntax
+//! tree without errors, with the same locations and the same desugaring.
+//!
+//! The trees built by the parser of the CST are complete when it reports no error. A node
+//! lacking a part is still reported as a syntax error on the node, rather than trusted.
+
+use std::rc::Rc;
+
+use super::{LineIndex, SyntaxElement, SyntaxKind, SyntaxNode, SyntaxToken};
+use crate::{
+    intern::intern,
+    lex::{Lexer, Span, Spanned, Token},
+    parse::{
+        Comparison, Equality, ExpressionTree, Factor, Function, Logical, ParseErrorKind,
+        ParseExpressionError, Primary, StatementTree, Term, Unary, Variable,
+    },
+};
+
+type Lowered<T> = Result<T, ParseExpressionError>;
+
+pub(super) fn lower_program(
+    root: &SyntaxNode,
+    lines: &LineIndex<'_>,
+) -> Lowered<Vec<Spanned<StatementTree>>> {
+    root.children()
+        .map(|node| statement(&node, lines))
+        .collect()
+}
+
+fn token_span(token: &SyntaxToken, lines: &LineIndex<'_>) -> Span {
+    lines.span(token.text_range())
+}
+
+/// Error on a node lacking a part, located at its first token.
+fn malformed(
+    node: &SyntaxNode,
+    lines: &LineIndex<'_>,
+    kind: ParseErrorKind,
+) -> ParseExpressionError {
+    match node.first_token() {
+        Some(token) => {
+            ParseExpressionError::new(kind, token_span(&token, lines), Some(token.text()))
+        }
+        None => ParseExpressionError::new(kind, lines.span(node.text_range()), None),
+    }
+}
+
+/// The first token of the given kind among the direct children of the node.
+fn token(
+    node: &SyntaxNode,
+    kind: SyntaxKind,
+    lines: &LineIndex<'_>,
+    missing: ParseErrorKind,
+) -> Lowered<SyntaxToken> {
+    node.tokens()
+        .find(|token| token.kind() == kind)
+        .ok_or_else(|| malformed(node, lines, missing))
+}
+
+fn identifier(token: &SyntaxToken, lines: &LineIndex<'_>) -> Spanned<Rc<str>> {
+    Spanned {
+        node: intern(token.text()),
+        span: token_span(token, lines),
+    }
+}
+
+fn statement(node: &SyntaxNode, lines: &LineIndex<'_>) -> Lowered<Spanned<StatementTree>> {
+    // A statement is located at its first token, except for declarations which are
+    // located at the declared name.
+    let Some(first_token) = node.first_token() else {
+        return Err(malformed(node, lines, ParseErrorKind::MissingStatement));
+    };
+    let mut span = token_span(&first_token, lines);
+    let mut children = node.children();
+    let statement = match node.kind() {
+        SyntaxKind::PrintStmt => StatementTree::Print(next_expression(node, &mut children, lines)?),
+        SyntaxKind::ExprStmt => StatementTree::Expr(next_expression(node, &mut children, lines)?),
+        SyntaxKind::ReturnStmt => StatementTree::Return(
+            children
+                .next()
+                .map(|value| expression(&value, lines))
+                .transpose()?,
+        ),
+        SyntaxKind::VarDecl => {
+            let name = token(
+                node,
+                SyntaxKind::Identifier,
+                lines,
+                ParseErrorKind::MissingIdentifier,
+            )?;
+            let ident = identifier(&name, lines);
+            span = ident.span;
+            StatementTree::VarDeclaration {
+                ident: ident.node,
+                expr: children
+                    .next()
+                    .map(|value| expression(&value, lines))
+                    .transpose()?,
+            }
+        }
+        SyntaxKind::Block => StatementTree::Block(lower_program(node, lines)?),
+        SyntaxKind::FunDecl => {
+            let Some(function_node) = children.next() else {
+                return Err(malformed(node, lines, ParseErrorKind::MissingIdentifier));
+            };
+            let function = function(&function_node, lines)?;
+            span = function.span;
+            StatementTree::Function(Rc::new(function))
+        }
+        SyntaxKind::ClassDecl => {
+            let mut identifiers = node
+                .tokens()
+                .filter(|token| token.kind() == SyntaxKind::Identifier);
+            let Some(name) = identifiers.next() else {
+                return Err(malformed(node, lines, ParseErrorKind::MissingIdentifier));
+            };
+            let name = identifier(&name, lines);
+            span = name.span;
+            StatementTree::Class {
+                name: name.node,
+                superclass: identifiers.next().map(|superclass| {
+                    let superclass = identifier(&superclass, lines);
+                    Spanned {
+                        node: ExpressionTree::Primary(Primary::Identifier(Variable::new(
+                            superclass.node,
+                        ))),
+                        span: superclass.span,
+                    }
+                }),
+                methods: children
+                    .map(|method| function(&method, lines).map(Rc::new))
+                    .collect::<Lowered<_>>()?,
+            }
+        }
+        SyntaxKind::IfStmt => {
+            let condition = next_expression(node, &mut children, lines)?;
+            let then_branch = next_statement(node, &mut children, lines)?;
+            StatementTree::If {
+                condition,
+                then_branch: Box::new(then_branch),
+                else_branch: children
+                    .next()
+                    .map(|else_branch| statement(&else_branch, lines).map(Box::new))
+                    .transpose()?,
+            }
+        }
+        SyntaxKind::WhileStmt => {
+            let condition = next_expression(node, &mut children, lines)?;
+            let body = next_statement(node, &mut children, lines)?;
+            StatementTree::While {
+                condition,
+                body: Box::new(body),
+            }
+        }
+        SyntaxKind::ForStmt => for_loop(node, span, lines)?,
+        _ => return Err(malformed(node, lines, ParseErrorKind::MissingStatement)),
+    };
+    Ok(Spanned {
+        node: statement,
+        span,
+    })
+}
+
+/// The next child of the node, which must be an expression.
+fn next_expression(
+    node: &SyntaxNode,
+    children: &mut impl Iterator<Item = SyntaxNode>,
+    lines: &LineIndex<'_>,
+) -> Lowered<Spanned<ExpressionTree>> {
+    match children.next() {
+        Some(child) => expression(&child, lines),
+        None => Err(malformed(node, lines, ParseErrorKind::ExpectedExpression)),
+    }
+}
+
+/// The next child of the node, which must be a statement.
+fn next_statement(
+    node: &SyntaxNode,
+    children: &mut impl Iterator<Item = SyntaxNode>,
+    lines: &LineIndex<'_>,
+) -> Lowered<Spanned<StatementTree>> {
+    match children.next() {
+        Some(child) => statement(&child, lines),
+        None => Err(malformed(node, lines, ParseErrorKind::MissingStatement)),
+    }
+}
+
+/// Desugar a `for` loop into a `while` loop, like the parser does:
+/// { initializer; while (condition) { body; increment; } }
+fn for_loop(node: &SyntaxNode, span: Span, lines: &LineIndex<'_>) -> Lowered<StatementTree> {
+    // The clauses are optional, so they are told apart by the tokens they follow.
+    let mut initializer = None;
+    let mut condition = None;
+    let mut increment = None;
+    let mut body = None;
+    let mut separators = 0;
+    for child in node.children_with_tokens() {
+        match child {
+            SyntaxElement::Token(token) => match token.kind() {
+                SyntaxKind::Semicolon | SyntaxKind::RightParen => separators += 1,
+                _ => {}
+            },
+            SyntaxElement::Node(child) => match separators {
+                // The initializer ends with its own semicolon.
+                0 => {
+                    initializer = Some(statement(&child, lines)?);
+                    separators += 1;
+                }
+                1 => condition = Some(expression(&child, lines)?),
+                2 => increment = Some(expression(&child, lines)?),
+                _ => body = Some(statement(&child, lines)?),
+            },
+        }
+    }
+    let condition = condition.unwrap_or(Spanned {
+        node: ExpressionTree::Primary(Primary::True),
+        span,
+    });
+    let Some(body) = body else {
+        return Err(malformed(node, lines, ParseErrorKind::MissingStatement));
+    };
+    let body = match increment {
+        Some(increment) => {
+            let increment = Spanned {
+                span: increment.span,
+                node: StatementTree::Expr(increment),
+            };
+            Spanned {
+                node: StatementTree::Block(vec![body, increment]),
+                span,
+            }
+        }
+        None => body,
+    };
+    let while_loop = StatementTree::While {
+        condition,
+        body: Box::new(body),
+    };
+    Ok(match initializer {
+        Some(initializer) => StatementTree::Block(vec![
+            initializer,
+            Spanned {
+                node: while_loop,
+                span,
+            },
+        ]),
+        None => while_loop,
+    })
+}
+
+fn function(node: &SyntaxNode, lines: &LineIndex<'_>) -> Lowered<Function> {
+    let mut identifiers = node
+        .tokens()
+        .filter(|token| token.kind() == SyntaxKind::Identifier);
+    let Some(name) = identifiers.next() else {
+        return Err(malformed(node, lines, ParseErrorKind::MissingIdentifier));
+    };
+    let name = identifier(&name, lines);
+    let Some(body) = node.children().next() else {
+        return Err(malformed(node, lines, ParseErrorKind::MissingLeftBrace));
+    };
+    Ok(Function {
+        name: name.node,
+        span: name.span,
+        params: identifiers.map(|param| identifier(&param, lines)).collect(),
+        body: lower_program(&body, lines)?,
+    })
+}
+
+fn expression(node: &SyntaxNode, lines: &LineIndex<'_>) -> Lowered<Spanned<ExpressionTree>> {
+    let mut operands = node
+        .children()
+        .map(|node| expression(&node, lines).map(Box::new));
+    let mut operand = || {
+        operands
+            .next()
+            .unwrap_or_else(|| Err(malformed(node, lines, ParseErrorKind::ExpectedExpression)))
+    };
+    // An expression is located at its main token: the operator, the name of the
+    // variable or of the property...
+    let main_token = match node.kind() {
+        SyntaxKind::CallExpr => token(
+            node,
+            SyntaxKind::RightParen,
+            lines,
+            ParseErrorKind::MissingRightParen,
+        )?,
+        SyntaxKind::GetExpr => token(
+            node,
+            SyntaxKind::Identifier,
+            lines,
+            ParseErrorKind::MissingIdentifier,
+        )?,
+        _ => node
+            .tokens()
+            .next()
+            .ok_or_else(|| malformed(node, lines, ParseErrorKind::ExpectedExpression))?,
+    };
+    let mut span = token_span(&main_token, lines);
+    let expression = match node.kind() {
+        SyntaxKind::Literal => match literal(main_token.text()) {
+            Some(literal) => ExpressionTree::Primary(literal),
+            None => return Err(malformed(node, lines, ParseErrorKind::ExpectedExpression)),
+        },
+        SyntaxKind::NameExpr => ExpressionTree::Primary(Primary::Identifier(Variable::new(
+            intern(main_token.text()),
+        ))),
+        SyntaxKind::ThisExpr => {
+            ExpressionTree::Primary(Primary::This(Variable::new(intern("this"))))
+        }
+        SyntaxKind::SuperExpr => {
+            let method = token(
+                node,
+                SyntaxKind::Identifier,
+                lines,
+                ParseErrorKind::MissingIdentifier,
+            )?;
+            ExpressionTree::Primary(Primary::Super(
+                Variable::new(intern("super")),
+                intern(method.text()),
+            ))
+        }
+        SyntaxKind::GroupExpr => ExpressionTree::Primary(Primary::Group(operand()?)),
+        SyntaxKind::UnaryExpr => match main_token.kind() {
+            SyntaxKind::Minus => ExpressionTree::Unary(Unary::Minus(operand()?)),
+            _ => ExpressionTree::Unary(Unary::Bang(operand()?)),
+        },
+        SyntaxKind::BinaryExpr => {
+            let (left, right) = (operand()?, operand()?);
+            match main_token.kind() {
+                SyntaxKind::Star => ExpressionTree::Factor(Factor::Star(left, right)),
+                SyntaxKind::Slash => ExpressionTree::Factor(Factor::Slash(left, right)),
+                SyntaxKind::Plus => ExpressionTree::Term(Term::Plus(left, right)),
+                SyntaxKind::Minus => ExpressionTree::Term(Term::Minus(left, right)),
+                SyntaxKind::Less => ExpressionTree::Comparison(Comparison::Less(left, right)),
+                SyntaxKind::LessEqual => {
+                    ExpressionTree::Comparison(Comparison::LessEqual(left, right))
+                }
+                SyntaxKind::Greater => ExpressionTree::Comparison(Comparison::Greater(left, right)),
+                SyntaxKind::GreaterEqual => {
+                    ExpressionTree::Comparison(Comparison::GreaterEqual(left, right))
+                }
+                SyntaxKind::EqualEqual => {
+                    ExpressionTree::Equality(Equality::EqualEqual(left, right))
+                }
+                SyntaxKind::BangEqual => ExpressionTree::Equality(Equality::BangEqual(left, right)),
+                SyntaxKind::And => ExpressionTree::Logical(Logical::And(left, right)),
+                SyntaxKind::Or => ExpressionTree::Logical(Logical::Or(left, right)),
+                _ => return Err(malformed(node, lines, ParseErrorKind::ExpectedExpression)),
+            }
+        }
+        SyntaxKind::CallExpr => ExpressionTree::Call {
+            callee: operand()?,
+            arguments: operands
+                .map(|argument| argument.map(|argument| *argument))
+                .collect::<Lowered<_>>()?,
+        },
+        SyntaxKind::GetExpr => ExpressionTree::Get {
+            object: operand()?,
+            name: intern(main_token.text()),
+        },
+        // The assignment keeps the location of its target.
+        SyntaxKind::AssignExpr => {
+            let target = operand()?;
+            span = target.span;
+            let value = operand()?;
+            match target.node {
+                ExpressionTree::Primary(Primary::Identifier(variable)) => {
+                    ExpressionTree::Assignment(variable, value)
+                }
+                ExpressionTree::Get { object, name } => ExpressionTree::Set {
+                    object,
+                    name,
+                    value,
+                },
+                _ => {
+                    return Err(malformed(
+                        node,
+                        lines,
+                        ParseErrorKind::InvalidAssignmentTarget,
+                    ))
+                }
+            }
+        }
+        _ => return Err(malformed(node, lines, ParseErrorKind::ExpectedExpression)),
+    };
+    Ok(Spanned {
+        node: expression,
+        span,
+    })
+}
+
+/// The value of a literal, decoded by the lexer. `None` if the text is not a literal.
+fn literal(text: &str) -> Option<Primary> {
+    let Some(Ok(Spanned { node, .. })) = Lexer::new(text).next() else {
+        return None;
+    };
+    Some(match node {
+        Token::Nil => Primary::Nil,
+        Token::True => Primary::True,
+        Token::False => Primary::False,
+        Token::Number(n, _) => Primary::Number(n),
+        Token::String(s, _) => Primary::String(intern(&s)),
+        _ => return None,
+    })
+}
@@ -0,0 +1,487 @@
+//! Builds the concrete syntax tree. The grammar, the errors and the recovery are the
+//! ones of the [`parse`](crate::parse) module, so both parsers accept the same programs
+//! and report the same errors.
+
+use std::rc::Rc;
+
+use super::{GreenBuilder, GreenNode, SyntaxKind};
+use crate::{
+    lex::Spanned,
+    parse::{ParseErrorKind, ParseExpressionError, MAX_ARGUMENTS},
+};
+
+/// The tokens with their text, trivia included, ending with the end of file.
+type Tokens<'t, 'src> = &'t [Spanned<(SyntaxKind, &'src str)>];
+
+pub(super) fn parse_program(tokens: Tokens<'_, '_>) -> (Rc<GreenNode>, Vec<ParseExpressionError>) {
+    let mut parser = Parser {
+        tokens,
+        position: 0,
+        builder: GreenBuilder::default(),
+        errors: Vec::new(),
+    };
+    parser.builder.start_node(SyntaxKind::Program);
+    while !parser.at(SyntaxKind::Eof) {
+        parser.declaration();
+    }
+    // The trivia at the end of the file.
+    parser.bump_trivia();
+    parser.builder.finish_node();
+    (parser.builder.finish(), parser.errors)
+}
+
+struct Parser<'t, 'src> {
+    tokens: Tokens<'t, 'src>,
+    /// Index of the next token to add to the tree, which may be trivia.
+    position: usize,
+    builder: GreenBuilder,
+    errors: Vec<ParseExpressionError>,
+}
+
+impl Parser<'_, '_> {
+    /// Index of the next token that is not trivia.
+    fn next_index(&self) -> usize {
+        self.position
+            + self.tokens[self.position..]
+                .iter()
+                .take_while(|token| token.node.0.is_trivia())
+                .count()
+    }
+
+    fn peek(&self) -> SyntaxKind {
+        self.tokens
+            .get(self.next_index())
+            .map_or(SyntaxKind::Eof, |token| token.node.0)
+    }
+
+    fn at(&self, kind: SyntaxKind) -> bool {
+        self.peek() == kind
+    }
+
+    /// Add the trivia preceding the next token to the current node.
+    fn bump_trivia(&mut self) {
+        while let Some(token) = self
+            .tokens
+            .get(self.position)
+            .filter(|token| token.node.0.is_trivia())
+        {
+            self.builder.token(token.node.0, token.node.1);
+            self.position += 1;
+        }
+    }
+
+    /// Add the next token to the current node, along with the trivia preceding it.
+    fn bump(&mut self) {
+        self.bump_trivia();
+        if let Some(token) = self
+            .tokens
+            .get(self.position)
+            .filter(|token| token.node.0 != SyntaxKind::Eof)
+        {
+            self.builder.token(token.node.0, token.node.1);
+            self.position += 1;
+        }
+    }
+
+    fn eat(&mut self, kind: SyntaxKind) -> bool {
+        let found = self.at(kind);
+        if found {
+            self.bump();
+        }
+        found
+    }
+
+    /// Consume the next token if it is the expected one, otherwise report an error on it.
+    fn expect(
+        &mut self,
+        kind: SyntaxKind,
+        error: ParseErrorKind,
+    ) -> Result<(), ParseExpressionError> {
+        if self.eat(kind) {
+            Ok(())
+        } else {
+            Err(self.error_at_next(error))
+        }
+    }
+
+    fn expect_identifier(&mut self) -> Result<(), ParseExpressionError> {
+        self.expect(SyntaxKind::Identifier, ParseErrorKind::MissingIdentifier)
+    }
+
+    /// Error located at the next token, which is left in the stream.
+    fn error_at_next(&self, kind: ParseErrorKind) -> ParseExpressionError {
+        let token = &self.tokens[self.next_index()];
+        let (kind_of_token, text) = token.node;
+        ParseExpressionError::new(
+            kind,
+            token.span,
+            (kind_of_token != SyntaxKind::Eof).then_some(text),
+        )
+    }
+
+    /// Start a node at the next token. The trivia before it belongs to the parent.
+    fn start_node(&mut self, kind: SyntaxKind) {
+        self.bump_trivia();
+        self.builder.start_node(kind);
+    }
+
+    /// Start a node that can be documented: the `///` comments right before it belong
+    /// to it, with the whitespace that follows them.
+    fn start_documented_node(&mut self, kind: SyntaxKind) {
+        let trivia = &self.tokens[self.position..self.next_index()];
+        let comments_start = trivia
+            .iter()
+            .rposition(|token| {
+                !matches!(
+                    token.node.0,
+                    SyntaxKind::DocComment | SyntaxKind::Whitespace
+                )
+            })
+            .map_or(0, |index| index + 1);
+        let documentation = trivia[comments_start..]
+            .iter()
+            .position(|token| token.node.0 == SyntaxKind::DocComment)
+            .map_or(trivia.len(), |index| comments_start + index);
+        for token in &trivia[..documentation] {
+            self.builder.token(token.node.0, token.node.1);
+            self.position += 1;
+        }
+        self.builder.start_node(kind);
+    }
+
+    /// Parse a declaration or a statement. On a syntax error, the error is recorded and
+    /// we skip to the next statement in an error node.
+    fn declaration(&mut self) {
+        let depth = self.builder.depth();
+        if let Err(err) = self.declaration_or_statement() {
+            self.errors.push(err);
+            // Close the nodes left open by the error.
+            while self.builder.depth() > depth {
+                self.builder.finish_node();
+            }
+            self.synchronize();
+        }
+    }
+
+    /// Discard tokens until we are likely at the beginning of a statement, either right
+    /// after a semicolon or before a keyword that starts a statement.
+    fn synchronize(&mut self) {
+        if self.at(SyntaxKind::Eof) {
+            return;
+        }
+        self.start_node(SyntaxKind::Error);
+        while !self.at(SyntaxKind::Eof) {
+            let semicolon = self.at(SyntaxKind::Semicolon);
+            self.bump();
+            if semicolon
+                || matches!(
+                    self.peek(),
+                    SyntaxKind::Class
+                        | SyntaxKind::Fun
+                        | SyntaxKind::Var
+                        | SyntaxKind::For
+                        | SyntaxKind::If
+                        | SyntaxKind::While
+                        | SyntaxKind::Print
+                        | SyntaxKind::Return
+                )
+            {
+                break;
+            }
+        }
+        self.builder.finish_node();
+    }
+
+    /// A declaration or any other statement, as found at the top level and in blocks.
+    /// Returns whether there was one to parse, before the end of the file.
+    fn declaration_or_statement(&mut self) -> Result<bool, ParseExpressionError> {
+        let kind = match self.peek() {
+            SyntaxKind::Var => SyntaxKind::VarDecl,
+            SyntaxKind::Fun => SyntaxKind::FunDecl,
+            SyntaxKind::Class => SyntaxKind::ClassDecl,
+            _ => return self.statement(),
+        };
+        self.start_documented_node(kind);
+        match kind {
+            SyntaxKind::VarDecl => self.var_declaration_content()?,
+            SyntaxKind::FunDecl => {
+                self.bump();
+                self.start_node(SyntaxKind::Function);
+                self.function_content()?;
+            }
+            _ => {
+                self.bump();
+                self.expect_identifier()?;
+                if self.eat(SyntaxKind::Less) {
+                    self.expect_identifier()?;
+                }
+                self.expect(SyntaxKind::LeftBrace, ParseErrorKind::MissingLeftBrace)?;
+                while !self.at(SyntaxKind::Eof) && !self.at(SyntaxKind::RightBrace) {
+                    self.start_documented_node(SyntaxKind::Function);
+                    self.function_content()?;
+                }
+                self.expect(SyntaxKind::RightBrace, ParseErrorKind::MissingRightBrace)?;
+            }
+        }
+        self.builder.finish_node();
+        Ok(true)
+    }
+
+    /// A statement that is not a declaration. Declarations are not allowed as the body
+    /// of a branch or a loop, so they are parsed as expressions and reported as such.
+    /// Returns whether there was a statement to parse, before the end of the file.
+    fn statement(&mut self) -> Result<bool, ParseExpressionError> {
+        let kind = match self.peek() {
+            SyntaxKind::Eof => return Ok(false),
+            SyntaxKind::Print => SyntaxKind::PrintStmt,
+            SyntaxKind::LeftBrace => SyntaxKind::Block,
+            SyntaxKind::Return => SyntaxKind::ReturnStmt,
+            SyntaxKind::If => SyntaxKind::IfStmt,
+            SyntaxKind::While => SyntaxKind::WhileStmt,
+            SyntaxKind::For => SyntaxKind::ForStmt,
+            _ => SyntaxKind::ExprStmt,
+        };
+        self.start_documented_node(kind);
+        match kind {
+            SyntaxKind::PrintStmt => {
+                self.bump();
+                self.expr(0)?;
+                self.expect(SyntaxKind::Semicolon, ParseErrorKind::MissingSemicolon)?;
+            }
+            SyntaxKind::Block => self.block_content()?,
+            SyntaxKind::ReturnStmt => {
+                self.bump();
+                if !self.at(SyntaxKind::Semicolon) {
+                    self.expr(0)?;
+                }
+                self.expect(SyntaxKind::Semicolon, ParseErrorKind::MissingSemicolon)?;
+            }
+            SyntaxKind::IfStmt => {
+                self.bump();
+                self.condition()?;
+                self.body()?;
+                if self.eat(SyntaxKind::Else) {
+                    self.body()?;
+                }
+            }
+            SyntaxKind::WhileStmt => {
+                self.bump();
+                self.condition()?;
+                self.body()?;
+            }
+            SyntaxKind::ForStmt => {
+                self.bump();
+                self.expect(SyntaxKind::LeftParen, ParseErrorKind::MissingLeftParen)?;
+                // The initializer is either a variable declaration or an expression
+                // statement, both consume their own semicolon.
+                if self.at(SyntaxKind::Var) {
+                    self.start_node(SyntaxKind::VarDecl);
+                    self.var_declaration_content()?;
+                    self.builder.finish_node();
+                } else if !self.eat(SyntaxKind::Semicolon) {
+                    self.start_node(SyntaxKind::ExprStmt);
+                    self.expression_statement_content()?;
+                    self.builder.finish_node();
+                }
+                if !self.at(SyntaxKind::Semicolon) {
+                    self.expr(0)?;
+                }
+                self.expect(SyntaxKind::Semicolon, ParseErrorKind::MissingSemicolon)?;
+                if !self.at(SyntaxKind::RightParen) {
+                    self.expr(0)?;
+                }
+                self.expect(SyntaxKind::RightParen, ParseErrorKind::MissingRightParen)?;
+                self.body()?;
+            }
+            _ => self.expression_statement_content()?,
+        }
+        self.builder.finish_node();
+        Ok(true)
+    }
+
+    /// The content of a [`SyntaxKind::VarDecl`] node, from the `var` keyword to the
+    /// semicolon.
+    fn var_declaration_content(&mut self) -> Result<(), ParseExpressionError> {
+        self.bump();
+        self.expect_identifier()?;
+        if self.eat(SyntaxKind::Equal) {
+            self.expr(0)?;
+        }
+        self.expect(SyntaxKind::Semicolon, ParseErrorKind::MissingSemicolon)
+    }
+
+    /// The content of a [`SyntaxKind::ExprStmt`] node.
+    fn expression_statement_content(&mut self) -> Result<(), ParseExpressionError> {
+        self.expr(0)?;
+        self.expect(SyntaxKind::Semicolon, ParseErrorKind::MissingSemicolon)
+    }
+
+    /// The parenthesized condition of an `if` or a `while`.
+    fn condition(&mut self) -> Result<(), ParseExpressionError> {
+        self.expect(SyntaxKind::LeftParen, ParseErrorKind::MissingLeftParen)?;
+        self.expr(0)?;
+        self.expect(SyntaxKind::RightParen, ParseErrorKind::MissingRightParen)
+    }
+
+    /// The statement of a branch or of a loop, which is mandatory.
+    fn body(&mut self) -> Result<(), ParseExpressionError> {
+        if self.statement()? {
+            Ok(())
+        } else {
+            Err(self.error_at_next(ParseErrorKind::MissingStatement))
+        }
+    }
+
+    /// The content of a [`SyntaxKind::Function`] node, from the name of the function to
+    /// the end of its body. The node is finished here.
+    fn function_content(&mut self) -> Result<(), ParseExpressionError> {
+        self.expect_identifier()?;
+        self.expect(SyntaxKind::LeftParen, ParseErrorKind::MissingLeftParen)?;
+        if !self.eat(SyntaxKind::RightParen) {
+            let mut parameters = 0;
+            loop {
+                if parameters == MAX_ARGUMENTS {
+                    // The function is still valid for the parser, keep going.
+                    let err = self.error_at_next(ParseErrorKind::TooManyParameters);
+                    self.errors.push(err);
+                }
+                self.expect_identifier()?;
+                parameters += 1;
+                if !self.eat(SyntaxKind::Comma) {
+                    break;
+                }
+            }
+            self.expect(SyntaxKind::RightParen, ParseErrorKind::MissingRightParen)?;
+        }
+        if !self.at(SyntaxKind::LeftBrace) {
+            return Err(self.error_at_next(ParseErrorKind::MissingLeftBrace));
+        }
+        self.start_node(SyntaxKind::Block);
+        self.block_content()?;
+        self.builder.finish_node();
+        self.builder.finish_node();
+        Ok(())
+    }
+
+    /// The content of a [`SyntaxKind::Block`] node, from its opening brace to its
+    /// closing one.
+    fn block_content(&mut self) -> Result<(), ParseExpressionError> {
+        self.bump();
+        while !self.at(SyntaxKind::Eof) && !self.at(SyntaxKind::RightBrace) {
+            self.declaration();
+        }
+        self.expect(SyntaxKind::RightBrace, ParseErrorKind::MissingRightBrace)
+    }
+
+    // Pratt parser, with the binding powers of `parse_expr`.
+    fn expr(&mut self, min_bp: u8) -> Result<(), ParseExpressionError> {
+        // The operands are wrapped in the node of their operator once we find it.
+        self.bump_trivia();
+        let checkpoint = self.builder.checkpoint();
+        let mut lhs = match self.peek() {
+            SyntaxKind::Nil
+            | SyntaxKind::True
+            | SyntaxKind::False
+            | SyntaxKind::Number
+            | SyntaxKind::String => {
+                self.start_node(SyntaxKind::Literal);
+                self.bump();
+                SyntaxKind::Literal
+            }
+            SyntaxKind::LeftParen => {
+                self.start_node(SyntaxKind::GroupExpr);
+                self.bump();
+                self.expr(0)?;
+                self.expect(SyntaxKind::RightParen, ParseErrorKind::MissingRightParen)?;
+                SyntaxKind::GroupExpr
+            }
+            SyntaxKind::Identifier => {
+                self.start_node(SyntaxKind::NameExpr);
+                self.bump();
+                SyntaxKind::NameExpr
+            }
+            SyntaxKind::This => {
+                self.start_node(SyntaxKind::ThisExpr);
+                self.bump();
+                SyntaxKind::ThisExpr
+            }
+            SyntaxKind::Super => {
+                self.start_node(SyntaxKind::SuperExpr);
+                self.bump();
+                self.expect(SyntaxKind::Dot, ParseErrorKind::MissingDot)?;
+                self.expect_identifier()?;
+                SyntaxKind::SuperExpr
+            }
+            SyntaxKind::Minus | SyntaxKind::Bang => {
+                self.start_node(SyntaxKind::UnaryExpr);
+                self.bump();
+                self.expr(7)?;
+                SyntaxKind::UnaryExpr
+            }
+            // The token is left in the stream, so we can synchronize from it.
+            _ => return Err(self.error_at_next(ParseErrorKind::ExpectedExpression)),
+        };
+        self.builder.finish_node();
+
+        // We parse the tokens until we hit something with a lower precedence.
+        loop {
+            let (kind, bp) = match self.peek() {
+                SyntaxKind::Star | SyntaxKind::Slash => (SyntaxKind::BinaryExpr, 7),
+                SyntaxKind::Plus | SyntaxKind::Minus => (SyntaxKind::BinaryExpr, 6),
+                SyntaxKind::Less
+                | SyntaxKind::LessEqual
+                | SyntaxKind::Greater
+                | SyntaxKind::GreaterEqual => (SyntaxKind::BinaryExpr, 5),
+                SyntaxKind::EqualEqual | SyntaxKind::BangEqual => (SyntaxKind::BinaryExpr, 4),
+                SyntaxKind::LeftParen => (SyntaxKind::CallExpr, 8),
+                SyntaxKind::Dot => (SyntaxKind::GetExpr, 8),
+                SyntaxKind::Equal => (SyntaxKind::AssignExpr, 1),
+                SyntaxKind::And => (SyntaxKind::BinaryExpr, 3),
+                SyntaxKind::Or => (SyntaxKind::BinaryExpr, 2),
+                _ => break,
+            };
+            if bp <= min_bp {
+                break;
+            }
+            let operator = &self.tokens[self.next_index()];
+            let (operator, text) = (operator.span, operator.node.1);
+            self.builder.start_node_at(checkpoint, kind);
+            self.bump();
+            match kind {
+                SyntaxKind::CallExpr => {
+                    if !self.at(SyntaxKind::RightParen) {
+                        let mut arguments = 0;
+                        loop {
+                            if arguments == MAX_ARGUMENTS {
+                                return Err(self.error_at_next(ParseErrorKind::TooManyArguments));
+                            }
+                            self.expr(0)?;
+                            arguments += 1;
+                            if !self.eat(SyntaxKind::Comma) {
+                                break;
+                            }
+                        }
+                    }
+                    self.expect(SyntaxKind::RightParen, ParseErrorKind::MissingRightParen)?;
+                }
+                SyntaxKind::GetExpr => self.expect_identifier()?,
+                // Assignment is right associative, so we parse the right hand side with
+                // a lower binding power than its own.
+                SyntaxKind::AssignExpr => {
+                    self.expr(bp - 1)?;
+                    if !matches!(lhs, SyntaxKind::NameExpr | SyntaxKind::GetExpr) {
+                        return Err(ParseExpressionError::new(
+                            ParseErrorKind::InvalidAssignmentTarget,
+                            operator,
+                            Some(text),
+                        ));
+                    }
+                }
+                _ => self.expr(bp)?,
+            }
+            self.builder.finish_node();
+            lhs = kind;
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,295 @@
+use std::io::{self, IsTerminal};
+
+use crate::lex::Span;
+
+/// An error ready to be shown to the user, with the part of the source it points to.
+#[derive(Debug)]
+pub struct Diagnostic {
+    /// Stable identifier of the error, e.g. `E0301`.
+    code: &'static str,
+    /// The one-line message, in the format of the reference implementation.
+    message: String,
+    span: Span,
+    notes: Vec<Note>,
+}
+
+#[derive(Debug)]
+struct Note {
+    message: String,
+    /// When present, the note is rendered under this part of the source.
+    span: Option<Span>,
+}
+
+impl Diagnostic {
+    pub fn new(code: &'static str, message: impl Into<String>, span: Span) -> Self {
+        Self {
+            code,
+            message: message.into(),
+            span,
+            notes: Vec::new(),
+        }
+    }
+
+    /// Add a note, pointing to another location of the source when `span` is given.
+    pub fn with_note(mut self, message: impl Into<String>, span: Option<Span>) -> Self {
+        self.notes.push(Note {
+            message: message.into(),
+            span,
+        });
+        self
+    }
+
+    /// Render the diagnostic, for instance:
+    ///
+    /// ```text
+    /// Operands must be two numbers or two strings.
+    ///  --> test.lox:1:11 [E0404]
+    ///   |
+    /// 1 | print "a" + 1;
+    ///   |           ^
+    /// ```
+    pub fn render(&self, filename: &str, source: &str, colour: bool) -> String {
+        let gutter = self
+            .notes
+            .iter()
+            .filter_map(|note| note.span)
+            .chain([self.span])
+            .map(|span| span.line.to_string().len())
+            .max()
+            .unwrap_or(1);
+        let mut renderer = Renderer {
+            output: String::new(),
+            source,
+            gutter,
+            style: Style { colour },
+        };
+
+        for line in self.message.lines() {
+            renderer.line(&renderer.style.paint(line, ERROR));
+        }
+        let location = format!(
+            "{} {filename}:{}:{} [{}]",
+            renderer.style.paint("-->", GUTTER),
+            self.span.line,
+            self.span.column,
+            renderer.style.paint(self.code, ERROR),
+        );
+        // The arrow points to the bar of the margin.
+        let location = format!("{:gutter$}{location}", "");
+        renderer.line(&location);
+        renderer.margin(&renderer.style.paint("|", GUTTER));
+        renderer.snippet(self.span, '^', "", ERROR);
+
+        for note in &self.notes {
+            match note.span {
+                Some(span) => {
+                    renderer.margin(&renderer.style.paint("|", GUTTER));
+                    renderer.snippet(span, '-', &note.message, NOTE);
+                }
+                None => {
+                    let note =
+                        format!("{} {}", renderer.style.paint("= note:", NOTE), note.message);
+                    renderer.margin(&note);
+                }
+            }
+        }
+        renderer.output
+    }
+}
+
+/// Print the diagnostics of a file on stderr, in colour if it is a terminal.
+pub struct Emitter<'a> {
+    filename: &'a str,
+    source: &'a str,
+    colour: bool,
+}
+
+impl<'a> Emitter<'a> {
+    pub fn new(filename: &'a str, source: &'a str) -> Self {
+        Self {
+            filename,
+            source,
+            colour: io::stderr().is_terminal(),
+        }
+    }
+
+    pub fn emit(&self, diagnostic: &Diagnostic) {
+        eprint!(
+            "{}",
+            diagnostic.render(self.filename, self.source, self.colour)
+        );
+    }
+}
+
+struct Renderer<'a> {
+    output: String,
+    source: &'a str,
+    /// Width of the line numbers.
+    gutter: usize,
+    style: Style,
+}
+
+impl Renderer<'_> {
+    fn line(&mut self, text: &str) {
+        self.output.push_str(text);
+        self.output.push('\n');
+    }
+
+    /// A line indented past the line numbers.
+    fn margin(&mut self, text: &str) {
+        let line = format!("{:gutter$} {text}", "", gutter = self.gutter);
+        self.line(&line);
+    }
+
+    /// Write the source line containing the start of `span`, and underline the span with
+    /// `marker`. Spans across several lines are only underlined up to the end of the first one.
+    fn snippet(&mut self, span: Span, marker: char, label: &str, colour: &str) {
+        let source = self.source;
+        let start = span.start.min(source.len());
+        let line_start = source[..start].rfind('\n').map_or(0, |i| i + 1);
+        let line_end = source[start..]
+            .find('\n')
+            .map_or(source.len(), |i| start + i);
+        let line = source[line_start..line_end].trim_end_matches('\r');
+
+        // Keep the tabs so the markers stay aligned with the code above them.
+        let padding: String = source[line_start..start]
+            .chars()
+            .map(|c| if c == '\t' { '\t' } else { ' ' })
+            .collect();
+        let end = span.end.clamp(start, line_start + line.len());
+        let width = source[start..end].chars().count().max(1);
+        let markers: String = std::iter::repeat(marker).take(width).collect();
+
+        let number = format!("{:>gutter$} |", span.line, gutter = self.gutter);
+        let code = format!("{} {line}", self.style.paint(&number, GUTTER));
+        self.line(&code);
+        let mut underline = format!(
+            "{} {padding}{}",
+            self.style.paint("|", GUTTER),
+            self.style.paint(&markers, colour)
+        );
+        if !label.is_empty() {
+            underline.push(' ');
+            underline.push_str(&self.style.paint(label, colour));
+        }
+        self.margin(&underline);
+    }
+}
+
+const ERROR: &str = "\x1b[1;31m";
+const NOTE: &str = "\x1b[1;36m";
+const GUTTER: &str = "\x1b[1;34m";
+
+#[derive(Clone, Copy)]
+struct Style {
+    colour: bool,
+}
+
+impl Style {
+    fn paint(self, text: &str, colour: &str) -> String {
+        if self.colour {
+            format!("{colour}{text}\x1b[0m")
+        } else {
+            text.to_string()
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Span of the first occurrence of `text` in `source`, which must be on `line`.
+    fn span_of(source: &str, text: &str, line: usize) -> Span {
+        let start = source.find(text).unwrap();
+        let line_start = source[..start].rfind('\n').map_or(0, |i| i + 1);
+        Span {
+            start,
+            end: start + text.len(),
+            line,
+            column: start - line_start + 1,
+        }
+    }
+
+    #[test]
+    fn carets_underline_the_span() {
+        let source = "print \"a\" + 1;\n";
+        let span = span_of(source, "+", 1);
+        let diagnostic = Diagnostic::new(
+            "E0404",
+            "Operands must be two numbers or two strings.",
+            span,
+        );
+        assert_eq!(
+            diagnostic.render("test.lox", source, false),
+            "Operands must be two numbers or two strings.\n \
+             --> test.lox:1:11 [E0404]\n  \
+             |\n\
+             1 | print \"a\" + 1;\n  \
+             |           ^\n"
+        );
+    }
+
+    #[test]
+    fn carets_cover_the_whole_token_and_keep_tabs() {
+        let source = "{\n\tvar abc = abc;\n}\n";
+        let span = span_of(source, "abc;", 2);
+        let span = Span {
+            end: span.start + 3,
+            ..span
+        };
+        let rendered = Diagnostic::new("E0301", "message", span).render("test.lox", source, false);
+        assert!(rendered.ends_with("2 | \tvar abc = abc;\n  | \t          ^^^\n"));
+    }
+
+    #[test]
+    fn notes_are_rendered_after_the_snippet() {
+        let source = "var a = 1;\nvar a = 2;\n";
+        let first = Span {
+            end: source.find(" = 1").unwrap(),
+            ..span_of(source, "a = 1", 1)
+        };
+        let second = Span {
+            end: source.find(" = 2").unwrap(),
+            ..span_of(source, "a = 2", 2)
+        };
+        let rendered = Diagnostic::new("E0302", "message", second)
+            .with_note("'a' is first declared here", Some(first))
+            .with_note("a note without location", None)
+            .render("test.lox", source, false);
+        assert_eq!(
+            rendered,
+            "message\n \
+             --> test.lox:2:5 [E0302]\n  \
+             |\n\
+             2 | var a = 2;\n  \
+             |     ^\n  \
+             |\n\
+             1 | var a = 1;\n  \
+             |     - 'a' is first declared here\n  \
+             = note: a note without location\n"
+        );
+    }
+
+    #[test]
+    fn colour_is_only_used_when_asked() {
+        let source = "print -nil;\n";
+        let diagnostic = Diagnostic::new(
+            "E0401",
+            "Operand must be a number.",
+            span_of(source, "-", 1),
+        );
+        let plain = diagnostic.render("test.lox", source, false);
+        assert!(!plain.contains('\x1b'));
+        let coloured = diagnostic.render("test.lox", source, true);
+        assert!(coloured.contains(&format!("{ERROR}Operand must be a number.\x1b[0m")));
+        assert!(coloured.contains(&format!("{ERROR}^\x1b[0m")));
+        // Without the escape codes, both renderings are the same.
+        let stripped = coloured
+            .replace(ERROR, "")
+            .replace(GUTTER, "")
+            .replace("\x1b[0m", "");
+        assert_eq!(stripped, plain);
+    }
+}
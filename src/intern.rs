@@ -0,0 +1,47 @@
+use std::{cell::RefCell, collections::HashSet, rc::Rc};
+
+thread_local! {
+    static STRINGS: RefCell<Interner> = RefCell::new(Interner {
+        strings: HashSet::new(),
+        threshold: MIN_THRESHOLD,
+    });
+}
+
+/// Number of strings from which the unused ones start to be dropped.
+const MIN_THRESHOLD: usize = 1024;
+
+struct Interner {
+    strings: HashSet<Rc<str>>,
+    /// Number of strings at which the unused ones are dropped, doubled each time so it
+    /// only costs a constant time per interned string.
+    threshold: usize,
+}
+
+/// Get the shared copy of a string, creating it the first time it is seen.
+///
+/// Identifiers and string literals are interned when they are parsed, so the trees and
+/// values built from them don't depend on the lifetime of the source, and every use of
+/// a name shares the same allocation.
+///
+/// The strings are interned per thread, and only as long as they are used: once the
+/// trees and the values holding a string are dropped, it is freed the next time the
+/// interned strings are pruned, so a long-running REPL doesn't keep every string it
+/// has seen. A string interned again after that gets a new allocation.
+pub fn intern(string: &str) -> Rc<str> {
+    STRINGS.with(|interner| {
+        let mut interner = interner.borrow_mut();
+        if let Some(interned) = interner.strings.get(string) {
+            return Rc::clone(interned);
+        }
+        if interner.strings.len() >= interner.threshold {
+            // The set holds the only reference to the strings nothing uses anymore.
+            interner
+                .strings
+                .retain(|interned| Rc::strong_count(interned) > 1);
+            interner.threshold = MIN_THRESHOLD.max(2 * interner.strings.len());
+        }
+        let interned: Rc<str> = Rc::from(string);
+        interner.strings.insert(Rc::clone(&interned));
+        interned
+    })
+}
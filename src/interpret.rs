@@ -1,275 +1,1000 @@
-use std::{borrow::Cow, collections::HashMap, fmt, mem};
+use std::{
+    cell::RefCell,
+    collections::HashMap,
+    error::Error,
+    fmt,
+    io::{self, Write},
+    mem,
+    ops::ControlFlow,
+    rc::Rc,
+};
 
-use crate::parse::{
-    Comparison, Equality, ExpressionTree, Factor, Primary, StatementTree, Term, Unary,
+use crate::{
+    compile::CompileError,
+    diagnostic::Diagnostic,
+    intern::intern,
+    lex::{Lexer, LexingError, Span, Spanned},
+    native::{self, NativeFunction},
+    parse::{
+        parse_statements, Comparison, Equality, ExpressionTree, Factor, Function, Logical,
+        ParseExpressionError, Primary, StatementTree, Term, Unary, Variable,
+    },
+    resolve::{resolve, ResolveError},
 };
 
-pub struct Interpreter<'de> {
-    /// Map variables identifier and their value.
-    environments: Environments<'de>,
+mod gc;
+
+use gc::Heap;
+
+/// Maximum number of nested calls, the top-level code counting as one.
+pub const MAX_CALL_DEPTH: usize = 1024;
+
+/// Evaluates the syntax trees. The global variables live as long as the interpreter, so
+/// several programs can be run one after the other in the same environment.
+///
+/// The evaluation recurses on the native stack: a program calling functions deeply, up
+/// to the limit where a stack overflow is reported, needs a thread with a large stack.
+///
+/// `print` statements write to `W`, the standard output by default.
+pub struct Interpreter<W = io::Stdout> {
+    globals: Environment,
+    /// The innermost scope of the code being evaluated.
+    environment: Environment,
+    output: W,
+    /// Number of function calls being evaluated.
+    call_depth: usize,
+    /// Declared last so it is dropped after the scopes, to free their cycles.
+    heap: Heap,
+}
+
+/// Statistics about the objects of the heap of an [`Interpreter`] or of a
+/// [`Vm`](crate::vm::Vm).
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct HeapStats {
+    /// Objects currently in the heap, including the unreachable ones not collected yet.
+    pub live_objects: usize,
+    /// Objects allocated since the creation of the interpreter.
+    pub allocated_objects: usize,
+    /// Objects freed by the collector since the creation of the interpreter.
+    pub freed_objects: usize,
+    pub collections: usize,
+    /// Number of objects that triggers the next collection.
+    pub next_collection: usize,
 }
 
-impl<'de> Interpreter<'de> {
+impl Default for Interpreter {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Interpreter {
     pub fn new() -> Self {
-        Self {
-            environments: Environments::new(),
+        Self::with_output(io::stdout())
+    }
+}
+
+impl<W: Write> Interpreter<W> {
+    /// Create an interpreter printing to `output`, for instance a `Vec<u8>` to capture
+    /// what a program prints, or `io::sink()` to discard it.
+    ///
+    /// ```
+    /// let mut interpreter = lox::Interpreter::with_output(Vec::new());
+    /// interpreter.run_source("print 1 + 2;").unwrap();
+    /// assert_eq!(interpreter.output(), b"3\n");
+    /// ```
+    pub fn with_output(output: W) -> Self {
+        let mut heap = Heap::default();
+        let globals = heap.alloc_scope(None);
+        let mut interpreter = Self {
+            environment: globals.clone(),
+            globals,
+            call_depth: 0,
+            output,
+            heap,
+        };
+        for &(name, arity, function) in native::BUILTINS {
+            interpreter.define_native(name, arity, function);
         }
+        interpreter
+    }
+
+    pub fn output(&self) -> &W {
+        &self.output
+    }
+
+    pub fn output_mut(&mut self) -> &mut W {
+        &mut self.output
     }
+
+    pub fn into_output(self) -> W {
+        self.output
+    }
+
+    /// Collect the garbage before every allocation, to find the objects that are freed
+    /// while still in use.
+    pub fn set_gc_stress(&mut self, enabled: bool) {
+        self.heap.stress = enabled;
+    }
+
+    /// Report on stderr every collection and every object freed.
+    pub fn set_gc_log(&mut self, enabled: bool) {
+        self.heap.log = enabled;
+    }
+
+    /// Most objects are freed by reference counting as soon as they are unused, only the
+    /// ones in cycles are left to the collector and counted as freed.
+    ///
+    /// ```
+    /// let mut interpreter = lox::Interpreter::with_output(Vec::new());
+    /// interpreter.set_gc_stress(true);
+    /// let source = "class A { init() { this.self = this; } } for (var i = 0; i < 10; i = i + 1) A();";
+    /// interpreter.run_source(source).unwrap();
+    /// assert!(interpreter.heap_stats().freed_objects > 0);
+    /// ```
+    pub fn heap_stats(&self) -> HeapStats {
+        self.heap.stats()
+    }
+
+    /// Define a global function implemented in Rust. The arguments are checked against
+    /// `arity` before `function` is called, and its errors become runtime errors.
+    ///
+    /// ```
+    /// use lox::{Interpreter, Value};
+    ///
+    /// let mut interpreter = Interpreter::new();
+    /// interpreter.define_native("double", 1, |arguments| {
+    ///     let number = f64::try_from(&arguments[0])?;
+    ///     Ok(Value::from(number * 2.0))
+    /// });
+    /// interpreter.run_source("print double(21);").unwrap();
+    /// ```
+    pub fn define_native(
+        &mut self,
+        name: &str,
+        arity: usize,
+        function: impl Fn(&[Value]) -> Result<Value, Box<dyn Error>> + 'static,
+    ) {
+        let function = NativeFunction::new(name, arity, function);
+        self.globals.insert(
+            Rc::clone(&function.name),
+            Value::NativeFunction(Rc::new(function)),
+        );
+    }
+
+    /// Run a whole program: lex, parse, resolve then evaluate it. Nothing is evaluated
+    /// if the program has a static error.
+    pub fn run_source(&mut self, source: &str) -> Result<(), RunError> {
+        let statements = parse_source(source)?;
+        self.evaluate(&statements).map_err(RunError::Evaluation)
+    }
+
+    /// The global variables, sorted by name.
+    pub fn globals(&self) -> Vec<(Rc<str>, Value)> {
+        let mut globals: Vec<_> = self
+            .globals
+            .0
+            .borrow()
+            .values
+            .iter()
+            .map(|(name, value)| (Rc::clone(name), value.clone()))
+            .collect();
+        globals.sort_by(|(lhs, _), (rhs, _)| lhs.cmp(rhs));
+        globals
+    }
+
     pub fn evaluate(
         &mut self,
-        token_tree: Vec<StatementTree<'de>>,
-    ) -> Result<(), EvaluationError<'de>> {
-        for statement in token_tree {
-            match statement {
-                StatementTree::Print(expr) => {
+        token_tree: &[Spanned<StatementTree>],
+    ) -> Result<(), EvaluationError> {
+        // A `return` outside of a function simply stops the program.
+        let _ = self.evaluate_statements(token_tree)?;
+        Ok(())
+    }
+
+    /// Evaluate the statements until the end, or until a `return` statement
+    /// is reached. In that case, we break with the returned value.
+    fn evaluate_statements(
+        &mut self,
+        statements: &[Spanned<StatementTree>],
+    ) -> Result<ControlFlow<Value>, EvaluationError> {
+        for statement in statements {
+            if let ControlFlow::Break(value) = self.evaluate_statement(statement)? {
+                return Ok(ControlFlow::Break(value));
+            }
+        }
+        Ok(ControlFlow::Continue(()))
+    }
+
+    fn evaluate_statement(
+        &mut self,
+        statement: &Spanned<StatementTree>,
+    ) -> Result<ControlFlow<Value>, EvaluationError> {
+        match &statement.node {
+            StatementTree::Print(expr) => {
+                let value = self.evaluate_expr(expr)?;
+                writeln!(self.output, "{value}").map_err(|err| {
+                    EvaluationError::new(EvaluationErrorKind::Output(err), statement.span)
+                })?;
+            }
+            StatementTree::Expr(expr) => {
+                // Expression statement is for expression
+                // that have side effects.
+                let _ = self.evaluate_expr(expr)?;
+            }
+            StatementTree::VarDeclaration { ident, expr } => {
+                if let Some(expr) = expr {
                     let value = self.evaluate_expr(expr)?;
-                    println!("{value}");
+                    self.environment.insert(Rc::clone(ident), value);
+                } else {
+                    self.environment.insert(Rc::clone(ident), Value::Nil);
                 }
-                StatementTree::Expr(expr) => {
-                    // Expression statement is for expression
-                    // that have side effects.
-                    let _ = self.evaluate_expr(expr)?;
+            }
+            StatementTree::Block(statements) => {
+                let environment = self.heap.alloc_scope(Some(self.environment.clone()));
+                return self.evaluate_block(statements, environment);
+            }
+            StatementTree::If {
+                condition,
+                then_branch,
+                else_branch,
+            } => {
+                if self.evaluate_expr(condition)?.is_truthy() {
+                    return self.evaluate_statement(then_branch);
+                } else if let Some(else_branch) = else_branch {
+                    return self.evaluate_statement(else_branch);
                 }
-                StatementTree::VarDeclaration { ident, expr } => {
-                    if let Some(expr) = expr {
-                        let value = self.evaluate_expr(expr)?;
-                        self.environments.insert(ident, value);
-                    } else {
-                        self.environments.insert(ident, Value::Nil);
+            }
+            StatementTree::While { condition, body } => {
+                while self.evaluate_expr(condition)?.is_truthy() {
+                    if let ControlFlow::Break(value) = self.evaluate_statement(body)? {
+                        return Ok(ControlFlow::Break(value));
                     }
                 }
-                StatementTree::Block(statements) => {
-                    self.environments.push_block();
-                    self.evaluate(statements)?;
-                    self.environments.pop_block();
+            }
+            StatementTree::Function(function) => {
+                let closure = self.heap.alloc_closure(Closure {
+                    function: Rc::clone(function),
+                    environment: self.environment.clone(),
+                    is_initializer: false,
+                });
+                self.environment
+                    .insert(Rc::clone(&function.name), Value::Function(closure));
+            }
+            StatementTree::Return(expr) => {
+                let value = match expr {
+                    Some(expr) => self.evaluate_expr(expr)?,
+                    None => Value::Nil,
+                };
+                return Ok(ControlFlow::Break(value));
+            }
+            StatementTree::Class {
+                name,
+                superclass,
+                methods,
+            } => {
+                let superclass = match superclass {
+                    Some(superclass) => match self.evaluate_expr(superclass)? {
+                        Value::Class(superclass) => Some(superclass),
+                        _ => {
+                            return Err(EvaluationError::new(
+                                EvaluationErrorKind::SuperclassNotAClass,
+                                superclass.span,
+                            ))
+                        }
+                    },
+                    None => None,
+                };
+                // Methods of a subclass are declared in a scope where `super`
+                // refers to the superclass.
+                let environment = match &superclass {
+                    Some(superclass) => {
+                        let environment = self.heap.alloc_scope(Some(self.environment.clone()));
+                        environment.insert(intern("super"), Value::Class(Rc::clone(superclass)));
+                        environment
+                    }
+                    None => self.environment.clone(),
+                };
+                let methods = methods
+                    .iter()
+                    .map(|method| {
+                        let closure = self.heap.alloc_closure(Closure {
+                            function: Rc::clone(method),
+                            environment: environment.clone(),
+                            is_initializer: &*method.name == "init",
+                        });
+                        (Rc::clone(&method.name), closure)
+                    })
+                    .collect();
+                let class = self.heap.alloc_class(Class {
+                    name: Rc::clone(name),
+                    superclass,
+                    methods,
+                });
+                self.environment
+                    .insert(Rc::clone(name), Value::Class(class));
+            }
+        };
+        Ok(ControlFlow::Continue(()))
+    }
+
+    /// Evaluate the statements in the given scope, then restore the current one.
+    fn evaluate_block(
+        &mut self,
+        statements: &[Spanned<StatementTree>],
+        environment: Environment,
+    ) -> Result<ControlFlow<Value>, EvaluationError> {
+        let previous = mem::replace(&mut self.environment, environment);
+        let result = self.evaluate_statements(statements);
+        // The scope must be restored even when we unwind because of
+        // a `return` or an error.
+        self.environment = previous;
+        result
+    }
+
+    fn call(
+        &mut self,
+        closure: &Closure,
+        arguments: Vec<Value>,
+        span: Span,
+    ) -> Result<Value, EvaluationError> {
+        if self.call_depth + 1 == MAX_CALL_DEPTH {
+            return Err(EvaluationError::new(
+                EvaluationErrorKind::StackOverflow,
+                span,
+            ));
+        }
+        // The function body is evaluated in the scope where the function was
+        // declared, not the one of the caller.
+        let environment = self.heap.alloc_scope(Some(closure.environment.clone()));
+        for (param, argument) in closure.function.params.iter().zip(arguments) {
+            environment.insert(Rc::clone(&param.node), argument);
+        }
+
+        self.call_depth += 1;
+        let result = self.evaluate_block(&closure.function.body, environment);
+        self.call_depth -= 1;
+        let value = match result? {
+            ControlFlow::Break(value) => value,
+            ControlFlow::Continue(()) => Value::Nil,
+        };
+        // An initializer always returns the instance, even with an early `return`.
+        if closure.is_initializer {
+            return Ok(closure.environment.get("this").unwrap_or(Value::Nil));
+        }
+        Ok(value)
+    }
+
+    /// Create a method bound to the instance, where `this` refers to it.
+    fn bind(&mut self, method: &Closure, instance: Rc<RefCell<Instance>>) -> Rc<Closure> {
+        let environment = self.heap.alloc_scope(Some(method.environment.clone()));
+        environment.insert(intern("this"), Value::Instance(instance));
+        self.heap.alloc_closure(Closure {
+            function: Rc::clone(&method.function),
+            environment,
+            is_initializer: method.is_initializer,
+        })
+    }
+
+    /// Local variables are looked up in the scope found by the resolver, the others
+    /// are globals.
+    fn lookup_variable(&self, variable: &Variable, span: Span) -> Result<Value, EvaluationError> {
+        let value = match variable.depth.get() {
+            Some(depth) => self.environment.ancestor(depth).get(&variable.name),
+            None => self.globals.get(&variable.name),
+        };
+        value.ok_or(EvaluationError::new(
+            EvaluationErrorKind::UndefinedVariable(Rc::clone(&variable.name)),
+            span,
+        ))
+    }
+
+    fn call_value(
+        &mut self,
+        callee: Value,
+        arguments: Vec<Value>,
+        span: Span,
+    ) -> Result<Value, EvaluationError> {
+        match callee {
+            Value::Function(closure) => {
+                check_arity(closure.function.params.len(), arguments.len(), span)?;
+                self.call(&closure, arguments, span)
+            }
+            Value::Class(class) => {
+                let instance = self.heap.alloc_instance(Instance {
+                    class: Rc::clone(&class),
+                    fields: HashMap::new(),
+                });
+                match class.find_method("init") {
+                    Some(initializer) => {
+                        check_arity(initializer.function.params.len(), arguments.len(), span)?;
+                        let initializer = self.bind(&initializer, Rc::clone(&instance));
+                        self.call(&initializer, arguments, span)?;
+                    }
+                    None => check_arity(0, arguments.len(), span)?,
                 }
-            };
+                Ok(Value::Instance(instance))
+            }
+            Value::NativeFunction(function) => {
+                check_arity(function.arity, arguments.len(), span)?;
+                function.call(&arguments, span)
+            }
+            _ => Err(EvaluationError::new(EvaluationErrorKind::NotCallable, span)),
         }
-        Ok(())
     }
 
     pub fn evaluate_expr(
         &mut self,
-        token_tree: ExpressionTree<'de>,
-    ) -> Result<Value<'de>, EvaluationError<'de>> {
-        Ok(match token_tree {
+        token_tree: &Spanned<ExpressionTree>,
+    ) -> Result<Value, EvaluationError> {
+        let span = token_tree.span;
+        Ok(match &token_tree.node {
             ExpressionTree::Primary(primary) => match primary {
-                Primary::String(string) => Value::String(Cow::Borrowed(string)),
-                Primary::Number(number) => Value::Number(number),
+                Primary::String(string) => Value::String(Rc::clone(string)),
+                Primary::Number(number) => Value::Number(*number),
                 Primary::True => Value::Boolean(true),
                 Primary::False => Value::Boolean(false),
                 Primary::Nil => Value::Nil,
-                Primary::Group(token_tree) => self.evaluate_expr(*token_tree)?,
-                Primary::Identifier(ident) => self
-                    .environments
-                    .get(ident)
-                    .ok_or(EvaluationError::UndefinedVariable(ident))?
-                    .clone(),
+                Primary::Group(token_tree) => self.evaluate_expr(token_tree)?,
+                Primary::Identifier(variable) | Primary::This(variable) => {
+                    self.lookup_variable(variable, span)?
+                }
+                Primary::Super(variable, method) => {
+                    let Value::Class(superclass) = self.lookup_variable(variable, span)? else {
+                        return Err(EvaluationError::new(
+                            EvaluationErrorKind::UndefinedVariable(intern("super")),
+                            span,
+                        ));
+                    };
+                    // `this` is always bound in the scope right inside the one of `super`.
+                    let depth = variable.depth.get().unwrap_or_default().saturating_sub(1);
+                    let Some(Value::Instance(instance)) =
+                        self.environment.ancestor(depth).get("this")
+                    else {
+                        return Err(EvaluationError::new(
+                            EvaluationErrorKind::UndefinedVariable(intern("this")),
+                            span,
+                        ));
+                    };
+                    let method = superclass.find_method(method).ok_or(EvaluationError::new(
+                        EvaluationErrorKind::UndefinedProperty(Rc::clone(method)),
+                        span,
+                    ))?;
+                    Value::Function(self.bind(&method, instance))
+                }
             },
             ExpressionTree::Unary(unary) => match unary {
                 Unary::Bang(token_tree) => {
-                    let value = self.evaluate_expr(*token_tree)?;
-                    match value {
-                        Value::Boolean(boolean) => match boolean {
-                            true => Value::Boolean(false),
-                            false => Value::Boolean(true),
-                        },
-                        Value::Number(_) | Value::String(_) => Value::Boolean(false),
-                        Value::Nil => Value::Boolean(true),
-                    }
+                    let value = self.evaluate_expr(token_tree)?;
+                    Value::Boolean(!value.is_truthy())
                 }
                 Unary::Minus(token_tree) => {
-                    let value_tmp = self.evaluate_expr(*token_tree)?;
-                    let value = value_tmp.as_number()?;
+                    let value_tmp = self.evaluate_expr(token_tree)?;
+                    let value = value_tmp.as_number(span)?;
 
                     Value::Number(-value)
                 }
             },
             ExpressionTree::Factor(factor) => match factor {
                 Factor::Slash(lhs, rhs) => {
-                    let lhs = self.evaluate_expr(*lhs)?.as_number()?;
-                    let rhs = self.evaluate_expr(*rhs)?.as_number()?;
+                    let (lhs, rhs) = self.evaluate_numbers(lhs, rhs, span)?;
                     Value::Number(lhs / rhs)
                 }
                 Factor::Star(lhs, rhs) => {
-                    let lhs = self.evaluate_expr(*lhs)?.as_number()?;
-                    let rhs = self.evaluate_expr(*rhs)?.as_number()?;
+                    let (lhs, rhs) = self.evaluate_numbers(lhs, rhs, span)?;
                     Value::Number(lhs * rhs)
                 }
             },
             ExpressionTree::Term(term) => match term {
                 Term::Minus(lhs, rhs) => {
-                    let lhs = self.evaluate_expr(*lhs)?.as_number()?;
-                    let rhs = self.evaluate_expr(*rhs)?.as_number()?;
+                    let (lhs, rhs) = self.evaluate_numbers(lhs, rhs, span)?;
                     Value::Number(lhs - rhs)
                 }
                 Term::Plus(lhs, rhs) => {
-                    match (self.evaluate_expr(*lhs)?, self.evaluate_expr(*rhs)?) {
+                    match (self.evaluate_expr(lhs)?, self.evaluate_expr(rhs)?) {
                         (Value::Number(lhs), Value::Number(rhs)) => Value::Number(lhs + rhs),
-                        (Value::String(lhs), Value::String(rhs)) => Value::String(lhs + rhs),
-                        _ => return Err(EvaluationError::WrongPlusOperands),
+                        (Value::String(lhs), Value::String(rhs)) => {
+                            Value::String(format!("{lhs}{rhs}").into())
+                        }
+                        _ => {
+                            return Err(EvaluationError::new(
+                                EvaluationErrorKind::WrongPlusOperands,
+                                span,
+                            ))
+                        }
                     }
                 }
             },
             ExpressionTree::Comparison(comparison) => match comparison {
                 Comparison::Less(lhs, rhs) => {
-                    let lhs = self.evaluate_expr(*lhs)?.as_number()?;
-                    let rhs = self.evaluate_expr(*rhs)?.as_number()?;
+                    let (lhs, rhs) = self.evaluate_numbers(lhs, rhs, span)?;
                     Value::Boolean(lhs < rhs)
                 }
                 Comparison::LessEqual(lhs, rhs) => {
-                    let lhs = self.evaluate_expr(*lhs)?.as_number()?;
-                    let rhs = self.evaluate_expr(*rhs)?.as_number()?;
+                    let (lhs, rhs) = self.evaluate_numbers(lhs, rhs, span)?;
                     Value::Boolean(lhs <= rhs)
                 }
                 Comparison::Greater(lhs, rhs) => {
-                    let lhs = self.evaluate_expr(*lhs)?.as_number()?;
-                    let rhs = self.evaluate_expr(*rhs)?.as_number()?;
+                    let (lhs, rhs) = self.evaluate_numbers(lhs, rhs, span)?;
                     Value::Boolean(lhs > rhs)
                 }
                 Comparison::GreaterEqual(lhs, rhs) => {
-                    let lhs = self.evaluate_expr(*lhs)?.as_number()?;
-                    let rhs = self.evaluate_expr(*rhs)?.as_number()?;
+                    let (lhs, rhs) = self.evaluate_numbers(lhs, rhs, span)?;
                     Value::Boolean(lhs >= rhs)
                 }
             },
             ExpressionTree::Equality(equality) => match equality {
                 Equality::EqualEqual(lhs, rhs) => {
-                    match (self.evaluate_expr(*lhs)?, self.evaluate_expr(*rhs)?) {
-                        (Value::Boolean(lhs), Value::Boolean(rhs)) => Value::Boolean(lhs == rhs),
-                        (Value::Number(lhs), Value::Number(rhs)) => Value::Boolean(lhs == rhs),
-                        (Value::String(lhs), Value::String(rhs)) => Value::Boolean(lhs == rhs),
-                        (Value::Nil, Value::Nil) => Value::Boolean(true),
-                        _ => Value::Boolean(false),
-                    }
+                    Value::Boolean(self.evaluate_expr(lhs)? == self.evaluate_expr(rhs)?)
                 }
                 Equality::BangEqual(lhs, rhs) => {
-                    match (self.evaluate_expr(*lhs)?, self.evaluate_expr(*rhs)?) {
-                        (Value::Boolean(lhs), Value::Boolean(rhs)) => Value::Boolean(lhs != rhs),
-                        (Value::Number(lhs), Value::Number(rhs)) => Value::Boolean(lhs != rhs),
-                        (Value::String(lhs), Value::String(rhs)) => Value::Boolean(lhs != rhs),
-                        (Value::Nil, Value::Nil) => Value::Boolean(true),
-                        _ => Value::Boolean(false),
-                    }
+                    Value::Boolean(self.evaluate_expr(lhs)? != self.evaluate_expr(rhs)?)
                 }
             },
-            ExpressionTree::Assignment(ident, expr) => match self.environments.get(ident) {
-                Some(_) => {
-                    // Evaluating assignement expression has side effect on the interpreter.
-                    let mut value = self.evaluate_expr(*expr)?;
-                    let environment_addr = self.environments.get_mut(ident).unwrap();
-                    mem::swap(environment_addr, &mut value);
-                    environment_addr.clone()
+            ExpressionTree::Logical(logical) => match logical {
+                // The operand value is returned as is, not coerced to a boolean.
+                Logical::And(lhs, rhs) => {
+                    let lhs = self.evaluate_expr(lhs)?;
+                    if !lhs.is_truthy() {
+                        lhs
+                    } else {
+                        self.evaluate_expr(rhs)?
+                    }
+                }
+                Logical::Or(lhs, rhs) => {
+                    let lhs = self.evaluate_expr(lhs)?;
+                    if lhs.is_truthy() {
+                        lhs
+                    } else {
+                        self.evaluate_expr(rhs)?
+                    }
                 }
-                None => return Err(EvaluationError::UndeclaredVariable(ident)),
             },
+            ExpressionTree::Assignment(variable, expr) => {
+                // Evaluating assignement expression has side effect on the interpreter.
+                let value = self.evaluate_expr(expr)?;
+                let environment = match variable.depth.get() {
+                    Some(depth) => self.environment.ancestor(depth),
+                    None => self.globals.clone(),
+                };
+                environment
+                    .assign(&variable.name, value.clone())
+                    .ok_or(EvaluationError::new(
+                        EvaluationErrorKind::UndeclaredVariable(Rc::clone(&variable.name)),
+                        span,
+                    ))?;
+                value
+            }
+            ExpressionTree::Call { callee, arguments } => {
+                let callee = self.evaluate_expr(callee)?;
+                let arguments = arguments
+                    .iter()
+                    .map(|argument| self.evaluate_expr(argument))
+                    .collect::<Result<Vec<_>, _>>()?;
+                self.call_value(callee, arguments, span)?
+            }
+            ExpressionTree::Get { object, name } => {
+                let Value::Instance(instance) = self.evaluate_expr(object)? else {
+                    return Err(EvaluationError::new(
+                        EvaluationErrorKind::NotAnInstance,
+                        span,
+                    ));
+                };
+                // Fields shadow methods.
+                let field = instance.borrow().fields.get(name).cloned();
+                match field {
+                    Some(value) => value,
+                    None => {
+                        let method = instance.borrow().class.find_method(name).ok_or(
+                            EvaluationError::new(
+                                EvaluationErrorKind::UndefinedProperty(Rc::clone(name)),
+                                span,
+                            ),
+                        )?;
+                        Value::Function(self.bind(&method, instance))
+                    }
+                }
+            }
+            ExpressionTree::Set {
+                object,
+                name,
+                value,
+            } => {
+                let Value::Instance(instance) = self.evaluate_expr(object)? else {
+                    return Err(EvaluationError::new(
+                        EvaluationErrorKind::NotAnInstanceField,
+                        span,
+                    ));
+                };
+                let value = self.evaluate_expr(value)?;
+                instance
+                    .borrow_mut()
+                    .fields
+                    .insert(Rc::clone(name), value.clone());
+                value
+            }
         })
     }
+
+    /// Evaluate the operands of a binary operator, which must both be numbers.
+    fn evaluate_numbers(
+        &mut self,
+        lhs: &Spanned<ExpressionTree>,
+        rhs: &Spanned<ExpressionTree>,
+        span: Span,
+    ) -> Result<(f64, f64), EvaluationError> {
+        match (self.evaluate_expr(lhs)?, self.evaluate_expr(rhs)?) {
+            (Value::Number(lhs), Value::Number(rhs)) => Ok((lhs, rhs)),
+            _ => Err(EvaluationError::new(
+                EvaluationErrorKind::ExpectedNumbers,
+                span,
+            )),
+        }
+    }
 }
 
 /// A value, produced by an expression.
 #[derive(Clone)]
-pub enum Value<'de> {
+pub enum Value {
     Boolean(bool),
     Number(f64),
-    String(Cow<'de, str>),
+    String(Rc<str>),
     Nil,
+    Function(Rc<Closure>),
+    NativeFunction(Rc<NativeFunction>),
+    Class(Rc<Class>),
+    Instance(Rc<RefCell<Instance>>),
+}
+
+/// A function together with the scope it was declared in.
+pub struct Closure {
+    function: Rc<Function>,
+    environment: Environment,
+    /// Whether this is the `init` method of a class.
+    is_initializer: bool,
+}
+
+pub struct Class {
+    name: Rc<str>,
+    superclass: Option<Rc<Class>>,
+    methods: HashMap<Rc<str>, Rc<Closure>>,
+}
+
+impl Class {
+    /// Look for the method in the class, then in its superclasses.
+    fn find_method(&self, name: &str) -> Option<Rc<Closure>> {
+        match self.methods.get(name) {
+            Some(method) => Some(Rc::clone(method)),
+            None => self.superclass.as_ref()?.find_method(name),
+        }
+    }
 }
 
-// We use explicit lifetime here because otherwise lifetime elision
-// will bind the lifetime of the return type to `self` but it must be bound to
-// the file content lifetime. (The one in the string variant)
-impl<'de> Value<'de> {
-    fn as_number(&self) -> Result<f64, EvaluationError<'de>> {
+pub struct Instance {
+    class: Rc<Class>,
+    fields: HashMap<Rc<str>, Value>,
+}
+
+fn check_arity(expected: usize, got: usize, span: Span) -> Result<(), EvaluationError> {
+    if expected != got {
+        return Err(EvaluationError::new(
+            EvaluationErrorKind::WrongArity { expected, got },
+            span,
+        ));
+    }
+    Ok(())
+}
+
+impl Value {
+    /// The operand of a unary operator, which must be a number.
+    fn as_number(&self, span: Span) -> Result<f64, EvaluationError> {
         if let Value::Number(value) = &self {
             Ok(*value)
         } else {
-            Err(EvaluationError::ExpectedNumber)
+            Err(EvaluationError::new(
+                EvaluationErrorKind::ExpectedNumber,
+                span,
+            ))
+        }
+    }
+
+    /// In Lox, `nil` and `false` are falsy, everything else is truthy.
+    fn is_truthy(&self) -> bool {
+        !matches!(self, Value::Nil | Value::Boolean(false))
+    }
+}
+
+/// Values of different types are never equal, and objects are compared by identity.
+impl PartialEq for Value {
+    fn eq(&self, other: &Self) -> bool {
+        match (self, other) {
+            (Value::Boolean(lhs), Value::Boolean(rhs)) => lhs == rhs,
+            (Value::Number(lhs), Value::Number(rhs)) => lhs == rhs,
+            (Value::String(lhs), Value::String(rhs)) => lhs == rhs,
+            (Value::Nil, Value::Nil) => true,
+            (Value::Function(lhs), Value::Function(rhs)) => Rc::ptr_eq(lhs, rhs),
+            (Value::NativeFunction(lhs), Value::NativeFunction(rhs)) => Rc::ptr_eq(lhs, rhs),
+            (Value::Class(lhs), Value::Class(rhs)) => Rc::ptr_eq(lhs, rhs),
+            (Value::Instance(lhs), Value::Instance(rhs)) => Rc::ptr_eq(lhs, rhs),
+            _ => false,
         }
     }
 }
 
-impl fmt::Display for Value<'_> {
+impl fmt::Display for Value {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         match self {
             Value::Boolean(boolean) => write!(f, "{boolean}"),
             Value::Number(number) => write!(f, "{number}"),
             Value::String(string) => write!(f, "{string}"),
             Value::Nil => write!(f, "nil"),
+            Value::Function(closure) => write!(f, "<fn {}>", closure.function.name),
+            Value::NativeFunction(_) => write!(f, "<native fn>"),
+            Value::Class(class) => write!(f, "{}", class.name),
+            Value::Instance(instance) => write!(f, "{} instance", instance.borrow().class.name),
         }
     }
 }
 
-/// The first element is the global variables,then the others are the scoped from the least to the most nested.
-struct Environments<'de>(Vec<HashMap<&'de str, Value<'de>>>);
+/// A handle to a scope of variables, linked to the scope enclosing it up to the
+/// global one.
+///
+/// Scopes are reference counted so a closure can keep the scope it was defined in
+/// alive after the block or the function that created it is done.
+#[derive(Clone)]
+struct Environment(Rc<RefCell<Scope>>);
+
+struct Scope {
+    values: HashMap<Rc<str>, Value>,
+    enclosing: Option<Environment>,
+}
+
+impl Environment {
+    /// The scope `depth` levels above this one.
+    fn ancestor(&self, depth: usize) -> Self {
+        let mut environment = self.clone();
+        for _ in 0..depth {
+            let enclosing = environment
+                .0
+                .borrow()
+                .enclosing
+                .clone()
+                .expect("the resolver only computes depth of existing scopes");
+            environment = enclosing;
+        }
+        environment
+    }
 
-impl<'de> Environments<'de> {
-    fn new() -> Self {
-        Self(vec![HashMap::new()])
+    /// Only look in this scope, the resolver tells us in which one the variable lives.
+    fn get(&self, ident: &str) -> Option<Value> {
+        self.0.borrow().values.get(ident).cloned()
     }
 
-    fn push_block(&mut self) {
-        self.0.push(HashMap::new());
+    /// Assign a value to an existing variable, returning `None` if it doesn't exist.
+    fn assign(&self, ident: &str, value: Value) -> Option<()> {
+        let mut scope = self.0.borrow_mut();
+        *scope.values.get_mut(ident)? = value;
+        Some(())
     }
 
-    fn pop_block(&mut self) {
-        if self.0.len() > 1 {
-            self.0.pop();
-        }
+    fn insert(&self, ident: Rc<str>, value: Value) -> Option<Value> {
+        self.0.borrow_mut().values.insert(ident, value)
     }
+}
 
-    fn get(&self, ident: &'de str) -> Option<&Value<'de>> {
-        // As we allow variable shadowing, we take the most nested first.
-        for environement in self.0.iter().rev() {
-            if let Some(value) = environement.get(ident) {
-                return Some(value);
-            }
+/// Lex, parse and resolve a whole program, collecting its static errors.
+pub(crate) fn parse_source(source: &str) -> Result<Vec<Spanned<StatementTree>>, RunError> {
+    let mut lexing = Vec::new();
+    let tokens: Vec<_> = Lexer::new(source)
+        .filter_map(|token| token.map_err(|err| lexing.push(err)).ok())
+        .collect();
+    let statements = match parse_statements(&mut tokens.into_iter().peekable()) {
+        Ok(statements) if lexing.is_empty() => statements,
+        Ok(_) => {
+            return Err(RunError::Syntax {
+                lexing,
+                parsing: Vec::new(),
+            })
         }
+        Err(parsing) => return Err(RunError::Syntax { lexing, parsing }),
+    };
+    resolve(&statements).map_err(RunError::Resolve)?;
+    Ok(statements)
+}
+
+/// An error from any stage of [`Interpreter::run_source`].
+#[derive(Debug)]
+#[non_exhaustive]
+pub enum RunError {
+    /// All the lexing and parsing errors of the program.
+    Syntax {
+        lexing: Vec<LexingError>,
+        parsing: Vec<ParseExpressionError>,
+    },
+    /// All the resolution errors of the program.
+    Resolve(Vec<ResolveError>),
+    /// Only produced by the [`Vm`](crate::vm::Vm).
+    Compile(CompileError),
+    Evaluation(EvaluationError),
+}
 
-        None
+impl RunError {
+    pub fn diagnostics(&self) -> Vec<Diagnostic> {
+        match self {
+            RunError::Syntax { lexing, parsing } => lexing
+                .iter()
+                .map(LexingError::diagnostic)
+                .chain(parsing.iter().map(ParseExpressionError::diagnostic))
+                .collect(),
+            RunError::Resolve(errors) => errors.iter().map(ResolveError::diagnostic).collect(),
+            RunError::Compile(err) => vec![err.diagnostic()],
+            RunError::Evaluation(err) => vec![err.diagnostic()],
+        }
     }
 
-    fn get_mut(&mut self, ident: &'de str) -> Option<&mut Value<'de>> {
-        for environement in self.0.iter_mut().rev() {
-            if let Some(value) = environement.get_mut(ident) {
-                return Some(value);
+    /// The exit code used by the reference implementation: 65 when the program is
+    /// invalid, 70 when it fails while running.
+    pub fn exit_code(&self) -> i32 {
+        match self {
+            RunError::Syntax { .. } | RunError::Resolve(_) | RunError::Compile(_) => 65,
+            RunError::Evaluation(_) => 70,
+        }
+    }
+}
+
+impl std::error::Error for RunError {}
+
+impl fmt::Display for RunError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            RunError::Syntax { lexing, parsing } => write_lines(
+                f,
+                lexing
+                    .iter()
+                    .map(|err| err as &dyn fmt::Display)
+                    .chain(parsing.iter().map(|err| err as &dyn fmt::Display)),
+            ),
+            RunError::Resolve(errors) => {
+                write_lines(f, errors.iter().map(|err| err as &dyn fmt::Display))
             }
+            RunError::Compile(err) => write!(f, "{err}"),
+            RunError::Evaluation(err) => write!(f, "{err}"),
         }
+    }
+}
 
-        None
+/// Write each error on its own line.
+fn write_lines<'a>(
+    f: &mut fmt::Formatter<'_>,
+    errors: impl Iterator<Item = &'a dyn fmt::Display>,
+) -> fmt::Result {
+    for (i, err) in errors.enumerate() {
+        if i > 0 {
+            writeln!(f)?;
+        }
+        write!(f, "{err}")?;
     }
+    Ok(())
+}
+
+#[derive(Debug)]
+pub struct EvaluationError {
+    kind: EvaluationErrorKind,
+    span: Span,
+}
 
-    fn insert(&mut self, ident: &'de str, value: Value<'de>) -> Option<Value<'de>> {
-        self.0
-            .last_mut()
-            .expect("should always have at least global env")
-            .insert(ident, value)
+impl EvaluationError {
+    pub(crate) fn new(kind: EvaluationErrorKind, span: Span) -> Self {
+        Self { kind, span }
+    }
+
+    pub fn kind(&self) -> &EvaluationErrorKind {
+        &self.kind
+    }
+
+    pub fn diagnostic(&self) -> Diagnostic {
+        // The location is shown by the diagnostic, no need for the `[line N]` suffix.
+        let diagnostic = Diagnostic::new(self.kind.code(), self.kind.to_string(), self.span);
+        match self.kind {
+            EvaluationErrorKind::UndefinedVariable(_)
+            | EvaluationErrorKind::UndeclaredVariable(_) => {
+                diagnostic.with_note("variables must be declared with 'var' before use", None)
+            }
+            _ => diagnostic,
+        }
     }
 }
 
 #[derive(Debug)]
-pub enum EvaluationError<'de> {
+#[non_exhaustive]
+pub enum EvaluationErrorKind {
+    /// The operand of a unary operator is not a number.
     ExpectedNumber,
-    UndeclaredVariable(&'de str),
-    UndefinedVariable(&'de str),
+    /// An operand of a binary operator is not a number.
+    ExpectedNumbers,
+    /// Assignment to a variable which was never declared.
+    UndeclaredVariable(Rc<str>),
+    /// Read of a variable which was never declared.
+    UndefinedVariable(Rc<str>),
     WrongPlusOperands,
+    NotCallable,
+    WrongArity {
+        expected: usize,
+        got: usize,
+    },
+    /// Too many nested calls.
+    StackOverflow,
+    NotAnInstance,
+    NotAnInstanceField,
+    UndefinedProperty(Rc<str>),
+    SuperclassNotAClass,
+    /// The output of the interpreter could not be written.
+    Output(io::Error),
+    /// A native function returned an error.
+    NativeFailure {
+        name: Rc<str>,
+        message: String,
+    },
+}
+
+impl EvaluationErrorKind {
+    fn code(&self) -> &'static str {
+        match self {
+            EvaluationErrorKind::ExpectedNumber => "E0401",
+            EvaluationErrorKind::UndeclaredVariable(_) => "E0402",
+            EvaluationErrorKind::UndefinedVariable(_) => "E0403",
+            EvaluationErrorKind::WrongPlusOperands => "E0404",
+            EvaluationErrorKind::NotCallable => "E0405",
+            EvaluationErrorKind::WrongArity { .. } => "E0406",
+            EvaluationErrorKind::NotAnInstance => "E0407",
+            EvaluationErrorKind::NotAnInstanceField => "E0408",
+            EvaluationErrorKind::UndefinedProperty(_) => "E0409",
+            EvaluationErrorKind::SuperclassNotAClass => "E0410",
+            EvaluationErrorKind::StackOverflow => "E0411",
+            EvaluationErrorKind::NativeFailure { .. } => "E0412",
+            EvaluationErrorKind::Output(_) => "E0413",
+            EvaluationErrorKind::ExpectedNumbers => "E0414",
+        }
+    }
 }
 
-impl<'de> std::error::Error for EvaluationError<'de> {}
+impl std::error::Error for EvaluationError {}
+
+impl fmt::Display for EvaluationError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let line = self.span.line;
+        write!(f, "{}\n[line {line}]", self.kind)
+    }
+}
 
-impl fmt::Display for EvaluationError<'_> {
+impl fmt::Display for EvaluationErrorKind {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         match self {
-            EvaluationError::ExpectedNumber => {
-                let var_name = write!(f, "Operand must be a number.");
-                var_name
-            }
-            EvaluationError::WrongPlusOperands => {
+            EvaluationErrorKind::ExpectedNumber => write!(f, "Operand must be a number."),
+            EvaluationErrorKind::ExpectedNumbers => write!(f, "Operands must be numbers."),
+            EvaluationErrorKind::WrongPlusOperands => {
                 write!(f, "Operands must be two numbers or two strings.")
             }
-            EvaluationError::UndefinedVariable(ident) => write!(f, "Undefined variable '{ident}'."),
-            EvaluationError::UndeclaredVariable(ident) => {
-                write!(f, "Undeclared variable '{ident}'.")
+            EvaluationErrorKind::UndefinedVariable(ident) => {
+                write!(f, "Undefined variable '{ident}'.")
+            }
+            EvaluationErrorKind::UndeclaredVariable(ident) => {
+                write!(f, "Undefined variable '{ident}'.")
+            }
+            EvaluationErrorKind::NotCallable => write!(f, "Can only call functions and classes."),
+            EvaluationErrorKind::WrongArity { expected, got } => {
+                write!(f, "Expected {expected} arguments but got {got}.")
+            }
+            EvaluationErrorKind::StackOverflow => write!(f, "Stack overflow."),
+            EvaluationErrorKind::NotAnInstance => write!(f, "Only instances have properties."),
+            EvaluationErrorKind::NotAnInstanceField => write!(f, "Only instances have fields."),
+            EvaluationErrorKind::UndefinedProperty(name) => {
+                write!(f, "Undefined property '{name}'.")
+            }
+            EvaluationErrorKind::SuperclassNotAClass => write!(f, "Superclass must be a class."),
+            EvaluationErrorKind::Output(err) => write!(f, "Failed to write the output: {err}"),
+            EvaluationErrorKind::NativeFailure { name, message } => {
+                write!(f, "Error in native function '{name}': {message}")
             }
         }
     }
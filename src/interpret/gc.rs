@@ -0,0 +1,326 @@
+use std::{
+    cell::RefCell,
+    collections::HashMap,
+    mem,
+    rc::{Rc, Weak},
+};
+
+use super::{Class, Closure, Environment, HeapStats, Instance, Scope, Value};
+
+/// Number of tracked objects triggering the first collection.
+const INITIAL_COLLECTION_THRESHOLD: usize = 1024;
+
+/// After a collection, the next one happens when the heap has grown by this factor.
+const HEAP_GROW_FACTOR: usize = 2;
+
+/// Keeps track of the objects of the [`Interpreter`](super::Interpreter) to free the
+/// cycles reference counting can't: a closure stored in the scope it captures, or an
+/// instance holding a method bound to itself.
+///
+/// The objects are shared with `Rc`, and most are freed as soon as they are unused. The
+/// collector only needs to find the roots: every reference to an object which is not
+/// held by another tracked object comes from outside of the heap, that is from the scope
+/// chain of the interpreter, from the Rust stack of the calls being evaluated, or from
+/// the embedder. The objects not reachable from these roots are garbage, and emptying
+/// their scopes and fields breaks the cycles between them.
+pub(super) struct Heap {
+    objects: Vec<Tracked>,
+    next_collection: usize,
+    /// Collect before every allocation.
+    pub(super) stress: bool,
+    /// Report every collection on stderr.
+    pub(super) log: bool,
+    stats: HeapStats,
+}
+
+/// A weak reference to an object which can be part of a cycle.
+enum Tracked {
+    Scope(Weak<RefCell<Scope>>),
+    Closure(Weak<Closure>),
+    Class(Weak<Class>),
+    Instance(Weak<RefCell<Instance>>),
+}
+
+/// A tracked object, kept alive while the collector looks into it.
+enum Object {
+    Scope(Rc<RefCell<Scope>>),
+    Closure(Rc<Closure>),
+    Class(Rc<Class>),
+    Instance(Rc<RefCell<Instance>>),
+}
+
+impl Tracked {
+    fn strong_count(&self) -> usize {
+        match self {
+            Tracked::Scope(scope) => scope.strong_count(),
+            Tracked::Closure(closure) => closure.strong_count(),
+            Tracked::Class(class) => class.strong_count(),
+            Tracked::Instance(instance) => instance.strong_count(),
+        }
+    }
+
+    /// Address of the object, which identifies it.
+    fn address(&self) -> *const () {
+        match self {
+            Tracked::Scope(scope) => scope.as_ptr().cast(),
+            Tracked::Closure(closure) => closure.as_ptr().cast(),
+            Tracked::Class(class) => class.as_ptr().cast(),
+            Tracked::Instance(instance) => instance.as_ptr().cast(),
+        }
+    }
+
+    fn upgrade(&self) -> Option<Object> {
+        Some(match self {
+            Tracked::Scope(scope) => Object::Scope(scope.upgrade()?),
+            Tracked::Closure(closure) => Object::Closure(closure.upgrade()?),
+            Tracked::Class(class) => Object::Class(class.upgrade()?),
+            Tracked::Instance(instance) => Object::Instance(instance.upgrade()?),
+        })
+    }
+
+    /// Name of the kind of object, for the logs of the collector.
+    fn kind(&self) -> &'static str {
+        match self {
+            Tracked::Scope(_) => "scope",
+            Tracked::Closure(_) => "closure",
+            Tracked::Class(_) => "class",
+            Tracked::Instance(_) => "instance",
+        }
+    }
+}
+
+impl Object {
+    /// Push the addresses of the objects this one refers to, or return `false` if its
+    /// content is borrowed and can't be looked at.
+    fn references(&self, references: &mut Vec<*const ()>) -> bool {
+        match self {
+            Object::Scope(scope) => {
+                let Ok(scope) = scope.try_borrow() else {
+                    return false;
+                };
+                references.extend(scope.values.values().filter_map(Value::address));
+                references.extend(
+                    scope
+                        .enclosing
+                        .iter()
+                        .map(|enclosing| address(&enclosing.0)),
+                );
+            }
+            Object::Closure(closure) => references.push(address(&closure.environment.0)),
+            Object::Class(class) => {
+                references.extend(class.superclass.iter().map(address));
+                references.extend(class.methods.values().map(address));
+            }
+            Object::Instance(instance) => {
+                let Ok(instance) = instance.try_borrow() else {
+                    return false;
+                };
+                references.push(address(&instance.class));
+                references.extend(instance.fields.values().filter_map(Value::address));
+            }
+        }
+        true
+    }
+
+    /// Empty the scope or the instance, dropping the references it holds.
+    fn clear(&self, garbage: &mut Vec<Value>, scopes: &mut Vec<Environment>) {
+        match self {
+            Object::Scope(scope) => {
+                let mut scope = scope.borrow_mut();
+                garbage.extend(mem::take(&mut scope.values).into_values());
+                scopes.extend(scope.enclosing.take());
+            }
+            Object::Instance(instance) => {
+                let fields = mem::take(&mut instance.borrow_mut().fields);
+                garbage.extend(fields.into_values());
+            }
+            // They can't be changed, so they are freed along with the scopes and the
+            // instances referring to them.
+            Object::Closure(_) | Object::Class(_) => {}
+        }
+    }
+}
+
+fn address<T>(object: &Rc<T>) -> *const () {
+    Rc::as_ptr(object).cast()
+}
+
+impl Value {
+    /// Address of the tracked object the value refers to.
+    fn address(&self) -> Option<*const ()> {
+        match self {
+            Value::Function(closure) => Some(address(closure)),
+            Value::Class(class) => Some(address(class)),
+            Value::Instance(instance) => Some(address(instance)),
+            Value::Boolean(_)
+            | Value::Number(_)
+            | Value::String(_)
+            | Value::Nil
+            | Value::NativeFunction(_) => None,
+        }
+    }
+}
+
+impl Default for Heap {
+    fn default() -> Self {
+        Self {
+            objects: Vec::new(),
+            next_collection: INITIAL_COLLECTION_THRESHOLD,
+            stress: false,
+            log: false,
+            stats: HeapStats {
+                next_collection: INITIAL_COLLECTION_THRESHOLD,
+                ..HeapStats::default()
+            },
+        }
+    }
+}
+
+impl Heap {
+    pub(super) fn alloc_scope(&mut self, enclosing: Option<Environment>) -> Environment {
+        let scope = Rc::new(RefCell::new(Scope {
+            values: HashMap::new(),
+            enclosing,
+        }));
+        self.track(Tracked::Scope(Rc::downgrade(&scope)));
+        Environment(scope)
+    }
+
+    pub(super) fn alloc_closure(&mut self, closure: Closure) -> Rc<Closure> {
+        let closure = Rc::new(closure);
+        self.track(Tracked::Closure(Rc::downgrade(&closure)));
+        closure
+    }
+
+    pub(super) fn alloc_class(&mut self, class: Class) -> Rc<Class> {
+        let class = Rc::new(class);
+        self.track(Tracked::Class(Rc::downgrade(&class)));
+        class
+    }
+
+    pub(super) fn alloc_instance(&mut self, instance: Instance) -> Rc<RefCell<Instance>> {
+        let instance = Rc::new(RefCell::new(instance));
+        self.track(Tracked::Instance(Rc::downgrade(&instance)));
+        instance
+    }
+
+    /// The new object is held by the caller, so it survives a collection.
+    fn track(&mut self, object: Tracked) {
+        if self.stress || self.objects.len() >= self.next_collection {
+            self.collect();
+        }
+        self.objects.push(object);
+        self.stats.allocated_objects += 1;
+    }
+
+    pub(super) fn stats(&self) -> HeapStats {
+        let live_objects = self
+            .objects
+            .iter()
+            .filter(|object| object.strong_count() > 0)
+            .count();
+        HeapStats {
+            live_objects,
+            ..self.stats
+        }
+    }
+
+    /// Free the cycles of objects not reachable from outside of the heap.
+    pub(super) fn collect(&mut self) {
+        // Forget the objects already freed by reference counting.
+        self.objects.retain(|object| object.strong_count() > 0);
+        let before = self.objects.len();
+        if self.log {
+            eprintln!("-- gc begin ({before} objects)");
+        }
+
+        let indices: HashMap<*const (), usize> = self
+            .objects
+            .iter()
+            .enumerate()
+            .map(|(index, object)| (object.address(), index))
+            .collect();
+        let resolve = |references: &mut Vec<*const ()>| -> Vec<usize> {
+            references
+                .drain(..)
+                .filter_map(|reference| indices.get(&reference).copied())
+                .collect()
+        };
+
+        // The references from outside of the heap are the ones left once the references
+        // between tracked objects are taken off. An object whose content is borrowed is
+        // being used, so it is a root too.
+        let mut external: Vec<usize> = self.objects.iter().map(Tracked::strong_count).collect();
+        let mut edges = Vec::with_capacity(self.objects.len());
+        let mut references = Vec::new();
+        for (index, object) in self.objects.iter().enumerate() {
+            let object = object.upgrade().expect("freed objects were forgotten");
+            if !object.references(&mut references) {
+                external[index] += 1;
+            }
+            let targets = resolve(&mut references);
+            for &target in &targets {
+                external[target] -= 1;
+            }
+            edges.push(targets);
+        }
+
+        // Mark: `gray` holds the objects reached but whose references are not traced yet.
+        let mut marks = vec![false; self.objects.len()];
+        let mut gray: Vec<usize> = (0..self.objects.len())
+            .filter(|&index| external[index] > 0)
+            .collect();
+        while let Some(index) = gray.pop() {
+            if mem::replace(&mut marks[index], true) {
+                continue;
+            }
+            gray.extend(&edges[index]);
+        }
+
+        // Sweep: empty the garbage, which then frees itself. The objects are kept alive
+        // until they are all emptied, and what they held is dropped last.
+        let mut garbage = Vec::new();
+        for (index, object) in self.objects.iter().enumerate() {
+            if marks[index] {
+                continue;
+            }
+            if self.log {
+                eprintln!("free {index} {}", object.kind());
+            }
+            garbage.push(object.upgrade().expect("freed objects were forgotten"));
+        }
+        let freed = garbage.len();
+        let mut values = Vec::new();
+        let mut scopes = Vec::new();
+        for object in &garbage {
+            object.clear(&mut values, &mut scopes);
+        }
+        drop(garbage);
+        drop(values);
+        drop(scopes);
+        self.objects.retain(|object| object.strong_count() > 0);
+
+        self.stats.freed_objects += freed;
+        self.stats.collections += 1;
+        self.next_collection =
+            (self.objects.len() * HEAP_GROW_FACTOR).max(INITIAL_COLLECTION_THRESHOLD);
+        self.stats.next_collection = self.next_collection;
+        if self.log {
+            eprintln!(
+                "-- gc end: freed {freed} objects ({before} -> {}), next at {}",
+                self.objects.len(),
+                self.next_collection
+            );
+        }
+    }
+}
+
+/// The heap is dropped after the scopes of the interpreter: the cycles they were part of
+/// are garbage now.
+impl Drop for Heap {
+    fn drop(&mut self) {
+        if !self.objects.is_empty() {
+            self.collect();
+        }
+    }
+}
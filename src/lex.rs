@@ -1,9 +1,27 @@
-use std::{fmt, iter::Peekable, str::CharIndices};
+use std::{borrow::Cow, fmt, iter::Peekable, str::CharIndices};
+
+use unicode_ident::{is_xid_continue, is_xid_start};
+
+use crate::diagnostic::Diagnostic;
 
 pub struct Lexer<'de> {
     file_content: &'de str,
     chars: Peekable<CharIndices<'de>>,
     line_count: usize,
+    /// Byte offset of the beginning of the current line.
+    line_start: usize,
+    /// Byte offset and column of the last token start, the columns of the following
+    /// tokens are counted from there instead of from the beginning of the line.
+    column_mark: (usize, usize),
+    /// Whether the end of file token has been produced.
+    done: bool,
+    /// Whether `///` comments are produced as tokens instead of being skipped.
+    doc_comments: bool,
+    /// Whether the whitespace and the comments are produced as tokens too.
+    trivia: bool,
+    /// Token produced right after the error reported on it, so the parser still gets a
+    /// literal and doesn't report a second error.
+    recovered: Option<Spanned<Token<'de>>>,
 }
 
 impl<'de> Lexer<'de> {
@@ -12,14 +30,333 @@ impl<'de> Lexer<'de> {
             file_content,
             chars: file_content.char_indices().peekable(),
             line_count: 1,
+            line_start: 0,
+            column_mark: (0, 1),
+            done: false,
+            doc_comments: false,
+            trivia: false,
+            recovered: None,
+        }
+    }
+
+    /// Produce the `///` comments as [`Token::DocComment`], for the tools attaching them
+    /// to the following declaration. The parser doesn't expect them.
+    ///
+    /// ```
+    /// use lox::{Lexer, Token};
+    ///
+    /// let mut tokens = Lexer::new("/// Says hi.\nfun hi() {}").with_doc_comments();
+    /// let comment = tokens.next().unwrap().unwrap();
+    /// assert_eq!(comment.node, Token::DocComment(" Says hi."));
+    /// ```
+    pub fn with_doc_comments(mut self) -> Self {
+        self.doc_comments = true;
+        self
+    }
+
+    /// Lossless mode: the whitespace and the comments are produced as
+    /// [`Token::Whitespace`], [`Token::Comment`] and [`Token::DocComment`], so the tokens
+    /// cover the whole source. This is what the [`cst`](crate::cst) is built from.
+    pub fn with_trivia(mut self) -> Self {
+        self.doc_comments = true;
+        self.trivia = true;
+        self
+    }
+
+    /// Span from `start` to the character we are about to read, for tokens that can
+    /// span several lines: they are located at the line and column they start on.
+    fn multiline_span(&mut self, start: usize, line: usize, column: usize) -> Span {
+        Span {
+            start,
+            end: self.offset(),
+            line,
+            column,
+        }
+    }
+
+    /// Consume the whitespace following the character at `start`, counting the lines.
+    fn whitespace(&mut self, start: usize) -> Span {
+        let line = self.line_count;
+        let column = self.column(start);
+        let mut next = self.file_content[start..]
+            .chars()
+            .next()
+            .map(|c| (start, c));
+        while let Some((i, c)) = next {
+            if c == '\n' {
+                self.line_count += 1;
+                self.line_start = i + 1;
+            }
+            next = self.chars.next_if(|(_, c)| c.is_whitespace());
+        }
+        self.multiline_span(start, line, column)
+    }
+
+    /// Skip a block comment, whose `/*` starts at `start`. They can be nested.
+    fn block_comment(&mut self, start: usize) -> Result<Span, LexingError> {
+        let line = self.line_count;
+        let column = self.column(start);
+        let mut depth = 1;
+        while depth > 0 {
+            let Some((i, c)) = self.chars.next() else {
+                return Err(LexingError {
+                    kind: LexingErrorKind::UnterminatedBlockComment,
+                    span: Span {
+                        start,
+                        end: start + 2,
+                        line,
+                        column,
+                    },
+                });
+            };
+            match c {
+                '/' if self.chars.next_if(|(_, c)| c == &'*').is_some() => depth += 1,
+                '*' if self.chars.next_if(|(_, c)| c == &'/').is_some() => depth -= 1,
+                '\n' => {
+                    self.line_count += 1;
+                    self.line_start = i + 1;
+                }
+                _ => {}
+            }
+        }
+        Ok(self.multiline_span(start, line, column))
+    }
+
+    /// Lex a string literal starting with the quote at `start`, decoding its escape
+    /// sequences. The string is only copied if it has some. An invalid escape sequence is
+    /// reported, and kept as written in the string produced after the error.
+    fn string(&mut self, start: usize) -> Result<Spanned<Token<'de>>, LexingError> {
+        // The string can span several lines, its location is the one of its beginning.
+        let line = self.line_count;
+        let column = self.column(start);
+        let mut decoded: Option<String> = None;
+        let mut invalid_escape = None;
+        let end = loop {
+            let Some((i, c)) = self.chars.next() else {
+                return Err(LexingError {
+                    kind: LexingErrorKind::UnterminatedString,
+                    span: Span {
+                        start,
+                        end: self.file_content.len(),
+                        line,
+                        column,
+                    },
+                });
+            };
+            let c = match c {
+                '"' => break i,
+                '\\' => match self.escape(i) {
+                    Ok(c) => {
+                        decoded
+                            .get_or_insert_with(|| self.file_content[start + 1..i].to_string())
+                            .push(c);
+                        continue;
+                    }
+                    Err(err) => {
+                        // Keep going until the end of the string to resume after it.
+                        invalid_escape.get_or_insert(err);
+                        decoded
+                            .get_or_insert_with(|| self.file_content[start + 1..i].to_string())
+                            .push_str(&self.file_content[i..self.offset()]);
+                        continue;
+                    }
+                },
+                '\n' => {
+                    self.line_count += 1;
+                    self.line_start = i + 1;
+                    c
+                }
+                c => c,
+            };
+            if let Some(decoded) = &mut decoded {
+                decoded.push(c);
+            }
+        };
+
+        let value = decoded.map_or(
+            Cow::Borrowed(&self.file_content[start + 1..end]),
+            Cow::Owned,
+        );
+        let token = Spanned {
+            node: Token::String(value, &self.file_content[start..=end]),
+            span: Span {
+                start,
+                end: end + 1,
+                line,
+                column,
+            },
+        };
+        match invalid_escape {
+            Some(err) => {
+                self.recovered = Some(token);
+                Err(err)
+            }
+            None => Ok(token),
+        }
+    }
+
+    /// Decode the escape sequence starting with the backslash at `start`.
+    fn escape(&mut self, start: usize) -> Result<char, LexingError> {
+        // A backslash at the end of a line is invalid, the line break is left to the
+        // caller so the lines are still counted.
+        let escaped = self.chars.next_if(|(_, c)| c != &'\n').map(|(_, c)| c);
+        let c = match escaped {
+            Some('n') => Some('\n'),
+            Some('t') => Some('\t'),
+            Some('"') => Some('"'),
+            Some('\\') => Some('\\'),
+            Some('u') => self.unicode_escape(),
+            _ => None,
+        };
+        c.ok_or_else(|| LexingError {
+            kind: LexingErrorKind::InvalidEscapeSequence,
+            span: self.span_from(start),
+        })
+    }
+
+    /// The character of a `\u{...}` escape, from 1 to 6 hexadecimal digits.
+    fn unicode_escape(&mut self) -> Option<char> {
+        self.chars.next_if(|(_, c)| c == &'{')?;
+        let mut code = 0;
+        let mut digits = 0;
+        while let Some((_, c)) = self.chars.next_if(|(_, c)| c.is_ascii_hexdigit()) {
+            code = code * 16 + c.to_digit(16)?;
+            digits += 1;
+            if digits > 6 {
+                return None;
+            }
+        }
+        self.chars.next_if(|(_, c)| c == &'}')?;
+        if digits == 0 {
+            return None;
+        }
+        char::from_u32(code)
+    }
+
+    /// Lex a number literal starting at `start`: decimal with an optional fraction and
+    /// exponent, or an integer prefixed by `0x`, `0b` or `0o`. Digits can be separated by
+    /// underscores. `None` if it is malformed, or too large to be represented, like
+    /// `1e400`.
+    fn number(&mut self, start: usize) -> Option<f64> {
+        let radix = match self.file_content[start..].get(..2) {
+            Some("0x") => 16,
+            Some("0b") => 2,
+            Some("0o") => 8,
+            _ => 10,
+        };
+        if radix != 10 {
+            self.chars.next();
+            self.consume_alphanumerics();
+            let digits = &self.file_content[start + 2..self.offset()];
+            return integer(digits, radix).filter(|number| number.is_finite());
+        }
+
+        self.consume_digits();
+        // `123.` is the number `123` followed by a dot, for instance for a method call.
+        let fraction = self.file_content[self.offset()..]
+            .strip_prefix('.')
+            .is_some_and(|rest| rest.starts_with(|c: char| c.is_ascii_digit()));
+        if fraction {
+            self.chars.next();
+            self.consume_digits();
+        }
+        if self
+            .chars
+            .next_if(|(_, c)| matches!(c, 'e' | 'E'))
+            .is_some()
+        {
+            self.chars.next_if(|(_, c)| matches!(c, '+' | '-'));
+            self.consume_digits();
+        }
+        // Letters right after a number, like in `12abc`, make it invalid.
+        self.consume_alphanumerics();
+
+        let literal = &self.file_content[start..self.offset()];
+        if !separators_are_valid(literal, 10) {
+            return None;
         }
+        literal
+            .replace('_', "")
+            .parse()
+            .ok()
+            .filter(|number: &f64| number.is_finite())
+    }
+
+    fn consume_digits(&mut self) {
+        while self
+            .chars
+            .next_if(|(_, c)| c.is_ascii_digit() || c == &'_')
+            .is_some()
+        {}
+    }
+
+    /// Consume the characters that can continue an identifier: letters, digits and
+    /// underscores, in the Unicode sense.
+    fn consume_alphanumerics(&mut self) {
+        while self.chars.next_if(|(_, c)| is_xid_continue(*c)).is_some() {}
     }
+
+    /// Byte offset of the character we are about to read.
+    fn offset(&mut self) -> usize {
+        self.chars
+            .peek()
+            .map_or(self.file_content.len(), |(i, _)| *i)
+    }
+
+    /// Column of the character at `start`, counted in characters from 1. The tokens are
+    /// lexed in order, so each character is only counted once.
+    fn column(&mut self, start: usize) -> usize {
+        let (mut offset, mut column) = self.column_mark;
+        if offset < self.line_start || offset > start {
+            (offset, column) = (self.line_start, 1);
+        }
+        column += self.file_content[offset..start].chars().count();
+        self.column_mark = (start, column);
+        column
+    }
+
+    /// Span from `start` to the character we are about to read.
+    fn span_from(&mut self, start: usize) -> Span {
+        Span {
+            start,
+            end: self.offset(),
+            line: self.line_count,
+            column: self.column(start),
+        }
+    }
+}
+
+/// The value of the digits of an integer in the given radix.
+fn integer(digits: &str, radix: u32) -> Option<f64> {
+    if digits.is_empty() || !separators_are_valid(digits, radix) {
+        return None;
+    }
+    digits
+        .chars()
+        .filter(|c| c != &'_')
+        .try_fold(0.0, |value, c| {
+            let digit = c.to_digit(radix)?;
+            Some(value * f64::from(radix) + f64::from(digit))
+        })
+}
+
+/// Whether every underscore of the literal is between two digits.
+fn separators_are_valid(literal: &str, radix: u32) -> bool {
+    let is_digit = |c: Option<char>| c.is_some_and(|c| c.is_digit(radix));
+    literal.char_indices().all(|(i, c)| {
+        c != '_'
+            || (is_digit(literal[..i].chars().next_back())
+                && is_digit(literal[i + 1..].chars().next()))
+    })
 }
 
 impl<'de> Iterator for Lexer<'de> {
-    type Item = Result<Token<'de>, LexingError>;
+    type Item = Result<Spanned<Token<'de>>, LexingError>;
 
     fn next(&mut self) -> Option<Self::Item> {
+        if let Some(token) = self.recovered.take() {
+            return Some(Ok(token));
+        }
         while let Some((i, c)) = self.chars.next() {
             let token = match c {
                 '(' => Token::LeftParen,
@@ -61,64 +398,65 @@ impl<'de> Iterator for Lexer<'de> {
                     }
                 }
                 '/' => {
-                    // We ignore the rest of the line
-                    if self.chars.next_if(|(_, c)| c == &'/').is_some() {
+                    if self.chars.next_if(|(_, c)| c == &'*').is_some() {
+                        match self.block_comment(i) {
+                            Ok(span) if self.trivia => {
+                                let node = Token::Comment(&self.file_content[i..span.end]);
+                                return Some(Ok(Spanned { node, span }));
+                            }
+                            Ok(_) => continue,
+                            Err(err) => return Some(Err(err)),
+                        }
+                    }
+                    if self.chars.next_if(|(_, c)| c == &'/').is_none() {
+                        Token::Slash
+                    } else {
+                        // We ignore the rest of the line, unless it is a doc comment:
+                        // `///` but not `////`.
                         while self.chars.next_if(|(_, c)| c != &'\n').is_some() {}
-                        continue;
+                        let comment = &self.file_content[i..self.offset()];
+                        match comment.strip_prefix("///") {
+                            Some(text) if self.doc_comments && !text.starts_with('/') => {
+                                Token::DocComment(text)
+                            }
+                            _ if self.trivia => Token::Comment(comment),
+                            _ => continue,
+                        }
                     }
-                    Token::Slash
                 }
-                ' ' | '\t' => {
-                    continue;
+                c if c.is_whitespace() && self.trivia => {
+                    let span = self.whitespace(i);
+                    let node = Token::Whitespace(&self.file_content[i..span.end]);
+                    return Some(Ok(Spanned { node, span }));
                 }
                 '\n' => {
                     self.line_count += 1;
+                    self.line_start = i + 1;
                     continue;
                 }
-                '"' => match self.chars.find(|(_, c)| c == &'"') {
-                    Some((end, _)) => Token::String(&self.file_content[i + 1..end]),
+                // Including the `\r` of the Windows line endings.
+                c if c.is_whitespace() => {
+                    continue;
+                }
+                '"' => return Some(self.string(i)),
+                '0'..='9' => match self.number(i) {
+                    Some(number) => Token::Number(number, &self.file_content[i..self.offset()]),
                     None => {
+                        // The number is still produced, with the value 0.
+                        let span = self.span_from(i);
+                        self.recovered = Some(Spanned {
+                            node: Token::Number(0.0, &self.file_content[i..span.end]),
+                            span,
+                        });
                         return Some(Err(LexingError {
-                            kind: LexingErrorKind::UnterminatedString,
-                            line_count: self.line_count,
+                            kind: LexingErrorKind::InvalidNumber,
+                            span,
                         }));
                     }
                 },
-                '0'..='9' => {
-                    let mut first_dot = false;
-                    let mut end = i;
-                    while let Some((_, c)) = self.chars.peek() {
-                        if c == &'.' && first_dot {
-                            break;
-                        }
-                        if c == &'.' {
-                            first_dot = true;
-                            end += 1;
-                            self.chars.next();
-                            continue;
-                        }
-
-                        if !c.is_ascii_digit() {
-                            break;
-                        }
-                        end += 1;
-                        self.chars.next();
-                    }
-
-                    let number_str = &self.file_content[i..=end];
-                    let number: f64 = number_str.parse().unwrap();
-                    Token::Number(number, number_str)
-                }
-                'a'..='z' | 'A'..='Z' | '_' => {
-                    let mut end = i;
-                    while self
-                        .chars
-                        .next_if(|(_, c)| matches!(c, 'a'..='z' | 'A'..='Z' | '_' | '0'..='9' ))
-                        .is_some()
-                    {
-                        end += 1;
-                    }
-                    let identifier = &self.file_content[i..=end];
+                c if c == '_' || is_xid_start(c) => {
+                    self.consume_alphanumerics();
+                    let identifier = &self.file_content[i..self.offset()];
                     match identifier {
                         "and" => Token::And,
                         "class" => Token::Class,
@@ -142,13 +480,55 @@ impl<'de> Iterator for Lexer<'de> {
                 c => {
                     return Some(Err(LexingError {
                         kind: LexingErrorKind::UnexpectedCharacter(c),
-                        line_count: self.line_count,
+                        span: self.span_from(i),
                     }));
                 }
             };
-            return Some(Ok(token));
+            return Some(Ok(Spanned {
+                node: token,
+                span: self.span_from(i),
+            }));
         }
-        None
+        if self.done {
+            return None;
+        }
+        self.done = true;
+        Some(Ok(Spanned {
+            node: Token::Eof,
+            span: self.span_from(self.file_content.len()),
+        }))
+    }
+}
+
+/// Location of a piece of source code.
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub struct Span {
+    /// Byte offset of the first character.
+    pub start: usize,
+    /// Byte offset right after the last character.
+    pub end: usize,
+    pub line: usize,
+    /// Column of the first character, starting at 1.
+    pub column: usize,
+}
+
+/// A token or a tree node, along with its location in the source.
+#[derive(Debug, PartialEq)]
+pub struct Spanned<T> {
+    pub node: T,
+    pub span: Span,
+}
+
+impl<T: fmt::Display> fmt::Display for Spanned<T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.node)
+    }
+}
+
+// Allows to compare the tokens regardless of their location.
+impl<'de> PartialEq<Token<'de>> for Spanned<Token<'de>> {
+    fn eq(&self, other: &Token<'de>) -> bool {
+        &self.node == other
     }
 }
 
@@ -173,7 +553,8 @@ pub enum Token<'de> {
     GreaterEqual,
     Greater,
     Slash,
-    String(&'de str),
+    /// The value, with the escape sequences decoded, and the source with the quotes.
+    String(Cow<'de, str>, &'de str),
     Number(f64, &'de str),
     Identifier(&'de str),
     And,
@@ -192,6 +573,63 @@ pub enum Token<'de> {
     Var,
     While,
     Print,
+    /// The text after the `///`, only produced by [`Lexer::with_doc_comments`].
+    DocComment(&'de str),
+    /// Only produced by [`Lexer::with_trivia`].
+    Whitespace(&'de str),
+    /// A line or block comment, with its delimiters. Only produced by
+    /// [`Lexer::with_trivia`].
+    Comment(&'de str),
+    Eof,
+}
+
+impl<'de> Token<'de> {
+    /// The source of the token, as written in the file.
+    pub fn lexeme(&self) -> Cow<'de, str> {
+        match self {
+            Token::LeftParen => Cow::Borrowed("("),
+            Token::RightParen => Cow::Borrowed(")"),
+            Token::LeftBrace => Cow::Borrowed("{"),
+            Token::RightBrace => Cow::Borrowed("}"),
+            Token::Comma => Cow::Borrowed(","),
+            Token::Dot => Cow::Borrowed("."),
+            Token::Minus => Cow::Borrowed("-"),
+            Token::Plus => Cow::Borrowed("+"),
+            Token::Semicolon => Cow::Borrowed(";"),
+            Token::Star => Cow::Borrowed("*"),
+            Token::EqualEqual => Cow::Borrowed("=="),
+            Token::Equal => Cow::Borrowed("="),
+            Token::BangEqual => Cow::Borrowed("!="),
+            Token::Bang => Cow::Borrowed("!"),
+            Token::LessEqual => Cow::Borrowed("<="),
+            Token::Less => Cow::Borrowed("<"),
+            Token::GreaterEqual => Cow::Borrowed(">="),
+            Token::Greater => Cow::Borrowed(">"),
+            Token::Slash => Cow::Borrowed("/"),
+            Token::String(_, lexeme) | Token::Number(_, lexeme) | Token::Identifier(lexeme) => {
+                Cow::Borrowed(lexeme)
+            }
+            Token::And => Cow::Borrowed("and"),
+            Token::Class => Cow::Borrowed("class"),
+            Token::Else => Cow::Borrowed("else"),
+            Token::False => Cow::Borrowed("false"),
+            Token::For => Cow::Borrowed("for"),
+            Token::Fun => Cow::Borrowed("fun"),
+            Token::If => Cow::Borrowed("if"),
+            Token::Nil => Cow::Borrowed("nil"),
+            Token::Or => Cow::Borrowed("or"),
+            Token::Return => Cow::Borrowed("return"),
+            Token::Super => Cow::Borrowed("super"),
+            Token::This => Cow::Borrowed("this"),
+            Token::True => Cow::Borrowed("true"),
+            Token::Var => Cow::Borrowed("var"),
+            Token::While => Cow::Borrowed("while"),
+            Token::Print => Cow::Borrowed("print"),
+            Token::DocComment(text) => Cow::Owned(format!("///{text}")),
+            Token::Whitespace(text) | Token::Comment(text) => Cow::Borrowed(text),
+            Token::Eof => Cow::Borrowed(""),
+        }
+    }
 }
 
 impl fmt::Display for Token<'_> {
@@ -216,7 +654,7 @@ impl fmt::Display for Token<'_> {
             Token::GreaterEqual => write!(f, "GREATER_EQUAL >= null"),
             Token::Greater => write!(f, "GREATER > null"),
             Token::Slash => write!(f, "SLASH / null"),
-            Token::String(literal) => write!(f, "STRING \"{literal}\" {literal}"),
+            Token::String(value, lexeme) => write!(f, "STRING {lexeme} {value}"),
             Token::Number(number, number_str) => write!(f, "NUMBER {number_str} {number:?}"),
             Token::Identifier(ident) => write!(f, "IDENTIFIER {ident} null"),
             Token::And => write!(f, "AND and null"),
@@ -235,20 +673,48 @@ impl fmt::Display for Token<'_> {
             Token::Var => write!(f, "VAR var null"),
             Token::While => write!(f, "WHILE while null"),
             Token::Print => write!(f, "PRINT print null"),
+            Token::DocComment(text) => write!(f, "DOC_COMMENT ///{text} null"),
+            Token::Whitespace(text) => write!(f, "WHITESPACE {text:?} null"),
+            Token::Comment(text) => write!(f, "COMMENT {text} null"),
+            Token::Eof => write!(f, "EOF  null"),
         }
     }
 }
+
 #[derive(Debug)]
 pub struct LexingError {
     kind: LexingErrorKind,
-    line_count: usize,
+    span: Span,
+}
+
+impl LexingError {
+    pub fn kind(&self) -> &LexingErrorKind {
+        &self.kind
+    }
+
+    pub fn diagnostic(&self) -> Diagnostic {
+        let diagnostic = Diagnostic::new(self.kind.code(), self.to_string(), self.span);
+        match self.kind {
+            LexingErrorKind::UnterminatedString => {
+                diagnostic.with_note("add a closing '\"' to end the string", None)
+            }
+            LexingErrorKind::InvalidEscapeSequence => diagnostic.with_note(
+                "the valid escape sequences are \\n, \\t, \\\", \\\\ and \\u{...}",
+                None,
+            ),
+            LexingErrorKind::UnterminatedBlockComment => {
+                diagnostic.with_note("add a closing '*/' to end the comment", None)
+            }
+            LexingErrorKind::UnexpectedCharacter(_) | LexingErrorKind::InvalidNumber => diagnostic,
+        }
+    }
 }
 
 impl std::error::Error for LexingError {}
 
 impl fmt::Display for LexingError {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-        let line_count = self.line_count;
+        let line_count = self.span.line;
         write!(f, "[line {line_count}] Error: ")?;
         match self.kind {
             LexingErrorKind::UnterminatedString => {
@@ -257,12 +723,74 @@ impl fmt::Display for LexingError {
             LexingErrorKind::UnexpectedCharacter(c) => {
                 write!(f, "Unexpected character: {c}")
             }
+            LexingErrorKind::InvalidEscapeSequence => write!(f, "Invalid escape sequence."),
+            LexingErrorKind::InvalidNumber => write!(f, "Invalid number."),
+            LexingErrorKind::UnterminatedBlockComment => write!(f, "Unterminated block comment."),
         }
     }
 }
 
 #[derive(Debug)]
+#[non_exhaustive]
 pub enum LexingErrorKind {
     UnterminatedString,
     UnexpectedCharacter(char),
+    InvalidEscapeSequence,
+    InvalidNumber,
+    UnterminatedBlockComment,
+}
+
+impl LexingErrorKind {
+    fn code(&self) -> &'static str {
+        match self {
+            LexingErrorKind::UnterminatedString => "E0101",
+            LexingErrorKind::UnexpectedCharacter(_) => "E0102",
+            LexingErrorKind::InvalidEscapeSequence => "E0103",
+            LexingErrorKind::InvalidNumber => "E0104",
+            LexingErrorKind::UnterminatedBlockComment => "E0105",
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Line and column of every token of `source`, errors included.
+    fn locations(source: &str) -> Vec<(usize, usize)> {
+        Lexer::new(source)
+            .map(|token| match token {
+                Ok(token) => (token.span.line, token.span.column),
+                Err(err) => (err.span.line, err.span.column),
+            })
+            .collect()
+    }
+
+    #[test]
+    fn columns_are_counted_in_characters() {
+        assert_eq!(
+            locations("var café = \"naïve\";"),
+            [(1, 1), (1, 5), (1, 10), (1, 12), (1, 19), (1, 20)]
+        );
+    }
+
+    #[test]
+    fn columns_restart_on_each_line() {
+        assert_eq!(
+            locations("ünï;\nx;"),
+            [(1, 1), (1, 4), (2, 1), (2, 2), (2, 3)]
+        );
+    }
+
+    #[test]
+    fn columns_after_a_multiline_token() {
+        let source = "\"é\nà\" é;\n/* ü\n */ x";
+        assert_eq!(locations(source), [(1, 1), (2, 4), (2, 5), (4, 5), (4, 6)]);
+    }
+
+    #[test]
+    fn errors_are_located_in_characters() {
+        let source = "\"ü\\q\" ü @";
+        assert_eq!(locations(source), [(1, 3), (1, 1), (1, 7), (1, 9), (1, 10)]);
+    }
 }
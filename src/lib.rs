@@ -0,0 +1,39 @@
+//! A tree-walking interpreter for the Lox language.
+//!
+//! The simplest way to embed it is to keep an [`Interpreter`] around and feed it programs:
+//!
+//! ```
+//! let mut interpreter = lox::Interpreter::new();
+//! interpreter.run_source("var greeting = \"Hello\";").unwrap();
+//! interpreter.run_source("print greeting + \", world!\";").unwrap();
+//! ```
+//!
+//! Each stage is also available on its own: the [`Lexer`] produces the tokens, the
+//! [`parse`] module builds the syntax trees, the [`resolve`] pass binds the variables and
+//! the [`Interpreter`] evaluates the trees.
+//!
+//! Alternatively, the [`compile`] module turns the trees into bytecode, run by the
+//! stack-based [`Vm`](vm::Vm).
+//!
+//! Tools that need to preserve the formatting, comments included, can use the lossless
+//! syntax trees of the [`cst`] module instead.
+
+pub mod chunk;
+pub mod compile;
+pub mod cst;
+pub mod diagnostic;
+mod intern;
+pub mod interpret;
+pub mod lex;
+pub mod native;
+pub mod parse;
+pub mod resolve;
+pub mod vm;
+
+pub use crate::{
+    interpret::{EvaluationError, EvaluationErrorKind, HeapStats, Interpreter, RunError, Value},
+    lex::{Lexer, LexingError, LexingErrorKind, Span, Spanned, Token},
+    native::{ConversionError, NativeFunction},
+    parse::{parse_expr, parse_statements, ParseErrorKind, ParseExpressionError},
+    resolve::{resolve, ResolveError, ResolveErrorKind},
+};
@@ -1,18 +1,40 @@
-mod interpret;
-mod lex;
-mod parse;
+mod repl;
 
-use crate::{
-    interpret::Interpreter,
-    lex::Lexer,
-    parse::{parse_expr, parse_statements},
+use lox::{
+    compile::compile_source, cst, diagnostic::Emitter, parse_expr, vm::Vm, Interpreter, Lexer,
+    LexingError, ParseExpressionError, Spanned, Token,
 };
-use std::{env, fs};
+use std::{env, fs, thread};
+
+/// The interpreter recurses on the native stack, with several frames per Lox call,
+/// much bigger in debug builds. This leaves room for the deepest recursion allowed
+/// before a stack overflow is reported.
+const STACK_SIZE: usize = 64 * 1024 * 1024;
 
 fn main() {
+    let child = thread::Builder::new()
+        .stack_size(STACK_SIZE)
+        .spawn(run)
+        .expect("the interpreter thread starts");
+    if let Err(panic) = child.join() {
+        std::panic::resume_unwind(panic);
+    }
+}
+
+fn run() {
     let args: Vec<String> = env::args().collect();
+    if args.len() == 1 || args[1] == "repl" {
+        if let Err(err) = repl::run() {
+            eprintln!("{err}");
+            std::process::exit(74);
+        }
+        return;
+    }
     if args.len() < 3 {
-        eprintln!("Usage: {} tokenize <filename>", args[0]);
+        eprintln!(
+            "Usage: {} [repl | tokenize <filename> | parse <filename> | evaluate <filename> | run <filename> [--backend tree|vm] [--gc-stress] [--gc-log] | disassemble <filename> | cst <filename>]",
+            args[0]
+        );
         return;
     }
 
@@ -23,84 +45,124 @@ fn main() {
         String::new()
     });
 
+    let emitter = Emitter::new(filename, &file_contents);
+
     match command.as_str() {
         "tokenize" => {
             let mut lexical_error = false;
-            if !file_contents.is_empty() {
-                let lexer = Lexer::new(&file_contents);
-                for token in lexer {
-                    match token {
-                        Ok(token) => println!("{token}"),
-                        Err(err) => {
-                            eprintln!("{err}");
-                            lexical_error = true
-                        }
+            let lexer = Lexer::new(&file_contents);
+            for token in lexer {
+                match token {
+                    Ok(token) => println!("{token}"),
+                    Err(err) => {
+                        emitter.emit(&err.diagnostic());
+                        lexical_error = true
                     }
                 }
             }
-            println!("EOF  null");
             if lexical_error {
                 std::process::exit(65);
             }
         }
         "parse" => {
-            let tokens = Lexer::new(&file_contents).map(|token| match token {
-                Ok(token) => token,
+            let (tokens, lexical_error) = tokenize(&file_contents, &emitter);
+            let tokens = &mut tokens.into_iter().peekable();
+            let token_tree = match parse_expr(tokens, 0) {
+                Ok(token_tree) => token_tree,
                 Err(err) => {
-                    eprintln!("{err}");
-                    std::process::exit(65)
+                    emitter.emit(&err.diagnostic());
+                    std::process::exit(65);
                 }
-            });
-            let tokens = &mut tokens.into_iter().peekable();
-            let Ok(token_tree) = parse_expr(tokens, 0) else {
-                std::process::exit(65);
             };
+            if lexical_error {
+                std::process::exit(65);
+            }
             println!("{token_tree}");
         }
         "evaluate" => {
-            let tokens = Lexer::new(&file_contents).map(|token| match token {
-                Ok(token) => token,
+            let (tokens, lexical_error) = tokenize(&file_contents, &emitter);
+            let tokens = &mut tokens.into_iter().peekable();
+            let token_tree = match parse_expr(tokens, 0) {
+                Ok(token_tree) => token_tree,
                 Err(err) => {
-                    eprintln!("{err}");
-                    std::process::exit(65)
+                    emitter.emit(&err.diagnostic());
+                    std::process::exit(65);
                 }
-            });
-            let tokens = &mut tokens.into_iter().peekable();
-
-            let Ok(token_tree) = parse_expr(tokens, 0) else {
-                std::process::exit(65);
             };
+            if lexical_error {
+                std::process::exit(65);
+            }
 
             let mut interpreter = Interpreter::new();
 
-            match interpreter.evaluate_expr(token_tree) {
+            match interpreter.evaluate_expr(&token_tree) {
                 Ok(value) => println!("{value}"),
                 Err(err) => {
-                    eprintln!("{err}");
+                    emitter.emit(&err.diagnostic());
                     std::process::exit(70);
                 }
             };
         }
         "run" => {
-            let tokens = Lexer::new(&file_contents).map(|token| match token {
-                Ok(token) => token,
-                Err(err) => {
-                    eprintln!("{err}");
-                    std::process::exit(65)
+            let Some(options) = RunOptions::parse(&args[3..]) else {
+                eprintln!(
+                    "Usage: {} run <filename> [--backend tree|vm] [--gc-stress] [--gc-log]",
+                    args[0]
+                );
+                std::process::exit(64);
+            };
+            let result = match options.backend {
+                Backend::Tree => {
+                    let mut interpreter = Interpreter::new();
+                    interpreter.set_gc_stress(options.gc_stress);
+                    interpreter.set_gc_log(options.gc_log);
+                    interpreter.run_source(&file_contents)
                 }
-            });
-            let tokens = &mut tokens.into_iter().peekable();
-            let token_tree = match parse_statements(tokens) {
-                Ok(token_tree) => token_tree,
+                Backend::Vm => {
+                    let mut vm = Vm::new();
+                    vm.set_gc_stress(options.gc_stress);
+                    vm.set_gc_log(options.gc_log);
+                    vm.run_source(&file_contents)
+                }
+            };
+            if let Err(err) = result {
+                for diagnostic in err.diagnostics() {
+                    emitter.emit(&diagnostic);
+                }
+                std::process::exit(err.exit_code());
+            }
+        }
+        "disassemble" => {
+            let function = match compile_source(&file_contents) {
+                Ok(function) => function,
                 Err(err) => {
-                    eprintln!("Failed to parse the statements: {err}");
-                    std::process::exit(65)
+                    for diagnostic in err.diagnostics() {
+                        emitter.emit(&diagnostic);
+                    }
+                    std::process::exit(err.exit_code());
                 }
             };
-            let mut interpreter = Interpreter::new();
-            if let Err(err) = interpreter.evaluate(token_tree) {
-                eprintln!("{err}");
-                std::process::exit(70);
+            print!("{}", function.disassemble());
+        }
+        "cst" => {
+            let parse = cst::parse(&file_contents);
+            print!("{:?}", parse.syntax());
+            let diagnostics: Vec<_> = parse
+                .lexing_errors()
+                .iter()
+                .map(LexingError::diagnostic)
+                .chain(
+                    parse
+                        .parsing_errors()
+                        .iter()
+                        .map(ParseExpressionError::diagnostic),
+                )
+                .collect();
+            for diagnostic in &diagnostics {
+                emitter.emit(diagnostic);
+            }
+            if !diagnostics.is_empty() {
+                std::process::exit(65);
             }
         }
         _ => {
@@ -108,3 +170,65 @@ fn main() {
         }
     }
 }
+
+/// The implementations able to run a program.
+enum Backend {
+    /// Walk the syntax trees, the default.
+    Tree,
+    /// Compile to bytecode, run by the virtual machine.
+    Vm,
+}
+
+/// The options of the `run` command.
+struct RunOptions {
+    backend: Backend,
+    gc_stress: bool,
+    gc_log: bool,
+}
+
+impl RunOptions {
+    /// `None` if the options are invalid.
+    fn parse(options: &[String]) -> Option<Self> {
+        let mut parsed = RunOptions {
+            backend: Backend::Tree,
+            gc_stress: false,
+            gc_log: false,
+        };
+        let mut options = options.iter().map(String::as_str);
+        while let Some(option) = options.next() {
+            match option {
+                "--backend" => {
+                    parsed.backend = match options.next()? {
+                        "tree" => Backend::Tree,
+                        "vm" => Backend::Vm,
+                        _ => return None,
+                    }
+                }
+                "--gc-stress" => parsed.gc_stress = true,
+                "--gc-log" => parsed.gc_log = true,
+                _ => return None,
+            }
+        }
+        Some(parsed)
+    }
+}
+
+/// Lex the whole file, reporting every lexing error. The parser still runs on the valid
+/// tokens so it can report its own errors too.
+fn tokenize<'de>(
+    file_contents: &'de str,
+    emitter: &Emitter<'_>,
+) -> (Vec<Spanned<Token<'de>>>, bool) {
+    let mut lexical_error = false;
+    let tokens = Lexer::new(file_contents)
+        .filter_map(|token| match token {
+            Ok(token) => Some(token),
+            Err(err) => {
+                emitter.emit(&err.diagnostic());
+                lexical_error = true;
+                None
+            }
+        })
+        .collect();
+    (tokens, lexical_error)
+}
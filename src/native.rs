@@ -0,0 +1,196 @@
+use std::{
+    error::Error,
+    fmt,
+    rc::Rc,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use crate::{
+    intern::intern,
+    interpret::{EvaluationError, EvaluationErrorKind, Value},
+    lex::Span,
+};
+
+/// The Rust side of a native function: it gets the arguments, already checked against
+/// the arity, and its errors are reported as runtime errors at the call.
+pub type NativeCallback = dyn Fn(&[Value]) -> Result<Value, Box<dyn Error>>;
+
+/// A function implemented in Rust and callable from Lox, with either backend.
+///
+/// The [`Vm`](crate::vm::Vm) converts its own values from and to [`Value`] around the
+/// call, but its functions, classes and instances only exist in its heap: a native
+/// function called by the machine only exchanges primitive values and native
+/// functions, other values are reported as a failure of the function.
+pub struct NativeFunction {
+    pub(crate) name: Rc<str>,
+    pub(crate) arity: usize,
+    pub(crate) callback: Box<NativeCallback>,
+}
+
+impl NativeFunction {
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    pub fn arity(&self) -> usize {
+        self.arity
+    }
+
+    pub(crate) fn new(
+        name: &str,
+        arity: usize,
+        callback: impl Fn(&[Value]) -> Result<Value, Box<dyn Error>> + 'static,
+    ) -> Self {
+        Self {
+            name: intern(name),
+            arity,
+            callback: Box::new(callback),
+        }
+    }
+
+    /// Call the function with arguments already checked against its arity.
+    pub(crate) fn call(&self, arguments: &[Value], span: Span) -> Result<Value, EvaluationError> {
+        (self.callback)(arguments).map_err(|err| self.failure(&*err, span))
+    }
+
+    /// The runtime error reported when the function fails at `span`.
+    pub(crate) fn failure(&self, err: &dyn Error, span: Span) -> EvaluationError {
+        EvaluationError::new(
+            EvaluationErrorKind::NativeFailure {
+                name: Rc::clone(&self.name),
+                message: err.to_string(),
+            },
+            span,
+        )
+    }
+}
+
+/// A native function defined in every interpreter: its name, arity and implementation.
+pub(crate) type Builtin = (
+    &'static str,
+    usize,
+    fn(&[Value]) -> Result<Value, Box<dyn Error>>,
+);
+
+/// The native functions both backends start with.
+pub(crate) const BUILTINS: &[Builtin] = &[("clock", 0, clock)];
+
+/// `clock()`, the number of seconds since the Unix epoch, for benchmarks.
+fn clock(_: &[Value]) -> Result<Value, Box<dyn Error>> {
+    let elapsed = SystemTime::now().duration_since(UNIX_EPOCH)?;
+    Ok(Value::Number(elapsed.as_secs_f64()))
+}
+
+impl Value {
+    /// Name of the type of the value, as shown in the error messages.
+    pub fn type_name(&self) -> &'static str {
+        match self {
+            Value::Boolean(_) => "boolean",
+            Value::Number(_) => "number",
+            Value::String(_) => "string",
+            Value::Nil => "nil",
+            Value::Function(_) | Value::NativeFunction(_) => "function",
+            Value::Class(_) => "class",
+            Value::Instance(_) => "instance",
+        }
+    }
+}
+
+/// Error of the conversion of a [`Value`] into a Rust type.
+#[derive(Debug)]
+pub struct ConversionError {
+    expected: &'static str,
+    found: &'static str,
+}
+
+impl ConversionError {
+    fn new(expected: &'static str, value: &Value) -> Self {
+        Self::of_type(expected, value.type_name())
+    }
+
+    /// A conversion from a value of the type named `found`, for the values of the
+    /// [`Vm`](crate::vm::Vm) which have no equivalent.
+    pub(crate) fn of_type(expected: &'static str, found: &'static str) -> Self {
+        Self { expected, found }
+    }
+}
+
+impl Error for ConversionError {}
+
+impl fmt::Display for ConversionError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Expected a {} but got a {}.", self.expected, self.found)
+    }
+}
+
+impl TryFrom<&Value> for f64 {
+    type Error = ConversionError;
+
+    fn try_from(value: &Value) -> Result<Self, Self::Error> {
+        match value {
+            Value::Number(number) => Ok(*number),
+            _ => Err(ConversionError::new("number", value)),
+        }
+    }
+}
+
+impl TryFrom<&Value> for bool {
+    type Error = ConversionError;
+
+    fn try_from(value: &Value) -> Result<Self, Self::Error> {
+        match value {
+            Value::Boolean(boolean) => Ok(*boolean),
+            _ => Err(ConversionError::new("boolean", value)),
+        }
+    }
+}
+
+impl TryFrom<&Value> for Rc<str> {
+    type Error = ConversionError;
+
+    fn try_from(value: &Value) -> Result<Self, Self::Error> {
+        match value {
+            Value::String(string) => Ok(Rc::clone(string)),
+            _ => Err(ConversionError::new("string", value)),
+        }
+    }
+}
+
+impl TryFrom<&Value> for String {
+    type Error = ConversionError;
+
+    fn try_from(value: &Value) -> Result<Self, Self::Error> {
+        Rc::<str>::try_from(value).map(|string| string.to_string())
+    }
+}
+
+impl From<f64> for Value {
+    fn from(number: f64) -> Self {
+        Value::Number(number)
+    }
+}
+
+impl From<bool> for Value {
+    fn from(boolean: bool) -> Self {
+        Value::Boolean(boolean)
+    }
+}
+
+impl From<&str> for Value {
+    fn from(string: &str) -> Self {
+        Value::String(Rc::from(string))
+    }
+}
+
+impl From<String> for Value {
+    fn from(string: String) -> Self {
+        Value::String(Rc::from(string))
+    }
+}
+
+/// `None` is `nil`.
+impl<T: Into<Value>> From<Option<T>> for Value {
+    fn from(value: Option<T>) -> Self {
+        value.map_or(Value::Nil, Into::into)
+    }
+}
@@ -1,153 +1,545 @@
-use std::{fmt, iter::Peekable};
+use std::{cell::Cell, fmt, iter::Peekable, rc::Rc};
 
-use crate::lex::Token;
+use crate::{
+    diagnostic::Diagnostic,
+    intern::intern,
+    lex::{Span, Spanned, Token},
+};
 
 // As we only want a single token lookahead, `Peekable` is all we need.
 //
-// Lifetime elision will put the wrong lifetime to the return time so we
-// must be explicit.
+// The tokens borrow from the source, but the trees own their names and strings
+// so they can outlive it.
 pub fn parse_statements<'de>(
-    tokens: &mut Peekable<impl Iterator<Item = Token<'de>>>,
-) -> Result<Vec<StatementTree<'de>>, ParseExpressionError<'de>> {
+    tokens: &mut Peekable<impl Iterator<Item = Spanned<Token<'de>>>>,
+) -> Result<Vec<Spanned<StatementTree>>, Vec<ParseExpressionError>> {
     let mut statements = Vec::new();
-    while let Some(statement) = parse_statement(tokens)? {
-        statements.push(statement);
+    let mut errors = Vec::new();
+    while !at_end(tokens) {
+        if let Some(statement) = parse_declaration(tokens, &mut errors) {
+            statements.push(statement);
+        }
+    }
+
+    if errors.is_empty() {
+        Ok(statements)
+    } else {
+        Err(errors)
+    }
+}
+
+/// Parse a statement. On a syntax error, the error is recorded and we skip to the
+/// next statement, so all the errors of a file are reported at once.
+fn parse_declaration<'de>(
+    tokens: &mut Peekable<impl Iterator<Item = Spanned<Token<'de>>>>,
+    errors: &mut Vec<ParseExpressionError>,
+) -> Option<Spanned<StatementTree>> {
+    match parse_declaration_or_statement(tokens, errors) {
+        Ok(statement) => statement,
+        Err(err) => {
+            errors.push(err);
+            synchronize(tokens);
+            None
+        }
+    }
+}
+
+/// Discard tokens until we are likely at the beginning of a statement, either right
+/// after a semicolon or before a keyword that starts a statement.
+fn synchronize<'de>(tokens: &mut Peekable<impl Iterator<Item = Spanned<Token<'de>>>>) {
+    while let Some(token) = tokens.next_if(|token| token != &Token::Eof) {
+        if token == Token::Semicolon {
+            return;
+        }
+        if tokens.peek().is_some_and(|token| {
+            matches!(
+                token.node,
+                Token::Class
+                    | Token::Fun
+                    | Token::Var
+                    | Token::For
+                    | Token::If
+                    | Token::While
+                    | Token::Print
+                    | Token::Return
+            )
+        }) {
+            return;
+        }
     }
+}
+
+fn at_end<'de>(tokens: &mut Peekable<impl Iterator<Item = Spanned<Token<'de>>>>) -> bool {
+    tokens.peek().map_or(true, |token| token == &Token::Eof)
+}
+
+/// Consume the next token if it is the expected one, otherwise report an error on it.
+fn expect<'de>(
+    tokens: &mut Peekable<impl Iterator<Item = Spanned<Token<'de>>>>,
+    expected: &Token<'de>,
+    kind: ParseErrorKind,
+) -> Result<Spanned<Token<'de>>, ParseExpressionError> {
+    match tokens.next_if_eq(expected) {
+        Some(token) => Ok(token),
+        None => Err(error_at_next(tokens, kind)),
+    }
+}
+
+fn expect_identifier<'de>(
+    tokens: &mut Peekable<impl Iterator<Item = Spanned<Token<'de>>>>,
+) -> Result<Spanned<Rc<str>>, ParseExpressionError> {
+    match tokens.next_if(|token| matches!(token.node, Token::Identifier(_))) {
+        Some(Spanned {
+            node: Token::Identifier(ident),
+            span,
+        }) => Ok(Spanned {
+            node: intern(ident),
+            span,
+        }),
+        _ => Err(error_at_next(tokens, ParseErrorKind::MissingIdentifier)),
+    }
+}
+
+/// Maximum number of parameters of a function and of arguments of a call, the bytecode
+/// stores the number of arguments in a byte.
+pub const MAX_ARGUMENTS: usize = 255;
+
+/// Error located at the next token, which is left in the stream.
+fn error_at_next<'de>(
+    tokens: &mut Peekable<impl Iterator<Item = Spanned<Token<'de>>>>,
+    kind: ParseErrorKind,
+) -> ParseExpressionError {
+    let (span, lexeme) = match tokens.peek() {
+        Some(token) if token != &Token::Eof => (token.span, Some(token.node.lexeme().into())),
+        Some(token) => (token.span, None),
+        None => (Span::default(), None),
+    };
+    ParseExpressionError { kind, span, lexeme }
+}
 
-    Ok(statements)
+/// Declarations are only allowed at the top level and directly inside blocks,
+/// the bodies of `if`, `while` and `for` only accept statements.
+fn parse_declaration_or_statement<'de>(
+    tokens: &mut Peekable<impl Iterator<Item = Spanned<Token<'de>>>>,
+    errors: &mut Vec<ParseExpressionError>,
+) -> Result<Option<Spanned<StatementTree>>, ParseExpressionError> {
+    let Some(token) = tokens.peek() else {
+        return Ok(None);
+    };
+    // A declaration is located at the declared name.
+    let span;
+    let declaration = match &token.node {
+        Token::Var => {
+            tokens.next();
+            return parse_var_declaration(tokens).map(Some);
+        }
+        Token::Fun => {
+            tokens.next();
+            let function = parse_function(tokens, errors)?;
+            span = function.span;
+            StatementTree::Function(Rc::new(function))
+        }
+        Token::Class => {
+            tokens.next();
+            let name = expect_identifier(tokens)?;
+            span = name.span;
+            let superclass = if tokens.next_if_eq(&Token::Less).is_some() {
+                let superclass = expect_identifier(tokens)?;
+                Some(Spanned {
+                    node: ExpressionTree::Primary(Primary::Identifier(Variable::new(
+                        superclass.node,
+                    ))),
+                    span: superclass.span,
+                })
+            } else {
+                None
+            };
+            expect(tokens, &Token::LeftBrace, ParseErrorKind::MissingLeftBrace)?;
+            let mut methods = Vec::new();
+            while !at_end(tokens)
+                && tokens
+                    .peek()
+                    .is_some_and(|token| token != &Token::RightBrace)
+            {
+                methods.push(Rc::new(parse_function(tokens, errors)?));
+            }
+            expect(
+                tokens,
+                &Token::RightBrace,
+                ParseErrorKind::MissingRightBrace,
+            )?;
+            StatementTree::Class {
+                name: name.node,
+                superclass,
+                methods,
+            }
+        }
+        _ => return parse_statement(tokens, errors),
+    };
+    Ok(Some(Spanned {
+        node: declaration,
+        span,
+    }))
+}
+
+/// Parses the rest of a variable declaration, once `var` has been consumed.
+fn parse_var_declaration<'de>(
+    tokens: &mut Peekable<impl Iterator<Item = Spanned<Token<'de>>>>,
+) -> Result<Spanned<StatementTree>, ParseExpressionError> {
+    let ident = expect_identifier(tokens)?;
+    let expr = if tokens.next_if_eq(&Token::Equal).is_some() {
+        Some(parse_expr(tokens, 0)?)
+    } else {
+        None
+    };
+
+    expect(tokens, &Token::Semicolon, ParseErrorKind::MissingSemicolon)?;
+    Ok(Spanned {
+        node: StatementTree::VarDeclaration {
+            ident: ident.node,
+            expr,
+        },
+        span: ident.span,
+    })
 }
 
 pub fn parse_statement<'de>(
-    tokens: &mut Peekable<impl Iterator<Item = Token<'de>>>,
-) -> Result<Option<StatementTree<'de>>, ParseExpressionError<'de>> {
+    tokens: &mut Peekable<impl Iterator<Item = Spanned<Token<'de>>>>,
+    errors: &mut Vec<ParseExpressionError>,
+) -> Result<Option<Spanned<StatementTree>>, ParseExpressionError> {
+    if at_end(tokens) {
+        return Ok(None);
+    }
     let Some(token) = tokens.peek() else {
         return Ok(None);
     };
+    // A statement is located at its first token.
+    let span = token.span;
     // A program is just 0 or more statements
-    let statement = match token {
+    let statement = match &token.node {
         Token::Print => {
             tokens.next();
             let expr = parse_expr(tokens, 0)?;
-            if let Some(token) = tokens.next() {
-                if token != Token::Semicolon {
-                    panic!("Expected semicolon got '{token}'");
-                }
-            }
+            expect(tokens, &Token::Semicolon, ParseErrorKind::MissingSemicolon)?;
             StatementTree::Print(expr)
         }
-        Token::Var => {
+        Token::LeftBrace => StatementTree::Block(parse_block(tokens, errors)?),
+        Token::Return => {
             tokens.next();
-            let Some(Token::Identifier(ident)) = tokens.next() else {
-                panic!("Expected identifier");
-            };
-            let expr = if tokens.next_if_eq(&Token::Equal).is_some() {
+            let expr = if tokens
+                .peek()
+                .is_some_and(|token| token == &Token::Semicolon)
+            {
+                None
+            } else {
                 Some(parse_expr(tokens, 0)?)
+            };
+            expect(tokens, &Token::Semicolon, ParseErrorKind::MissingSemicolon)?;
+            StatementTree::Return(expr)
+        }
+        Token::If => {
+            tokens.next();
+            expect(tokens, &Token::LeftParen, ParseErrorKind::MissingLeftParen)?;
+            let condition = parse_expr(tokens, 0)?;
+            expect(
+                tokens,
+                &Token::RightParen,
+                ParseErrorKind::MissingRightParen,
+            )?;
+            let Some(then_branch) = parse_statement(tokens, errors)? else {
+                return Err(error_at_next(tokens, ParseErrorKind::MissingStatement));
+            };
+            // The `else` is bound to the nearest `if`, as the nested `if` eagerly
+            // consumes it before we get the chance to see it.
+            let else_branch = if tokens.next_if_eq(&Token::Else).is_some() {
+                let Some(else_branch) = parse_statement(tokens, errors)? else {
+                    return Err(error_at_next(tokens, ParseErrorKind::MissingStatement));
+                };
+                Some(Box::new(else_branch))
             } else {
                 None
             };
-
-            if let Some(token) = tokens.next() {
-                if token != Token::Semicolon {
-                    panic!("Expected semicolon got '{token}'");
-                }
+            StatementTree::If {
+                condition,
+                then_branch: Box::new(then_branch),
+                else_branch,
             }
-            StatementTree::VarDeclaration { ident, expr }
         }
-
-        Token::LeftBrace => {
+        Token::While => {
+            tokens.next();
+            expect(tokens, &Token::LeftParen, ParseErrorKind::MissingLeftParen)?;
+            let condition = parse_expr(tokens, 0)?;
+            expect(
+                tokens,
+                &Token::RightParen,
+                ParseErrorKind::MissingRightParen,
+            )?;
+            let Some(body) = parse_statement(tokens, errors)? else {
+                return Err(error_at_next(tokens, ParseErrorKind::MissingStatement));
+            };
+            StatementTree::While {
+                condition,
+                body: Box::new(body),
+            }
+        }
+        Token::For => {
             tokens.next();
-            let mut block_statements = Vec::new();
+            expect(tokens, &Token::LeftParen, ParseErrorKind::MissingLeftParen)?;
+            // The initializer is either empty, a variable declaration or an expression
+            // statement, the last two already consume their trailing semicolon.
+            let initializer = if tokens.next_if_eq(&Token::Semicolon).is_some() {
+                None
+            } else if tokens.next_if_eq(&Token::Var).is_some() {
+                Some(parse_var_declaration(tokens)?)
+            } else {
+                Some(parse_expression_statement(tokens)?)
+            };
+            let condition = if tokens
+                .peek()
+                .is_some_and(|token| token == &Token::Semicolon)
+            {
+                Spanned {
+                    node: ExpressionTree::Primary(Primary::True),
+                    span,
+                }
+            } else {
+                parse_expr(tokens, 0)?
+            };
+            expect(tokens, &Token::Semicolon, ParseErrorKind::MissingSemicolon)?;
+            let increment = if tokens
+                .peek()
+                .is_some_and(|token| token == &Token::RightParen)
+            {
+                None
+            } else {
+                Some(parse_expr(tokens, 0)?)
+            };
+            expect(
+                tokens,
+                &Token::RightParen,
+                ParseErrorKind::MissingRightParen,
+            )?;
+            let Some(body) = parse_statement(tokens, errors)? else {
+                return Err(error_at_next(tokens, ParseErrorKind::MissingStatement));
+            };
 
-            while let Some(statement) = parse_statement(tokens)? {
-                block_statements.push(statement);
-                if tokens
-                    .peek()
-                    .is_some_and(|token| token == &Token::RightBrace)
-                {
-                    break;
+            // We desugar the `for` loop into a `while` loop:
+            // { initializer; while (condition) { body; increment; } }
+            let body = match increment {
+                Some(increment) => {
+                    let increment = Spanned {
+                        span: increment.span,
+                        node: StatementTree::Expr(increment),
+                    };
+                    Spanned {
+                        node: StatementTree::Block(vec![body, increment]),
+                        span,
+                    }
                 }
+                None => body,
+            };
+            let while_loop = StatementTree::While {
+                condition,
+                body: Box::new(body),
+            };
+            // The enclosing block gives its own scope to the initializer.
+            match initializer {
+                Some(initializer) => StatementTree::Block(vec![
+                    initializer,
+                    Spanned {
+                        node: while_loop,
+                        span,
+                    },
+                ]),
+                None => while_loop,
             }
+        }
+        _ => return parse_expression_statement(tokens).map(Some),
+    };
+    Ok(Some(Spanned {
+        node: statement,
+        span,
+    }))
+}
 
-            if !matches!(tokens.next(), Some(Token::RightBrace)) {
-                return Err(ParseExpressionError::MissingRightBrace);
+fn parse_expression_statement<'de>(
+    tokens: &mut Peekable<impl Iterator<Item = Spanned<Token<'de>>>>,
+) -> Result<Spanned<StatementTree>, ParseExpressionError> {
+    // Located at its first token, like the other statements.
+    let span = tokens.peek().map(|token| token.span).unwrap_or_default();
+    let expr = parse_expr(tokens, 0)?;
+    expect(tokens, &Token::Semicolon, ParseErrorKind::MissingSemicolon)?;
+    Ok(Spanned {
+        node: StatementTree::Expr(expr),
+        span,
+    })
+}
+
+/// Parse a function from its name to the end of its body. It is shared by
+/// function declarations and methods, which don't start with the `fun` keyword.
+fn parse_function<'de>(
+    tokens: &mut Peekable<impl Iterator<Item = Spanned<Token<'de>>>>,
+    errors: &mut Vec<ParseExpressionError>,
+) -> Result<Function, ParseExpressionError> {
+    let name = expect_identifier(tokens)?;
+    expect(tokens, &Token::LeftParen, ParseErrorKind::MissingLeftParen)?;
+    let mut params = Vec::new();
+    if tokens.next_if_eq(&Token::RightParen).is_none() {
+        loop {
+            if params.len() == MAX_ARGUMENTS {
+                // The function is still valid for the parser, keep going.
+                errors.push(error_at_next(tokens, ParseErrorKind::TooManyParameters));
             }
-            StatementTree::Block(block_statements)
-        }
-        Token::RightBrace => {
-            unreachable!();
-        }
-        _ => {
-            let expr = parse_expr(tokens, 0)?;
-            if let Some(token) = tokens.next() {
-                if token != Token::Semicolon {
-                    panic!("Expected semicolon got '{token}'");
-                }
+            params.push(expect_identifier(tokens)?);
+            if tokens.next_if_eq(&Token::Comma).is_none() {
+                break;
             }
-            StatementTree::Expr(expr)
         }
-    };
-    Ok(Some(statement))
+        expect(
+            tokens,
+            &Token::RightParen,
+            ParseErrorKind::MissingRightParen,
+        )?;
+    }
+    if !tokens
+        .peek()
+        .is_some_and(|token| token == &Token::LeftBrace)
+    {
+        return Err(error_at_next(tokens, ParseErrorKind::MissingLeftBrace));
+    }
+    let body = parse_block(tokens, errors)?;
+    Ok(Function {
+        name: name.node,
+        span: name.span,
+        params,
+        body,
+    })
 }
 
-pub enum StatementTree<'de> {
+/// Parse a block, from its opening brace to its closing one.
+fn parse_block<'de>(
+    tokens: &mut Peekable<impl Iterator<Item = Spanned<Token<'de>>>>,
+    errors: &mut Vec<ParseExpressionError>,
+) -> Result<Vec<Spanned<StatementTree>>, ParseExpressionError> {
+    tokens.next();
+    let mut block_statements = Vec::new();
+
+    while !at_end(tokens)
+        && tokens
+            .peek()
+            .is_some_and(|token| token != &Token::RightBrace)
+    {
+        if let Some(statement) = parse_declaration(tokens, errors) {
+            block_statements.push(statement);
+        }
+    }
+
+    expect(
+        tokens,
+        &Token::RightBrace,
+        ParseErrorKind::MissingRightBrace,
+    )?;
+    Ok(block_statements)
+}
+
+#[derive(Debug, PartialEq)]
+pub enum StatementTree {
     /// Print statement.
-    Print(ExpressionTree<'de>),
+    Print(Spanned<ExpressionTree>),
     /// Expression statement, for expression that have side effect.
-    Expr(ExpressionTree<'de>),
+    Expr(Spanned<ExpressionTree>),
     /// Block statement. In Lox they don't produce value, like in
     /// Rust where block are expression.
-    Block(Vec<StatementTree<'de>>),
+    Block(Vec<Spanned<StatementTree>>),
     VarDeclaration {
-        ident: &'de str,
-        expr: Option<ExpressionTree<'de>>,
+        ident: Rc<str>,
+        expr: Option<Spanned<ExpressionTree>>,
+    },
+    If {
+        condition: Spanned<ExpressionTree>,
+        then_branch: Box<Spanned<StatementTree>>,
+        else_branch: Option<Box<Spanned<StatementTree>>>,
+    },
+    /// The only loop of the tree, `for` loops are desugared into it.
+    While {
+        condition: Spanned<ExpressionTree>,
+        body: Box<Spanned<StatementTree>>,
     },
+    /// Function declaration. It is shared with the function values created
+    /// when the declaration is evaluated.
+    Function(Rc<Function>),
+    Return(Option<Spanned<ExpressionTree>>),
+    Class {
+        name: Rc<str>,
+        /// Always a variable, but evaluated like any expression.
+        superclass: Option<Spanned<ExpressionTree>>,
+        methods: Vec<Rc<Function>>,
+    },
+}
+
+#[derive(Debug, PartialEq)]
+pub struct Function {
+    pub name: Rc<str>,
+    /// Location of the name.
+    pub span: Span,
+    pub params: Vec<Spanned<Rc<str>>>,
+    pub body: Vec<Spanned<StatementTree>>,
 }
 
 // Pratt parser
 pub fn parse_expr<'de>(
-    tokens: &mut Peekable<impl Iterator<Item = Token<'de>>>,
+    tokens: &mut Peekable<impl Iterator<Item = Spanned<Token<'de>>>>,
     min_bp: u8,
-) -> Result<ExpressionTree<'de>, ParseExpressionError<'de>> {
-    let mut lhs = if let Some(token) = tokens.next() {
-        match token {
+) -> Result<Spanned<ExpressionTree>, ParseExpressionError> {
+    // The token is left in the stream when it can't start an expression, so we
+    // can synchronize from it.
+    let Some(token) = tokens.next_if(|token| starts_expression(&token.node)) else {
+        return Err(error_at_next(tokens, ParseErrorKind::ExpectedExpression));
+    };
+    let mut lhs = {
+        // An expression is located at its main token: the operator, the
+        // name of the variable or of the property...
+        let span = token.span;
+        let node = match token.node {
             Token::Nil => ExpressionTree::Primary(Primary::Nil),
             Token::True => ExpressionTree::Primary(Primary::True),
             Token::False => ExpressionTree::Primary(Primary::False),
             Token::Number(n, _) => ExpressionTree::Primary(Primary::Number(n)),
-            Token::String(s) => ExpressionTree::Primary(Primary::String(s)),
+            Token::String(s, _) => ExpressionTree::Primary(Primary::String(intern(&s))),
             Token::LeftParen => {
                 let expr_tree =
                     ExpressionTree::Primary(Primary::Group(Box::new(parse_expr(tokens, 0)?)));
-                if !tokens
-                    .next()
-                    .is_some_and(|token| token == Token::RightParen)
-                {
-                    return Err(ParseExpressionError::MissingRightParen);
-                }
+                expect(
+                    tokens,
+                    &Token::RightParen,
+                    ParseErrorKind::MissingRightParen,
+                )?;
                 expr_tree
             }
             Token::Identifier(ident) => {
-                if tokens.next_if_eq(&Token::Equal).is_some() {
-                    ExpressionTree::Assignment(ident, Box::new(parse_expr(tokens, 1)?))
-                } else {
-                    ExpressionTree::Primary(Primary::Identifier(ident))
-                }
+                ExpressionTree::Primary(Primary::Identifier(Variable::new(intern(ident))))
+            }
+            Token::This => ExpressionTree::Primary(Primary::This(Variable::new(intern("this")))),
+            Token::Super => {
+                expect(tokens, &Token::Dot, ParseErrorKind::MissingDot)?;
+                let method = expect_identifier(tokens)?;
+                ExpressionTree::Primary(Primary::Super(Variable::new(intern("super")), method.node))
             }
 
             // prefix operator (Unary)
-            Token::Minus => ExpressionTree::Unary(Unary::Minus(Box::new(parse_expr(tokens, 5)?))),
-            Token::Bang => ExpressionTree::Unary(Unary::Bang(Box::new(parse_expr(tokens, 5)?))),
-            token => return Err(ParseExpressionError::InvalidToken(token)),
-        }
-    } else {
-        ExpressionTree::Primary(Primary::Nil)
+            Token::Minus => ExpressionTree::Unary(Unary::Minus(Box::new(parse_expr(tokens, 7)?))),
+            Token::Bang => ExpressionTree::Unary(Unary::Bang(Box::new(parse_expr(tokens, 7)?))),
+            _ => unreachable!("the token has been checked to start an expression"),
+        };
+        Spanned { node, span }
     };
 
     // We parse the tokens until we hit something with a lower precedence.
     while let Some(next_token) = tokens.peek() {
-        match next_token {
+        let span = next_token.span;
+        match &next_token.node {
             Token::Star => {
-                let bp = 5;
+                let bp = 7;
                 if bp > min_bp {
                     tokens.next();
                 } else {
@@ -156,61 +548,84 @@ pub fn parse_expr<'de>(
                 // Here we want to pass the next items until we encounter something that have the same level of
                 // precedence that the Star. If it's lower, for instance a +, we stop
                 let rhs = parse_expr(tokens, bp)?;
-                lhs = ExpressionTree::Factor(Factor::Star(Box::new(lhs), Box::new(rhs)));
+                lhs = Spanned {
+                    node: ExpressionTree::Factor(Factor::Star(Box::new(lhs), Box::new(rhs))),
+                    span,
+                };
             }
             Token::Slash => {
-                let bp = 5;
+                let bp = 7;
                 if bp > min_bp {
                     tokens.next();
                 } else {
                     break;
                 }
                 let rhs = parse_expr(tokens, bp)?;
-                lhs = ExpressionTree::Factor(Factor::Slash(Box::new(lhs), Box::new(rhs)));
+                lhs = Spanned {
+                    node: ExpressionTree::Factor(Factor::Slash(Box::new(lhs), Box::new(rhs))),
+                    span,
+                };
             }
             Token::Plus => {
-                let bp = 4;
+                let bp = 6;
                 if bp > min_bp {
                     tokens.next();
                 } else {
                     break;
                 }
                 let rhs = parse_expr(tokens, bp)?;
-                lhs = ExpressionTree::Term(Term::Plus(Box::new(lhs), Box::new(rhs)));
+                lhs = Spanned {
+                    node: ExpressionTree::Term(Term::Plus(Box::new(lhs), Box::new(rhs))),
+                    span,
+                };
             }
             Token::Minus => {
-                let bp = 4;
+                let bp = 6;
                 if bp > min_bp {
                     tokens.next();
                 } else {
                     break;
                 }
                 let rhs = parse_expr(tokens, bp)?;
-                lhs = ExpressionTree::Term(Term::Minus(Box::new(lhs), Box::new(rhs)));
+                lhs = Spanned {
+                    node: ExpressionTree::Term(Term::Minus(Box::new(lhs), Box::new(rhs))),
+                    span,
+                };
             }
             Token::Less => {
-                let bp = 3;
+                let bp = 5;
                 if bp > min_bp {
                     tokens.next();
                 } else {
                     break;
                 }
                 let rhs = parse_expr(tokens, bp)?;
-                lhs = ExpressionTree::Comparison(Comparison::Less(Box::new(lhs), Box::new(rhs)));
+                lhs = Spanned {
+                    node: ExpressionTree::Comparison(Comparison::Less(
+                        Box::new(lhs),
+                        Box::new(rhs),
+                    )),
+                    span,
+                };
             }
             Token::LessEqual => {
-                let bp = 3;
+                let bp = 5;
                 if bp > min_bp {
                     tokens.next();
                 } else {
                     break;
                 }
                 let rhs = parse_expr(tokens, bp)?;
-                lhs =
-                    ExpressionTree::Comparison(Comparison::LessEqual(Box::new(lhs), Box::new(rhs)));
+                lhs = Spanned {
+                    node: ExpressionTree::Comparison(Comparison::LessEqual(
+                        Box::new(lhs),
+                        Box::new(rhs),
+                    )),
+                    span,
+                };
             }
             Token::Greater => {
-                let bp = 3;
+                let bp = 5;
                 if bp > min_bp {
                     tokens.next();
                 } else {
@@ -218,33 +633,160 @@ pub fn parse_expr<'de>(
                 }
 
                 let rhs = parse_expr(tokens, bp)?;
-                lhs = ExpressionTree::Comparison(Comparison::Greater(Box::new(lhs), Box::new(rhs)));
+                lhs = Spanned {
+                    node: ExpressionTree::Comparison(Comparison::Greater(
+                        Box::new(lhs),
+                        Box::new(rhs),
+                    )),
+                    span,
+                };
             }
             Token::GreaterEqual => {
-                let bp = 3;
+                let bp = 5;
                 if bp > min_bp {
                     tokens.next();
                 } else {
                     break;
                 }
                 let rhs = parse_expr(tokens, bp)?;
-                lhs = ExpressionTree::Comparison(Comparison::GreaterEqual(
-                    Box::new(lhs),
-                    Box::new(rhs),
-                ));
+                lhs = Spanned {
+                    node: ExpressionTree::Comparison(Comparison::GreaterEqual(
+                        Box::new(lhs),
+                        Box::new(rhs),
+                    )),
+                    span,
+                };
             }
 
             Token::EqualEqual => {
-                let bp = 2;
+                let bp = 4;
                 if bp > min_bp {
                     tokens.next();
                 } else {
                     break;
                 }
                 let rhs = parse_expr(tokens, bp)?;
-                lhs = ExpressionTree::Equality(Equality::EqualEqual(Box::new(lhs), Box::new(rhs)));
+                lhs = Spanned {
+                    node: ExpressionTree::Equality(Equality::EqualEqual(
+                        Box::new(lhs),
+                        Box::new(rhs),
+                    )),
+                    span,
+                };
             }
             Token::BangEqual => {
+                let bp = 4;
+                if bp > min_bp {
+                    tokens.next();
+                } else {
+                    break;
+                }
+                let rhs = parse_expr(tokens, bp)?;
+                lhs = Spanned {
+                    node: ExpressionTree::Equality(Equality::BangEqual(
+                        Box::new(lhs),
+                        Box::new(rhs),
+                    )),
+                    span,
+                };
+            }
+            // Call is a postfix operator, it binds tighter than everything else.
+            Token::LeftParen => {
+                let bp = 8;
+                if bp > min_bp {
+                    tokens.next();
+                } else {
+                    break;
+                }
+                let mut arguments = Vec::new();
+                // A call is located at its closing parenthesis.
+                let mut right_paren = tokens.next_if_eq(&Token::RightParen);
+                if right_paren.is_none() {
+                    loop {
+                        if arguments.len() == MAX_ARGUMENTS {
+                            return Err(error_at_next(tokens, ParseErrorKind::TooManyArguments));
+                        }
+                        arguments.push(parse_expr(tokens, 0)?);
+                        if tokens.next_if_eq(&Token::Comma).is_none() {
+                            break;
+                        }
+                    }
+                    right_paren = tokens.next_if_eq(&Token::RightParen);
+                }
+                let Some(right_paren) = right_paren else {
+                    return Err(error_at_next(tokens, ParseErrorKind::MissingRightParen));
+                };
+                lhs = Spanned {
+                    node: ExpressionTree::Call {
+                        callee: Box::new(lhs),
+                        arguments,
+                    },
+                    span: right_paren.span,
+                };
+            }
+            Token::Dot => {
+                let bp = 8;
+                if bp > min_bp {
+                    tokens.next();
+                } else {
+                    break;
+                }
+                let name = expect_identifier(tokens)?;
+                lhs = Spanned {
+                    node: ExpressionTree::Get {
+                        object: Box::new(lhs),
+                        name: name.node,
+                    },
+                    span: name.span,
+                };
+            }
+            // Assignment is right associative, so we parse the right hand side with
+            // a lower binding power than its own.
+            Token::Equal => {
+                let bp = 1;
+                if bp > min_bp {
+                    tokens.next();
+                } else {
+                    break;
+                }
+                let value = Box::new(parse_expr(tokens, bp - 1)?);
+                // The assignment keeps the location of its target.
+                let node = match lhs.node {
+                    ExpressionTree::Primary(Primary::Identifier(variable)) => {
+                        ExpressionTree::Assignment(variable, value)
+                    }
+                    ExpressionTree::Get { object, name } => ExpressionTree::Set {
+                        object,
+                        name,
+                        value,
+                    },
+                    _ => {
+                        return Err(ParseExpressionError {
+                            kind: ParseErrorKind::InvalidAssignmentTarget,
+                            span,
+                            lexeme: Some("=".into()),
+                        })
+                    }
+                };
+                lhs = Spanned {
+                    node,
+                    span: lhs.span,
+                };
+            }
+            Token::And => {
+                let bp = 3;
+                if bp > min_bp {
+                    tokens.next();
+                } else {
+                    break;
+                }
+                let rhs = parse_expr(tokens, bp)?;
+                lhs = Spanned {
+                    node: ExpressionTree::Logical(Logical::And(Box::new(lhs), Box::new(rhs))),
+                    span,
+                };
+            }
+            Token::Or => {
                 let bp = 2;
                 if bp > min_bp {
                     tokens.next();
@@ -252,7 +794,10 @@ pub fn parse_expr<'de>(
                     break;
                 }
                 let rhs = parse_expr(tokens, bp)?;
-                lhs = ExpressionTree::Equality(Equality::BangEqual(Box::new(lhs), Box::new(rhs)));
+                lhs = Spanned {
+                    node: ExpressionTree::Logical(Logical::Or(Box::new(lhs), Box::new(rhs))),
+                    span,
+                };
             }
             _ => {
                 break;
@@ -262,20 +807,53 @@ pub fn parse_expr<'de>(
 
     Ok(lhs)
 }
+fn starts_expression(token: &Token<'_>) -> bool {
+    matches!(
+        token,
+        Token::Nil
+            | Token::True
+            | Token::False
+            | Token::Number(..)
+            | Token::String(..)
+            | Token::LeftParen
+            | Token::Identifier(_)
+            | Token::This
+            | Token::Super
+            | Token::Minus
+            | Token::Bang
+    )
+}
+
 // We only have left associativity (exept for prefix operator) so we can use only one binding power number
 
 #[derive(Debug, PartialEq)]
-pub enum ExpressionTree<'de> {
-    Primary(Primary<'de>),
-    Unary(Unary<'de>),
-    Factor(Factor<'de>),
-    Term(Term<'de>),
-    Comparison(Comparison<'de>),
-    Equality(Equality<'de>),
-    Assignment(&'de str, Box<ExpressionTree<'de>>),
+pub enum ExpressionTree {
+    Primary(Primary),
+    Unary(Unary),
+    Factor(Factor),
+    Term(Term),
+    Comparison(Comparison),
+    Equality(Equality),
+    Logical(Logical),
+    Assignment(Variable, Box<Spanned<ExpressionTree>>),
+    Call {
+        callee: Box<Spanned<ExpressionTree>>,
+        arguments: Vec<Spanned<ExpressionTree>>,
+    },
+    /// Property access.
+    Get {
+        object: Box<Spanned<ExpressionTree>>,
+        name: Rc<str>,
+    },
+    /// Property assignment.
+    Set {
+        object: Box<Spanned<ExpressionTree>>,
+        name: Rc<str>,
+        value: Box<Spanned<ExpressionTree>>,
+    },
 }
 
-impl fmt::Display for ExpressionTree<'_> {
+impl fmt::Display for ExpressionTree {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         match self {
             ExpressionTree::Primary(prim) => write!(f, "{prim}"),
@@ -284,24 +862,59 @@ impl fmt::Display for ExpressionTree<'_> {
             ExpressionTree::Term(term) => write!(f, "{term}"),
             ExpressionTree::Comparison(comparison) => write!(f, "{comparison}"),
             ExpressionTree::Equality(equality) => write!(f, "{equality}"),
-            ExpressionTree::Assignment(ident, expr) => write!(f, "{ident} = {expr}"),
+            ExpressionTree::Logical(logical) => write!(f, "{logical}"),
+            ExpressionTree::Assignment(variable, expr) => write!(f, "{} = {expr}", variable.name),
+            ExpressionTree::Call { callee, arguments } => {
+                write!(f, "(call {callee}")?;
+                for argument in arguments {
+                    write!(f, " {argument}")?;
+                }
+                write!(f, ")")
+            }
+            ExpressionTree::Get { object, name } => write!(f, "(. {object} {name})"),
+            ExpressionTree::Set {
+                object,
+                name,
+                value,
+            } => write!(f, "(= (. {object} {name}) {value})"),
         }
     }
 }
 
 #[derive(Debug, PartialEq)]
-pub enum Primary<'de> {
-    String(&'de str),
+pub enum Primary {
+    String(Rc<str>),
     Number(f64),
     True,
     False,
     Nil,
-    Group(Box<ExpressionTree<'de>>),
+    Group(Box<Spanned<ExpressionTree>>),
     // A variable name
-    Identifier(&'de str),
+    Identifier(Variable),
+    This(Variable),
+    // The method looked up in the superclass
+    Super(Variable, Rc<str>),
 }
 
-impl fmt::Display for Primary<'_> {
+/// A use of a variable, `this` and `super` included.
+#[derive(Debug, PartialEq)]
+pub struct Variable {
+    pub name: Rc<str>,
+    /// Number of scopes between the use of the variable and its declaration,
+    /// computed by the resolver. `None` means it is a global variable.
+    pub depth: Cell<Option<usize>>,
+}
+
+impl Variable {
+    pub fn new(name: Rc<str>) -> Self {
+        Self {
+            name,
+            depth: Cell::new(None),
+        }
+    }
+}
+
+impl fmt::Display for Primary {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         match self {
             Primary::String(s) => write!(f, "{s}"),
@@ -311,16 +924,18 @@ impl fmt::Display for Primary<'_> {
             Primary::Nil => write!(f, "nil"),
             Primary::Group(tt) => write!(f, "(group {tt})"),
             Primary::Identifier(_) => todo!(),
+            Primary::This(_) => write!(f, "this"),
+            Primary::Super(_, method) => write!(f, "(super {method})"),
         }
     }
 }
 #[derive(Debug, PartialEq)]
-pub enum Unary<'de> {
-    Bang(Box<ExpressionTree<'de>>),
-    Minus(Box<ExpressionTree<'de>>),
+pub enum Unary {
+    Bang(Box<Spanned<ExpressionTree>>),
+    Minus(Box<Spanned<ExpressionTree>>),
 }
 
-impl fmt::Display for Unary<'_> {
+impl fmt::Display for Unary {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         match self {
             Unary::Bang(tt) => write!(f, "(! {tt})"),
@@ -330,12 +945,12 @@ impl fmt::Display for Unary<'_> {
 }
 
 #[derive(Debug, PartialEq)]
-pub enum Factor<'de> {
-    Slash(Box<ExpressionTree<'de>>, Box<ExpressionTree<'de>>),
-    Star(Box<ExpressionTree<'de>>, Box<ExpressionTree<'de>>),
+pub enum Factor {
+    Slash(Box<Spanned<ExpressionTree>>, Box<Spanned<ExpressionTree>>),
+    Star(Box<Spanned<ExpressionTree>>, Box<Spanned<ExpressionTree>>),
 }
 
-impl fmt::Display for Factor<'_> {
+impl fmt::Display for Factor {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         match self {
             Factor::Slash(left, right) => write!(f, "(/ {left} {right})"),
@@ -345,12 +960,12 @@ impl fmt::Display for Factor<'_> {
 }
 
 #[derive(Debug, PartialEq)]
-pub enum Term<'de> {
-    Minus(Box<ExpressionTree<'de>>, Box<ExpressionTree<'de>>),
-    Plus(Box<ExpressionTree<'de>>, Box<ExpressionTree<'de>>),
+pub enum Term {
+    Minus(Box<Spanned<ExpressionTree>>, Box<Spanned<ExpressionTree>>),
+    Plus(Box<Spanned<ExpressionTree>>, Box<Spanned<ExpressionTree>>),
 }
 
-impl fmt::Display for Term<'_> {
+impl fmt::Display for Term {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         match self {
             Term::Minus(left, right) => write!(f, "(- {left} {right})"),
@@ -360,14 +975,14 @@ impl fmt::Display for Term<'_> {
 }
 
 #[derive(Debug, PartialEq)]
-pub enum Comparison<'de> {
-    Less(Box<ExpressionTree<'de>>, Box<ExpressionTree<'de>>),
-    LessEqual(Box<ExpressionTree<'de>>, Box<ExpressionTree<'de>>),
-    Greater(Box<ExpressionTree<'de>>, Box<ExpressionTree<'de>>),
-    GreaterEqual(Box<ExpressionTree<'de>>, Box<ExpressionTree<'de>>),
+pub enum Comparison {
+    Less(Box<Spanned<ExpressionTree>>, Box<Spanned<ExpressionTree>>),
+    LessEqual(Box<Spanned<ExpressionTree>>, Box<Spanned<ExpressionTree>>),
+    Greater(Box<Spanned<ExpressionTree>>, Box<Spanned<ExpressionTree>>),
+    GreaterEqual(Box<Spanned<ExpressionTree>>, Box<Spanned<ExpressionTree>>),
 }
 
-impl fmt::Display for Comparison<'_> {
+impl fmt::Display for Comparison {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         match self {
             Comparison::Less(left, right) => write!(f, "(< {left} {right})"),
@@ -379,12 +994,12 @@ impl fmt::Display for Comparison<'_> {
 }
 
 #[derive(Debug, PartialEq)]
-pub enum Equality<'de> {
-    EqualEqual(Box<ExpressionTree<'de>>, Box<ExpressionTree<'de>>),
-    BangEqual(Box<ExpressionTree<'de>>, Box<ExpressionTree<'de>>),
+pub enum Equality {
+    EqualEqual(Box<Spanned<ExpressionTree>>, Box<Spanned<ExpressionTree>>),
+    BangEqual(Box<Spanned<ExpressionTree>>, Box<Spanned<ExpressionTree>>),
 }
 
-impl fmt::Display for Equality<'_> {
+impl fmt::Display for Equality {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         match self {
             Equality::EqualEqual(left, right) => write!(f, "(== {left} {right})"),
@@ -393,21 +1008,110 @@ impl fmt::Display for Equality<'_> {
     }
 }
 
+/// Unlike the other binary operators, the right operand is only evaluated if needed.
+#[derive(Debug, PartialEq)]
+pub enum Logical {
+    And(Box<Spanned<ExpressionTree>>, Box<Spanned<ExpressionTree>>),
+    Or(Box<Spanned<ExpressionTree>>, Box<Spanned<ExpressionTree>>),
+}
+
+impl fmt::Display for Logical {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Logical::And(left, right) => write!(f, "(and {left} {right})"),
+            Logical::Or(left, right) => write!(f, "(or {left} {right})"),
+        }
+    }
+}
+
+#[derive(Debug)]
+pub struct ParseExpressionError {
+    kind: ParseErrorKind,
+    span: Span,
+    /// The token the error was found on, `None` at the end of the file.
+    lexeme: Option<Box<str>>,
+}
+
+impl ParseExpressionError {
+    pub fn kind(&self) -> &ParseErrorKind {
+        &self.kind
+    }
+
+    pub(crate) fn new(kind: ParseErrorKind, span: Span, lexeme: Option<&str>) -> Self {
+        Self {
+            kind,
+            span,
+            lexeme: lexeme.map(Box::from),
+        }
+    }
+
+    pub fn diagnostic(&self) -> Diagnostic {
+        Diagnostic::new(self.kind.code(), self.to_string(), self.span)
+    }
+}
+
 #[derive(Debug)]
-pub enum ParseExpressionError<'de> {
-    InvalidToken(Token<'de>),
+#[non_exhaustive]
+pub enum ParseErrorKind {
+    ExpectedExpression,
+    MissingIdentifier,
+    MissingLeftParen,
     MissingRightParen,
+    MissingLeftBrace,
     MissingRightBrace,
+    MissingSemicolon,
+    MissingDot,
+    MissingStatement,
+    InvalidAssignmentTarget,
+    TooManyParameters,
+    TooManyArguments,
 }
 
-impl<'de> std::error::Error for ParseExpressionError<'de> {}
+impl ParseErrorKind {
+    fn code(&self) -> &'static str {
+        match self {
+            ParseErrorKind::ExpectedExpression => "E0201",
+            ParseErrorKind::MissingIdentifier => "E0202",
+            ParseErrorKind::MissingLeftParen => "E0203",
+            ParseErrorKind::MissingRightParen => "E0204",
+            ParseErrorKind::MissingLeftBrace => "E0205",
+            ParseErrorKind::MissingRightBrace => "E0206",
+            ParseErrorKind::MissingSemicolon => "E0207",
+            ParseErrorKind::MissingDot => "E0208",
+            ParseErrorKind::MissingStatement => "E0209",
+            ParseErrorKind::InvalidAssignmentTarget => "E0210",
+            ParseErrorKind::TooManyParameters => "E0211",
+            ParseErrorKind::TooManyArguments => "E0212",
+        }
+    }
+}
+
+impl std::error::Error for ParseExpressionError {}
 
-impl fmt::Display for ParseExpressionError<'_> {
+impl fmt::Display for ParseExpressionError {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-        match self {
-            ParseExpressionError::InvalidToken(token) => write!(f, "invalid token: {token}"),
-            ParseExpressionError::MissingRightParen => write!(f, "missing right paren"),
-            ParseExpressionError::MissingRightBrace => write!(f, "missing right brace"),
+        let line = self.span.line;
+        match &self.lexeme {
+            Some(lexeme) => write!(f, "[line {line}] Error at '{lexeme}': ")?,
+            None => write!(f, "[line {line}] Error at end: ")?,
+        }
+        match self.kind {
+            ParseErrorKind::ExpectedExpression => write!(f, "Expect expression."),
+            ParseErrorKind::MissingIdentifier => write!(f, "Expect identifier."),
+            ParseErrorKind::MissingLeftParen => write!(f, "Expect '('."),
+            ParseErrorKind::MissingRightParen => write!(f, "Expect ')'."),
+            ParseErrorKind::MissingLeftBrace => write!(f, "Expect '{{'."),
+            ParseErrorKind::MissingRightBrace => write!(f, "Expect '}}'."),
+            ParseErrorKind::MissingSemicolon => write!(f, "Expect ';'."),
+            ParseErrorKind::MissingDot => write!(f, "Expect '.'."),
+            ParseErrorKind::MissingStatement => write!(f, "Expect statement."),
+            ParseErrorKind::InvalidAssignmentTarget => write!(f, "Invalid assignment target."),
+            ParseErrorKind::TooManyParameters => {
+                write!(f, "Can't have more than {MAX_ARGUMENTS} parameters.")
+            }
+            ParseErrorKind::TooManyArguments => {
+                write!(f, "Can't have more than {MAX_ARGUMENTS} arguments.")
+            }
         }
     }
 }
@@ -0,0 +1,326 @@
+use std::{
+    fs,
+    io::{self, Write},
+};
+
+use rustyline::{error::ReadlineError, DefaultEditor};
+
+use lox::{
+    diagnostic::Emitter, lex::LexingErrorKind, parse::StatementTree, parse_statements, resolve,
+    Interpreter, Lexer, Spanned, Token,
+};
+
+const HELP: &str = "\
+Enter Lox statements, or an expression to print its value.
+Input continues on the next line while braces or parentheses are open.
+
+Commands:
+  :help         Show this message
+  :reset        Forget every variable, function and class
+  :load <file>  Run a file in the current session
+  :env          List the global variables
+
+Press Ctrl-D to exit, Ctrl-C to discard the current input.";
+
+/// Interactive session, where the state of the interpreter is kept between inputs.
+pub fn run() -> rustyline::Result<()> {
+    let mut editor = DefaultEditor::new()?;
+    let mut interpreter = Interpreter::new();
+    let mut input = String::new();
+
+    println!("Lox REPL, type :help for help.");
+    loop {
+        let prompt = if input.is_empty() { "> " } else { "... " };
+        let line = match editor.readline(prompt) {
+            Ok(line) => line,
+            Err(ReadlineError::Interrupted) => {
+                input.clear();
+                continue;
+            }
+            Err(ReadlineError::Eof) => return Ok(()),
+            Err(err) => return Err(err),
+        };
+
+        if input.is_empty() {
+            if let Some(command) = line.trim().strip_prefix(':') {
+                editor.add_history_entry(line.as_str())?;
+                run_command(command, &mut interpreter, &mut io::stdout())?;
+                continue;
+            }
+            if line.trim().is_empty() {
+                continue;
+            }
+        }
+        input.push_str(&line);
+        input.push('\n');
+        if is_incomplete(&input) {
+            continue;
+        }
+
+        editor.add_history_entry(input.trim_end())?;
+        run_source(&mut interpreter, "<repl>", &input, true);
+        input.clear();
+    }
+}
+
+/// Run a `:command`, writing its output to `out`.
+fn run_command(
+    command: &str,
+    interpreter: &mut Interpreter,
+    out: &mut impl Write,
+) -> io::Result<()> {
+    let (name, argument) = command
+        .split_once(char::is_whitespace)
+        .map_or((command, ""), |(name, argument)| (name, argument.trim()));
+    match name {
+        "help" => writeln!(out, "{HELP}")?,
+        "reset" => {
+            *interpreter = Interpreter::new();
+            writeln!(out, "Environment cleared.")?;
+        }
+        "load" if !argument.is_empty() => match fs::read_to_string(argument) {
+            Ok(file_contents) => run_source(interpreter, argument, &file_contents, false),
+            Err(err) => eprintln!("Failed to read file {argument}: {err}"),
+        },
+        "load" => eprintln!("Usage: :load <file>"),
+        "env" => {
+            for (name, value) in interpreter.globals() {
+                writeln!(out, "{name} = {value}")?;
+            }
+        }
+        _ => eprintln!("Unknown command ':{name}', type :help for the list of commands."),
+    }
+    Ok(())
+}
+
+/// Whether the user is still typing: a block, a group, a string or a comment is not
+/// closed yet.
+fn is_incomplete(input: &str) -> bool {
+    let mut depth = 0i32;
+    for token in Lexer::new(input) {
+        match token {
+            Ok(token) => match token.node {
+                Token::LeftBrace | Token::LeftParen => depth += 1,
+                Token::RightBrace | Token::RightParen => depth -= 1,
+                _ => {}
+            },
+            Err(err)
+                if matches!(
+                    err.kind(),
+                    LexingErrorKind::UnterminatedString | LexingErrorKind::UnterminatedBlockComment
+                ) =>
+            {
+                return true
+            }
+            Err(_) => {}
+        }
+    }
+    depth > 0
+}
+
+/// Run the source in the interpreter, reporting the errors. When `echo` is set, a lone
+/// expression without a trailing semicolon is printed, like a `print` statement.
+fn run_source(interpreter: &mut Interpreter, filename: &str, source: &str, echo: bool) {
+    let emitter = Emitter::new(filename, source);
+    let Some(statements) = parse_source(&emitter, source, echo) else {
+        return;
+    };
+    if let Err(errors) = resolve(&statements) {
+        for err in errors {
+            emitter.emit(&err.diagnostic());
+        }
+        return;
+    }
+    if let Err(err) = interpreter.evaluate(&statements) {
+        emitter.emit(&err.diagnostic());
+    }
+}
+
+/// Parse the source, reporting the errors. When `echo` is set, a final expression
+/// without its semicolon is turned into a `print` statement.
+fn parse_source(
+    emitter: &Emitter<'_>,
+    source: &str,
+    echo: bool,
+) -> Option<Vec<Spanned<StatementTree>>> {
+    let mut lexical_error = false;
+    let mut tokens: Vec<Spanned<Token<'_>>> = Lexer::new(source)
+        .filter_map(|token| match token {
+            Ok(token) => Some(token),
+            Err(err) => {
+                emitter.emit(&err.diagnostic());
+                lexical_error = true;
+                None
+            }
+        })
+        .collect();
+
+    // The semicolon of the last statement is optional in the REPL.
+    let bare = echo
+        && tokens.len() >= 2
+        && !matches!(
+            tokens[tokens.len() - 2].node,
+            Token::Semicolon | Token::RightBrace
+        );
+    if bare {
+        let eof = tokens.len() - 1;
+        let span = tokens[eof].span;
+        tokens.insert(
+            eof,
+            Spanned {
+                node: Token::Semicolon,
+                span,
+            },
+        );
+    }
+
+    let mut statements = match parse_statements(&mut tokens.into_iter().peekable()) {
+        Ok(statements) => statements,
+        Err(errors) => {
+            for err in errors {
+                emitter.emit(&err.diagnostic());
+            }
+            return None;
+        }
+    };
+    if lexical_error {
+        return None;
+    }
+    // Only the statement that was missing its semicolon is echoed, the ones
+    // before it run as usual.
+    if bare {
+        if let Some(last) = statements.pop() {
+            let node = match last.node {
+                StatementTree::Expr(expr) => StatementTree::Print(expr),
+                node => node,
+            };
+            statements.push(Spanned {
+                node,
+                span: last.span,
+            });
+        }
+    }
+    Some(statements)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(source: &str) -> Vec<Spanned<StatementTree>> {
+        parse_source(&Emitter::new("<repl>", source), source, true).unwrap()
+    }
+
+    fn command(command: &str, interpreter: &mut Interpreter) -> String {
+        let mut out = Vec::new();
+        run_command(command, interpreter, &mut out).unwrap();
+        String::from_utf8(out).unwrap()
+    }
+
+    #[test]
+    fn final_expression_is_echoed() {
+        let statements = parse("1 + 2\n");
+        assert!(matches!(
+            statements[..],
+            [Spanned {
+                node: StatementTree::Print(_),
+                ..
+            }]
+        ));
+    }
+
+    #[test]
+    fn final_expression_is_echoed_after_other_statements() {
+        let statements = parse("var a = 1; a\n");
+        assert!(matches!(
+            statements[..],
+            [
+                Spanned {
+                    node: StatementTree::VarDeclaration { .. },
+                    ..
+                },
+                Spanned {
+                    node: StatementTree::Print(_),
+                    ..
+                }
+            ]
+        ));
+    }
+
+    #[test]
+    fn terminated_statements_are_not_echoed() {
+        let statements = parse("var a = 1; a;\n");
+        assert!(matches!(
+            statements[..],
+            [
+                Spanned {
+                    node: StatementTree::VarDeclaration { .. },
+                    ..
+                },
+                Spanned {
+                    node: StatementTree::Expr(_),
+                    ..
+                }
+            ]
+        ));
+        let statements = parse("var b = 2\n");
+        assert!(matches!(
+            statements[..],
+            [Spanned {
+                node: StatementTree::VarDeclaration { .. },
+                ..
+            }]
+        ));
+    }
+
+    #[test]
+    fn open_delimiters_continue_the_input() {
+        assert!(is_incomplete("fun f() {\n"));
+        assert!(is_incomplete("fun f() {\n  print (1 +\n"));
+        assert!(is_incomplete("print \"a\n"));
+        assert!(!is_incomplete("fun f() {\n  print 1;\n}\n"));
+        assert!(!is_incomplete("print \"a\nb\";\n"));
+        // Unbalanced closing delimiters are reported by the parser instead.
+        assert!(!is_incomplete("}\n"));
+    }
+
+    #[test]
+    fn env_lists_the_globals_in_order() {
+        let mut interpreter = Interpreter::new();
+        run_source(&mut interpreter, "<repl>", "var b = \"two\";\n", true);
+        run_source(&mut interpreter, "<repl>", "var a = 1;\n", true);
+        assert_eq!(
+            command("env", &mut interpreter),
+            "a = 1\nb = two\nclock = <native fn>\n"
+        );
+    }
+
+    #[test]
+    fn reset_forgets_the_globals() {
+        let mut interpreter = Interpreter::new();
+        run_source(&mut interpreter, "<repl>", "var a = 1;\n", true);
+        assert_eq!(command("reset", &mut interpreter), "Environment cleared.\n");
+        // The native functions are defined again.
+        assert_eq!(command("env", &mut interpreter), "clock = <native fn>\n");
+    }
+
+    #[test]
+    fn load_runs_a_file_in_the_session() {
+        let path = std::env::temp_dir().join(format!("repl-load-{}.lox", std::process::id()));
+        fs::write(
+            &path,
+            "var loaded = 40;\nfun add(n) { return loaded + n; }\n",
+        )
+        .unwrap();
+        let mut interpreter = Interpreter::new();
+        let output = command(&format!("load {}", path.display()), &mut interpreter);
+        fs::remove_file(&path).unwrap();
+        assert_eq!(output, "");
+
+        run_source(&mut interpreter, "<repl>", "var total = add(2);\n", true);
+        assert_eq!(
+            command("env", &mut interpreter),
+            "add = <fn add>\nclock = <native fn>\nloaded = 40\ntotal = 42\n"
+        );
+    }
+}
@@ -0,0 +1,402 @@
+use std::{collections::HashMap, fmt, mem, rc::Rc};
+
+use crate::{
+    diagnostic::Diagnostic,
+    intern::intern,
+    lex::{Span, Spanned},
+    parse::{
+        Comparison, Equality, ExpressionTree, Factor, Function, Logical, Primary, StatementTree,
+        Term, Unary, Variable,
+    },
+};
+
+/// Static pass between the parsing and the interpretation.
+///
+/// For every use of a local variable, it computes the number of scopes between the use and
+/// the declaration, so the interpreter can directly jump to the right scope. It also reports
+/// all the errors that can be detected without running the program.
+pub fn resolve(statements: &[Spanned<StatementTree>]) -> Result<(), Vec<ResolveError>> {
+    let mut resolver = Resolver::default();
+    resolver.resolve_statements(statements);
+    if resolver.errors.is_empty() {
+        Ok(())
+    } else {
+        Err(resolver.errors)
+    }
+}
+
+#[derive(Default)]
+struct Resolver {
+    /// The local scopes, from the outermost to the innermost. Global variables are not
+    /// tracked.
+    scopes: Vec<HashMap<Rc<str>, Local>>,
+    function: FunctionKind,
+    class: ClassKind,
+    /// The errors don't stop the resolution, so they are all reported at once.
+    errors: Vec<ResolveError>,
+}
+
+struct Local {
+    /// `false` while the initializer of the variable is being resolved.
+    defined: bool,
+    /// Where the variable is declared.
+    span: Span,
+}
+
+impl Local {
+    /// A variable created by the language itself, like `this`.
+    fn implicit() -> Self {
+        Self {
+            defined: true,
+            span: Span::default(),
+        }
+    }
+}
+
+#[derive(Clone, Copy, Default, PartialEq)]
+enum FunctionKind {
+    #[default]
+    None,
+    Function,
+    Method,
+    Initializer,
+}
+
+#[derive(Clone, Copy, Default, PartialEq)]
+enum ClassKind {
+    #[default]
+    None,
+    Class,
+    Subclass,
+}
+
+impl Resolver {
+    fn error(&mut self, kind: ResolveErrorKind, span: Span) {
+        self.errors.push(ResolveError::new(kind, span));
+    }
+
+    fn resolve_statements(&mut self, statements: &[Spanned<StatementTree>]) {
+        for statement in statements {
+            self.resolve_statement(statement);
+        }
+    }
+
+    fn resolve_statement(&mut self, statement: &Spanned<StatementTree>) {
+        let span = statement.span;
+        match &statement.node {
+            StatementTree::Print(expr) | StatementTree::Expr(expr) => self.resolve_expr(expr),
+            StatementTree::VarDeclaration { ident, expr } => {
+                self.declare(ident, span);
+                if let Some(expr) = expr {
+                    self.resolve_expr(expr);
+                }
+                self.define(ident);
+            }
+            StatementTree::Block(statements) => {
+                self.scopes.push(HashMap::new());
+                self.resolve_statements(statements);
+                self.scopes.pop();
+            }
+            StatementTree::If {
+                condition,
+                then_branch,
+                else_branch,
+            } => {
+                self.resolve_expr(condition);
+                self.resolve_statement(then_branch);
+                if let Some(else_branch) = else_branch {
+                    self.resolve_statement(else_branch);
+                }
+            }
+            StatementTree::While { condition, body } => {
+                self.resolve_expr(condition);
+                self.resolve_statement(body);
+            }
+            StatementTree::Function(function) => {
+                // The function is defined before its body is resolved, so it can
+                // call itself recursively.
+                self.declare(&function.name, span);
+                self.define(&function.name);
+                self.resolve_function(function, FunctionKind::Function);
+            }
+            StatementTree::Return(expr) => {
+                if self.function == FunctionKind::None {
+                    self.error(ResolveErrorKind::TopLevelReturn, span);
+                }
+                if let Some(expr) = expr {
+                    if self.function == FunctionKind::Initializer {
+                        self.error(ResolveErrorKind::ReturnFromInitializer, span);
+                    }
+                    self.resolve_expr(expr);
+                }
+            }
+            StatementTree::Class {
+                name,
+                superclass,
+                methods,
+            } => {
+                let enclosing_class = mem::replace(&mut self.class, ClassKind::Class);
+                self.resolve_class(name, span, superclass.as_ref(), methods);
+                self.class = enclosing_class;
+            }
+        }
+    }
+
+    fn resolve_class(
+        &mut self,
+        name: &Rc<str>,
+        span: Span,
+        superclass: Option<&Spanned<ExpressionTree>>,
+        methods: &[Rc<Function>],
+    ) {
+        self.declare(name, span);
+        self.define(name);
+
+        if let Some(superclass) = superclass {
+            if let ExpressionTree::Primary(Primary::Identifier(variable)) = &superclass.node {
+                if &variable.name == name {
+                    self.error(
+                        ResolveErrorKind::InheritFromItself(Rc::clone(name)),
+                        superclass.span,
+                    );
+                }
+            }
+            self.class = ClassKind::Subclass;
+            self.resolve_expr(superclass);
+            self.scopes
+                .push(HashMap::from([(intern("super"), Local::implicit())]));
+        }
+
+        // Mirror the scope created by the interpreter when a method is bound to an instance.
+        self.scopes
+            .push(HashMap::from([(intern("this"), Local::implicit())]));
+        for method in methods {
+            let kind = if &*method.name == "init" {
+                FunctionKind::Initializer
+            } else {
+                FunctionKind::Method
+            };
+            self.resolve_function(method, kind);
+        }
+        self.scopes.pop();
+
+        if superclass.is_some() {
+            self.scopes.pop();
+        }
+    }
+
+    fn resolve_function(&mut self, function: &Function, kind: FunctionKind) {
+        let enclosing_function = mem::replace(&mut self.function, kind);
+        // Parameters and body share the same scope, like in the interpreter.
+        self.scopes.push(HashMap::new());
+        for param in &function.params {
+            self.declare(&param.node, param.span);
+            self.define(&param.node);
+        }
+        self.resolve_statements(&function.body);
+        self.scopes.pop();
+        self.function = enclosing_function;
+    }
+
+    fn resolve_expr(&mut self, expr: &Spanned<ExpressionTree>) {
+        let span = expr.span;
+        match &expr.node {
+            ExpressionTree::Primary(primary) => match primary {
+                Primary::String(_)
+                | Primary::Number(_)
+                | Primary::True
+                | Primary::False
+                | Primary::Nil => {}
+                Primary::Group(expr) => self.resolve_expr(expr),
+                Primary::Identifier(variable) => {
+                    if self
+                        .scopes
+                        .last()
+                        .and_then(|scope| scope.get(&variable.name))
+                        .is_some_and(|local| !local.defined)
+                    {
+                        self.error(
+                            ResolveErrorKind::ReadInOwnInitializer(Rc::clone(&variable.name)),
+                            span,
+                        );
+                    }
+                    self.resolve_local(variable);
+                }
+                Primary::This(variable) => {
+                    if self.class == ClassKind::None {
+                        self.error(ResolveErrorKind::ThisOutsideClass, span);
+                    }
+                    self.resolve_local(variable);
+                }
+                Primary::Super(variable, _) => match self.class {
+                    ClassKind::None => self.error(ResolveErrorKind::SuperOutsideClass, span),
+                    ClassKind::Class => self.error(ResolveErrorKind::SuperWithoutSuperclass, span),
+                    ClassKind::Subclass => self.resolve_local(variable),
+                },
+            },
+            ExpressionTree::Unary(Unary::Bang(expr) | Unary::Minus(expr)) => {
+                self.resolve_expr(expr)
+            }
+            ExpressionTree::Factor(Factor::Slash(lhs, rhs) | Factor::Star(lhs, rhs))
+            | ExpressionTree::Term(Term::Minus(lhs, rhs) | Term::Plus(lhs, rhs))
+            | ExpressionTree::Comparison(
+                Comparison::Less(lhs, rhs)
+                | Comparison::LessEqual(lhs, rhs)
+                | Comparison::Greater(lhs, rhs)
+                | Comparison::GreaterEqual(lhs, rhs),
+            )
+            | ExpressionTree::Equality(
+                Equality::EqualEqual(lhs, rhs) | Equality::BangEqual(lhs, rhs),
+            )
+            | ExpressionTree::Logical(Logical::And(lhs, rhs) | Logical::Or(lhs, rhs)) => {
+                self.resolve_expr(lhs);
+                self.resolve_expr(rhs);
+            }
+            ExpressionTree::Assignment(variable, expr) => {
+                self.resolve_expr(expr);
+                self.resolve_local(variable);
+            }
+            ExpressionTree::Call { callee, arguments } => {
+                self.resolve_expr(callee);
+                for argument in arguments {
+                    self.resolve_expr(argument);
+                }
+            }
+            ExpressionTree::Get { object, .. } => self.resolve_expr(object),
+            ExpressionTree::Set { object, value, .. } => {
+                self.resolve_expr(value);
+                self.resolve_expr(object);
+            }
+        }
+    }
+
+    fn declare(&mut self, name: &Rc<str>, span: Span) {
+        if let Some(scope) = self.scopes.last_mut() {
+            let local = Local {
+                defined: false,
+                span,
+            };
+            if let Some(previous) = scope.insert(Rc::clone(name), local) {
+                self.error(
+                    ResolveErrorKind::AlreadyDeclared {
+                        name: Rc::clone(name),
+                        previous: previous.span,
+                    },
+                    span,
+                );
+            }
+        }
+    }
+
+    fn define(&mut self, name: &str) {
+        if let Some(local) = self.scopes.last_mut().and_then(|scope| scope.get_mut(name)) {
+            local.defined = true;
+        }
+    }
+
+    /// If the variable is not found in a local scope, we assume it is global.
+    fn resolve_local(&mut self, variable: &Variable) {
+        for (depth, scope) in self.scopes.iter().rev().enumerate() {
+            if scope.contains_key(&variable.name) {
+                variable.depth.set(Some(depth));
+                return;
+            }
+        }
+    }
+}
+
+#[derive(Debug)]
+pub struct ResolveError {
+    kind: ResolveErrorKind,
+    span: Span,
+}
+
+impl ResolveError {
+    fn new(kind: ResolveErrorKind, span: Span) -> Self {
+        Self { kind, span }
+    }
+
+    pub fn kind(&self) -> &ResolveErrorKind {
+        &self.kind
+    }
+
+    pub fn diagnostic(&self) -> Diagnostic {
+        let diagnostic = Diagnostic::new(self.kind.code(), self.to_string(), self.span);
+        match &self.kind {
+            ResolveErrorKind::AlreadyDeclared { name, previous } => {
+                diagnostic.with_note(format!("'{name}' is first declared here"), Some(*previous))
+            }
+            ResolveErrorKind::ReturnFromInitializer => {
+                diagnostic.with_note("an initializer always returns 'this'", None)
+            }
+            _ => diagnostic,
+        }
+    }
+}
+
+#[derive(Debug)]
+#[non_exhaustive]
+pub enum ResolveErrorKind {
+    ReadInOwnInitializer(Rc<str>),
+    AlreadyDeclared { name: Rc<str>, previous: Span },
+    TopLevelReturn,
+    ReturnFromInitializer,
+    ThisOutsideClass,
+    SuperOutsideClass,
+    SuperWithoutSuperclass,
+    InheritFromItself(Rc<str>),
+}
+
+impl ResolveErrorKind {
+    fn code(&self) -> &'static str {
+        match self {
+            ResolveErrorKind::ReadInOwnInitializer(_) => "E0301",
+            ResolveErrorKind::AlreadyDeclared { .. } => "E0302",
+            ResolveErrorKind::TopLevelReturn => "E0303",
+            ResolveErrorKind::ReturnFromInitializer => "E0304",
+            ResolveErrorKind::ThisOutsideClass => "E0305",
+            ResolveErrorKind::SuperOutsideClass => "E0306",
+            ResolveErrorKind::SuperWithoutSuperclass => "E0307",
+            ResolveErrorKind::InheritFromItself(_) => "E0308",
+        }
+    }
+}
+
+impl std::error::Error for ResolveError {}
+
+impl fmt::Display for ResolveError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let line = self.span.line;
+        write!(f, "[line {line}] ")?;
+        match &self.kind {
+            ResolveErrorKind::ReadInOwnInitializer(ident) => write!(
+                f,
+                "Error at '{ident}': Can't read local variable in its own initializer."
+            ),
+            ResolveErrorKind::AlreadyDeclared { name: ident, .. } => write!(
+                f,
+                "Error at '{ident}': Already a variable with this name in this scope."
+            ),
+            ResolveErrorKind::TopLevelReturn => {
+                write!(f, "Error at 'return': Can't return from top-level code.")
+            }
+            ResolveErrorKind::ReturnFromInitializer => write!(
+                f,
+                "Error at 'return': Can't return a value from an initializer."
+            ),
+            ResolveErrorKind::ThisOutsideClass => {
+                write!(f, "Error at 'this': Can't use 'this' outside of a class.")
+            }
+            ResolveErrorKind::SuperOutsideClass => {
+                write!(f, "Error at 'super': Can't use 'super' outside of a class.")
+            }
+            ResolveErrorKind::SuperWithoutSuperclass => write!(
+                f,
+                "Error at 'super': Can't use 'super' in a class with no superclass."
+            ),
+            ResolveErrorKind::InheritFromItself(name) => {
+                write!(f, "Error at '{name}': A class can't inherit from itself.")
+            }
+        }
+    }
+}
@@ -0,0 +1,957 @@
+use std::{
+    collections::HashMap,
+    error::Error,
+    fmt,
+    io::{self, Write},
+    rc::Rc,
+};
+
+use crate::{
+    chunk::{Constant, Function, OpCode},
+    compile::compile_source,
+    interpret::{self, EvaluationError, EvaluationErrorKind, HeapStats, RunError, MAX_CALL_DEPTH},
+    lex::Span,
+    native::{self, ConversionError, NativeFunction},
+};
+
+/// A value of the virtual machine. Strings can't refer to other values, so they are
+/// shared without going through the heap.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Value {
+    Nil,
+    Boolean(bool),
+    Number(f64),
+    String(Rc<str>),
+    Object(ObjRef),
+}
+
+impl Value {
+    /// In Lox, `nil` and `false` are falsy, everything else is truthy.
+    fn is_truthy(&self) -> bool {
+        !matches!(self, Value::Nil | Value::Boolean(false))
+    }
+
+    fn as_object(&self) -> Option<ObjRef> {
+        match self {
+            Value::Object(object) => Some(*object),
+            _ => None,
+        }
+    }
+}
+
+/// Handle to an object of the heap.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct ObjRef(usize);
+
+enum Object {
+    Closure(Closure),
+    Upvalue(Upvalue),
+    Class(Class),
+    Instance(Instance),
+    BoundMethod(BoundMethod),
+    Native(Rc<NativeFunction>),
+}
+
+impl Object {
+    /// Name of the type of the object, as shown in the error messages.
+    fn type_name(&self) -> &'static str {
+        match self {
+            Object::Closure(_) | Object::BoundMethod(_) | Object::Native(_) => "function",
+            Object::Upvalue(_) => "upvalue",
+            Object::Class(_) => "class",
+            Object::Instance(_) => "instance",
+        }
+    }
+
+    /// Name of the kind of object, for the logs of the collector.
+    fn kind(&self) -> &'static str {
+        match self {
+            Object::Closure(_) => "closure",
+            Object::Upvalue(_) => "upvalue",
+            Object::Class(_) => "class",
+            Object::Instance(_) => "instance",
+            Object::BoundMethod(_) => "bound method",
+            Object::Native(_) => "native",
+        }
+    }
+
+    /// Push the objects this one refers to.
+    fn references(&self, references: &mut Vec<ObjRef>) {
+        match self {
+            Object::Closure(closure) => references.extend(&closure.upvalues),
+            Object::Upvalue(Upvalue::Open(_)) | Object::Native(_) => {}
+            Object::Upvalue(Upvalue::Closed(value)) => references.extend(value.as_object()),
+            Object::Class(class) => references.extend(class.methods.values()),
+            Object::Instance(instance) => {
+                references.push(instance.class);
+                references.extend(instance.fields.values().filter_map(Value::as_object));
+            }
+            Object::BoundMethod(bound) => {
+                references.push(bound.method);
+                references.extend(bound.receiver.as_object());
+            }
+        }
+    }
+}
+
+struct Closure {
+    function: Rc<Function>,
+    upvalues: Vec<ObjRef>,
+}
+
+/// A variable captured by a closure. It lives on the stack while its scope is active,
+/// then it is moved in the upvalue.
+enum Upvalue {
+    Open(usize),
+    Closed(Value),
+}
+
+struct Class {
+    name: Rc<str>,
+    /// The closures of the methods, inherited ones included.
+    methods: HashMap<Rc<str>, ObjRef>,
+}
+
+struct Instance {
+    class: ObjRef,
+    fields: HashMap<Rc<str>, Value>,
+}
+
+struct BoundMethod {
+    receiver: Value,
+    method: ObjRef,
+}
+
+/// Number of live objects triggering the first collection.
+const INITIAL_COLLECTION_THRESHOLD: usize = 1024;
+
+/// After a collection, the next one happens when the heap has grown by this factor.
+const HEAP_GROW_FACTOR: usize = 2;
+
+/// Owns every object created by the program, they are referred to by their index.
+///
+/// Objects can refer to each other in cycles, so they are freed by a mark-and-sweep
+/// collector: the ones not reachable from the roots of the [`Vm`] are dropped and their
+/// slot is reused.
+struct Heap {
+    objects: Vec<Option<Object>>,
+    marks: Vec<bool>,
+    /// Slots of the freed objects.
+    free: Vec<usize>,
+    next_collection: usize,
+    /// Collect before every allocation.
+    stress: bool,
+    /// Report every collection on stderr.
+    log: bool,
+    stats: HeapStats,
+}
+
+impl Default for Heap {
+    fn default() -> Self {
+        Self {
+            objects: Vec::new(),
+            marks: Vec::new(),
+            free: Vec::new(),
+            next_collection: INITIAL_COLLECTION_THRESHOLD,
+            stress: false,
+            log: false,
+            stats: HeapStats::default(),
+        }
+    }
+}
+
+impl Heap {
+    fn should_collect(&self) -> bool {
+        self.stress || self.stats.live_objects >= self.next_collection
+    }
+
+    fn alloc(&mut self, object: Object) -> ObjRef {
+        self.stats.live_objects += 1;
+        self.stats.allocated_objects += 1;
+        match self.free.pop() {
+            Some(index) => {
+                self.objects[index] = Some(object);
+                ObjRef(index)
+            }
+            None => {
+                self.objects.push(Some(object));
+                self.marks.push(false);
+                ObjRef(self.objects.len() - 1)
+            }
+        }
+    }
+
+    fn get(&self, object: ObjRef) -> &Object {
+        self.objects[object.0]
+            .as_ref()
+            .expect("reachable objects are not freed")
+    }
+
+    fn get_mut(&mut self, object: ObjRef) -> &mut Object {
+        self.objects[object.0]
+            .as_mut()
+            .expect("reachable objects are not freed")
+    }
+
+    fn closure(&self, object: ObjRef) -> &Closure {
+        match self.get(object) {
+            Object::Closure(closure) => closure,
+            _ => unreachable!("the compiler only produces closures there"),
+        }
+    }
+
+    fn class(&self, object: ObjRef) -> &Class {
+        match self.get(object) {
+            Object::Class(class) => class,
+            _ => unreachable!("the compiler only produces classes there"),
+        }
+    }
+
+    /// Free every object not reachable from `roots`.
+    fn collect(&mut self, roots: Vec<ObjRef>) {
+        let before = self.stats.live_objects;
+        if self.log {
+            eprintln!("-- gc begin ({before} objects)");
+        }
+
+        // Mark: `gray` holds the objects reached but whose references are not traced yet.
+        let mut gray = roots;
+        while let Some(object) = gray.pop() {
+            if std::mem::replace(&mut self.marks[object.0], true) {
+                continue;
+            }
+            self.get(object).references(&mut gray);
+        }
+
+        // Sweep.
+        let mut freed = 0;
+        for (index, (object, mark)) in self.objects.iter_mut().zip(&mut self.marks).enumerate() {
+            if std::mem::take(mark) {
+                continue;
+            }
+            if let Some(garbage) = object.take() {
+                if self.log {
+                    eprintln!("free {index} {}", garbage.kind());
+                }
+                self.free.push(index);
+                freed += 1;
+            }
+        }
+
+        self.stats.live_objects -= freed;
+        self.stats.freed_objects += freed;
+        self.stats.collections += 1;
+        self.next_collection =
+            (self.stats.live_objects * HEAP_GROW_FACTOR).max(INITIAL_COLLECTION_THRESHOLD);
+        self.stats.next_collection = self.next_collection;
+        if self.log {
+            eprintln!(
+                "-- gc end: freed {freed} objects ({before} -> {}), next at {}",
+                self.stats.live_objects, self.next_collection
+            );
+        }
+    }
+}
+
+struct CallFrame {
+    closure: ObjRef,
+    function: Rc<Function>,
+    /// Offset of the next instruction to execute.
+    ip: usize,
+    /// Index of the first slot of the frame in the stack.
+    slots: usize,
+}
+
+/// Stack-based virtual machine executing the compiled programs.
+///
+/// Like the tree-walking [`Interpreter`](crate::Interpreter), it keeps its global
+/// variables between runs, and prints to `W`.
+pub struct Vm<W = io::Stdout> {
+    stack: Vec<Value>,
+    frames: Vec<CallFrame>,
+    globals: HashMap<Rc<str>, Value>,
+    /// The upvalues still pointing to the stack, sorted by slot.
+    open_upvalues: Vec<ObjRef>,
+    heap: Heap,
+    output: W,
+}
+
+impl Default for Vm {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Vm {
+    pub fn new() -> Self {
+        Self::with_output(io::stdout())
+    }
+}
+
+impl<W: Write> Vm<W> {
+    pub fn with_output(output: W) -> Self {
+        let mut vm = Self {
+            stack: Vec::new(),
+            frames: Vec::new(),
+            globals: HashMap::new(),
+            open_upvalues: Vec::new(),
+            heap: Heap::default(),
+            output,
+        };
+        for &(name, arity, function) in native::BUILTINS {
+            vm.define_native(name, arity, function);
+        }
+        vm
+    }
+
+    /// Define a global function implemented in Rust, like
+    /// [`Interpreter::define_native`](crate::Interpreter::define_native): the same
+    /// function can be given to both backends, see [`NativeFunction`] for the values
+    /// it can receive.
+    ///
+    /// ```
+    /// use lox::{vm::Vm, Value};
+    ///
+    /// let mut vm = Vm::with_output(Vec::new());
+    /// vm.define_native("double", 1, |arguments| {
+    ///     let number = f64::try_from(&arguments[0])?;
+    ///     Ok(Value::from(number * 2.0))
+    /// });
+    /// vm.run_source("print double(21);").unwrap();
+    /// assert_eq!(vm.into_output(), b"42\n");
+    /// ```
+    pub fn define_native(
+        &mut self,
+        name: &str,
+        arity: usize,
+        function: impl Fn(&[interpret::Value]) -> Result<interpret::Value, Box<dyn Error>> + 'static,
+    ) {
+        let function = Rc::new(NativeFunction::new(name, arity, function));
+        let name = Rc::clone(&function.name);
+        let function = self.alloc(Object::Native(function));
+        self.globals.insert(name, Value::Object(function));
+    }
+
+    pub fn output(&self) -> &W {
+        &self.output
+    }
+
+    /// Collect the garbage before every allocation, to find the objects that are freed
+    /// while still in use.
+    pub fn set_gc_stress(&mut self, enabled: bool) {
+        self.heap.stress = enabled;
+    }
+
+    /// Report on stderr every collection and every object freed.
+    pub fn set_gc_log(&mut self, enabled: bool) {
+        self.heap.log = enabled;
+    }
+
+    /// ```
+    /// let mut vm = lox::vm::Vm::with_output(Vec::new());
+    /// vm.set_gc_stress(true);
+    /// vm.run_source("class A {} for (var i = 0; i < 10; i = i + 1) A();").unwrap();
+    /// assert!(vm.heap_stats().freed_objects > 0);
+    /// ```
+    pub fn heap_stats(&self) -> HeapStats {
+        self.heap.stats
+    }
+
+    pub fn into_output(self) -> W {
+        self.output
+    }
+
+    /// Compile and run a whole program, see [`Interpreter::run_source`](crate::Interpreter::run_source).
+    pub fn run_source(&mut self, source: &str) -> Result<(), RunError> {
+        let function = compile_source(source)?;
+        self.interpret(function).map_err(RunError::Evaluation)
+    }
+
+    /// Execute the top-level function of a program.
+    pub fn interpret(&mut self, function: Rc<Function>) -> Result<(), EvaluationError> {
+        let closure = self.alloc(Object::Closure(Closure {
+            function,
+            upvalues: Vec::new(),
+        }));
+        self.stack.push(Value::Object(closure));
+        let result = self
+            .call_closure(closure, 0, Span::default())
+            .and_then(|()| self.run());
+        if result.is_err() {
+            // Leave the machine ready for the next program.
+            self.stack.clear();
+            self.frames.clear();
+            self.open_upvalues.clear();
+        }
+        result
+    }
+
+    fn run(&mut self) -> Result<(), EvaluationError> {
+        let mut frame = self.frames.last().expect("a function is being called");
+        let mut function = Rc::clone(&frame.function);
+        let mut ip = frame.ip;
+        let mut slots = frame.slots;
+
+        loop {
+            let start = ip;
+            let error = |kind| EvaluationError::new(kind, function.chunk.span_at(start));
+            let code = &function.chunk.code;
+            let op = OpCode::try_from(code[ip]).expect("the compiler only emits valid opcodes");
+            ip += 1;
+            match op {
+                OpCode::Constant => {
+                    let index = function.chunk.read_u16(ip);
+                    ip += 2;
+                    let value = match &function.chunk.constants[usize::from(index)] {
+                        Constant::Number(number) => Value::Number(*number),
+                        Constant::String(string) => Value::String(Rc::clone(string)),
+                        Constant::Function(_) => unreachable!("functions are loaded as closures"),
+                    };
+                    self.stack.push(value);
+                }
+                OpCode::Nil => self.stack.push(Value::Nil),
+                OpCode::True => self.stack.push(Value::Boolean(true)),
+                OpCode::False => self.stack.push(Value::Boolean(false)),
+                OpCode::Pop => {
+                    self.pop();
+                }
+                OpCode::GetLocal => {
+                    let slot = usize::from(code[ip]);
+                    ip += 1;
+                    self.stack.push(self.stack[slots + slot].clone());
+                }
+                OpCode::SetLocal => {
+                    let slot = usize::from(code[ip]);
+                    ip += 1;
+                    // Assignment is an expression, the value stays on the stack.
+                    self.stack[slots + slot] = self.peek(0).clone();
+                }
+                OpCode::GetGlobal => {
+                    let name = self.name_at(&function, ip);
+                    ip += 2;
+                    let Some(value) = self.globals.get(&name) else {
+                        return Err(error(EvaluationErrorKind::UndefinedVariable(name)));
+                    };
+                    self.stack.push(value.clone());
+                }
+                OpCode::DefineGlobal => {
+                    let name = self.name_at(&function, ip);
+                    ip += 2;
+                    let value = self.pop();
+                    self.globals.insert(name, value);
+                }
+                OpCode::SetGlobal => {
+                    let name = self.name_at(&function, ip);
+                    ip += 2;
+                    let value = self.peek(0).clone();
+                    let Some(global) = self.globals.get_mut(&name) else {
+                        return Err(error(EvaluationErrorKind::UndeclaredVariable(name)));
+                    };
+                    *global = value;
+                }
+                OpCode::GetUpvalue => {
+                    let index = usize::from(code[ip]);
+                    ip += 1;
+                    let upvalue = self.upvalue(index);
+                    let value = match self.heap.get(upvalue) {
+                        Object::Upvalue(Upvalue::Open(slot)) => self.stack[*slot].clone(),
+                        Object::Upvalue(Upvalue::Closed(value)) => value.clone(),
+                        _ => unreachable!("closures only capture upvalues"),
+                    };
+                    self.stack.push(value);
+                }
+                OpCode::SetUpvalue => {
+                    let index = usize::from(code[ip]);
+                    ip += 1;
+                    let upvalue = self.upvalue(index);
+                    let value = self.peek(0).clone();
+                    match self.heap.get_mut(upvalue) {
+                        Object::Upvalue(Upvalue::Open(slot)) => {
+                            let slot = *slot;
+                            self.stack[slot] = value;
+                        }
+                        Object::Upvalue(Upvalue::Closed(closed)) => *closed = value,
+                        _ => unreachable!("closures only capture upvalues"),
+                    }
+                }
+                OpCode::GetProperty => {
+                    let name = self.name_at(&function, ip);
+                    ip += 2;
+                    let Value::Object(object) = self.peek(0).clone() else {
+                        return Err(error(EvaluationErrorKind::NotAnInstance));
+                    };
+                    let Object::Instance(instance) = self.heap.get(object) else {
+                        return Err(error(EvaluationErrorKind::NotAnInstance));
+                    };
+                    // Fields shadow methods.
+                    match instance.fields.get(&name) {
+                        Some(value) => {
+                            let value = value.clone();
+                            self.pop();
+                            self.stack.push(value);
+                        }
+                        None => {
+                            let class = instance.class;
+                            self.bind_method(class, name).map_err(|kind| {
+                                EvaluationError::new(kind, function.chunk.span_at(start))
+                            })?;
+                        }
+                    }
+                }
+                OpCode::SetProperty => {
+                    let name = self.name_at(&function, ip);
+                    ip += 2;
+                    let value = self.pop();
+                    let Value::Object(object) = self.pop() else {
+                        return Err(error(EvaluationErrorKind::NotAnInstanceField));
+                    };
+                    let Object::Instance(instance) = self.heap.get_mut(object) else {
+                        return Err(error(EvaluationErrorKind::NotAnInstanceField));
+                    };
+                    instance.fields.insert(name, value.clone());
+                    self.stack.push(value);
+                }
+                OpCode::GetSuper => {
+                    let name = self.name_at(&function, ip);
+                    ip += 2;
+                    let Value::Object(superclass) = self.pop() else {
+                        unreachable!("`super` is always a class");
+                    };
+                    self.bind_method(superclass, name).map_err(|kind| {
+                        EvaluationError::new(kind, function.chunk.span_at(start))
+                    })?;
+                }
+                OpCode::Equal => {
+                    let rhs = self.pop();
+                    let lhs = self.pop();
+                    self.stack.push(Value::Boolean(lhs == rhs));
+                }
+                OpCode::Greater => {
+                    let (lhs, rhs) = self.pop_numbers().map_err(error)?;
+                    self.stack.push(Value::Boolean(lhs > rhs));
+                }
+                OpCode::GreaterEqual => {
+                    let (lhs, rhs) = self.pop_numbers().map_err(error)?;
+                    self.stack.push(Value::Boolean(lhs >= rhs));
+                }
+                OpCode::Less => {
+                    let (lhs, rhs) = self.pop_numbers().map_err(error)?;
+                    self.stack.push(Value::Boolean(lhs < rhs));
+                }
+                OpCode::LessEqual => {
+                    let (lhs, rhs) = self.pop_numbers().map_err(error)?;
+                    self.stack.push(Value::Boolean(lhs <= rhs));
+                }
+                OpCode::Add => {
+                    let rhs = self.pop();
+                    let lhs = self.pop();
+                    let value = match (lhs, rhs) {
+                        (Value::Number(lhs), Value::Number(rhs)) => Value::Number(lhs + rhs),
+                        (Value::String(lhs), Value::String(rhs)) => {
+                            Value::String(format!("{lhs}{rhs}").into())
+                        }
+                        _ => return Err(error(EvaluationErrorKind::WrongPlusOperands)),
+                    };
+                    self.stack.push(value);
+                }
+                OpCode::Subtract => {
+                    let (lhs, rhs) = self.pop_numbers().map_err(error)?;
+                    self.stack.push(Value::Number(lhs - rhs));
+                }
+                OpCode::Multiply => {
+                    let (lhs, rhs) = self.pop_numbers().map_err(error)?;
+                    self.stack.push(Value::Number(lhs * rhs));
+                }
+                OpCode::Divide => {
+                    let (lhs, rhs) = self.pop_numbers().map_err(error)?;
+                    self.stack.push(Value::Number(lhs / rhs));
+                }
+                OpCode::Not => {
+                    let value = self.pop();
+                    self.stack.push(Value::Boolean(!value.is_truthy()));
+                }
+                OpCode::Negate => {
+                    let Value::Number(number) = self.pop() else {
+                        return Err(error(EvaluationErrorKind::ExpectedNumber));
+                    };
+                    self.stack.push(Value::Number(-number));
+                }
+                OpCode::Print => {
+                    let value = self.pop();
+                    let displayed = self.display(&value).to_string();
+                    writeln!(self.output, "{displayed}")
+                        .map_err(|err| error(EvaluationErrorKind::Output(err)))?;
+                }
+                OpCode::Jump => {
+                    let offset = function.chunk.read_u16(ip);
+                    ip += 2 + usize::from(offset);
+                }
+                OpCode::JumpIfFalse => {
+                    let offset = function.chunk.read_u16(ip);
+                    ip += 2;
+                    if !self.peek(0).is_truthy() {
+                        ip += usize::from(offset);
+                    }
+                }
+                OpCode::Loop => {
+                    let offset = function.chunk.read_u16(ip);
+                    ip = ip + 2 - usize::from(offset);
+                }
+                OpCode::Call => {
+                    let count = usize::from(code[ip]);
+                    ip += 1;
+                    self.frames
+                        .last_mut()
+                        .expect("a function is being called")
+                        .ip = ip;
+                    let callee = self.peek(count).clone();
+                    self.call_value(callee, count, function.chunk.span_at(start))?;
+                    frame = self.frames.last().expect("a function is being called");
+                    function = Rc::clone(&frame.function);
+                    ip = frame.ip;
+                    slots = frame.slots;
+                }
+                OpCode::Closure => {
+                    let index = function.chunk.read_u16(ip);
+                    ip += 2;
+                    let Constant::Function(compiled) =
+                        &function.chunk.constants[usize::from(index)]
+                    else {
+                        unreachable!("closures are only created from functions");
+                    };
+                    let mut upvalues = Vec::with_capacity(compiled.upvalue_count);
+                    for _ in 0..compiled.upvalue_count {
+                        let is_local = code[ip] == 1;
+                        let index = usize::from(code[ip + 1]);
+                        ip += 2;
+                        let upvalue = if is_local {
+                            self.capture_upvalue(slots + index)
+                        } else {
+                            self.upvalue(index)
+                        };
+                        upvalues.push(upvalue);
+                    }
+                    let closure = self.alloc(Object::Closure(Closure {
+                        function: Rc::clone(compiled),
+                        upvalues,
+                    }));
+                    self.stack.push(Value::Object(closure));
+                }
+                OpCode::CloseUpvalue => {
+                    self.close_upvalues(self.stack.len() - 1);
+                    self.pop();
+                }
+                OpCode::Return => {
+                    let result = self.pop();
+                    self.close_upvalues(slots);
+                    self.frames.pop();
+                    self.stack.truncate(slots);
+                    let Some(caller) = self.frames.last() else {
+                        return Ok(());
+                    };
+                    self.stack.push(result);
+                    frame = caller;
+                    function = Rc::clone(&frame.function);
+                    ip = frame.ip;
+                    slots = frame.slots;
+                }
+                OpCode::Class => {
+                    let name = self.name_at(&function, ip);
+                    ip += 2;
+                    let class = self.alloc(Object::Class(Class {
+                        name,
+                        methods: HashMap::new(),
+                    }));
+                    self.stack.push(Value::Object(class));
+                }
+                OpCode::Inherit => {
+                    let superclass = match self.peek(1) {
+                        Value::Object(object)
+                            if matches!(self.heap.get(*object), Object::Class(_)) =>
+                        {
+                            *object
+                        }
+                        _ => return Err(error(EvaluationErrorKind::SuperclassNotAClass)),
+                    };
+                    let Value::Object(subclass) = self.pop() else {
+                        unreachable!("the class is right above its superclass");
+                    };
+                    let methods = self.heap.class(superclass).methods.clone();
+                    if let Object::Class(subclass) = self.heap.get_mut(subclass) {
+                        subclass.methods.extend(methods);
+                    }
+                }
+                OpCode::Method => {
+                    let name = self.name_at(&function, ip);
+                    ip += 2;
+                    let Value::Object(method) = self.pop() else {
+                        unreachable!("methods are closures");
+                    };
+                    let Value::Object(class) = self.peek(0) else {
+                        unreachable!("methods are defined right above their class");
+                    };
+                    if let Object::Class(class) = self.heap.get_mut(*class) {
+                        class.methods.insert(name, method);
+                    }
+                }
+            }
+        }
+    }
+
+    fn pop(&mut self) -> Value {
+        self.stack
+            .pop()
+            .expect("the compiler keeps the stack balanced")
+    }
+
+    fn peek(&self, distance: usize) -> &Value {
+        &self.stack[self.stack.len() - 1 - distance]
+    }
+
+    fn pop_numbers(&mut self) -> Result<(f64, f64), EvaluationErrorKind> {
+        match (self.peek(1), self.peek(0)) {
+            (Value::Number(lhs), Value::Number(rhs)) => {
+                let operands = (*lhs, *rhs);
+                self.stack.truncate(self.stack.len() - 2);
+                Ok(operands)
+            }
+            _ => Err(EvaluationErrorKind::ExpectedNumbers),
+        }
+    }
+
+    /// The name stored in the constants at the index read at `ip`.
+    fn name_at(&self, function: &Function, ip: usize) -> Rc<str> {
+        let index = function.chunk.read_u16(ip);
+        match &function.chunk.constants[usize::from(index)] {
+            Constant::String(name) => Rc::clone(name),
+            _ => unreachable!("names are stored as strings"),
+        }
+    }
+
+    /// The upvalue at `index` in the closure being executed.
+    fn upvalue(&self, index: usize) -> ObjRef {
+        let frame = self.frames.last().expect("a function is being called");
+        self.heap.closure(frame.closure).upvalues[index]
+    }
+
+    fn call_value(
+        &mut self,
+        callee: Value,
+        count: usize,
+        span: Span,
+    ) -> Result<(), EvaluationError> {
+        let error = |kind| EvaluationError::new(kind, span);
+        let Value::Object(object) = callee else {
+            return Err(error(EvaluationErrorKind::NotCallable));
+        };
+        match self.heap.get(object) {
+            Object::Closure(_) => self.call_closure(object, count, span),
+            Object::Class(class) => {
+                let initializer = class.methods.get("init").copied();
+                let instance = self.alloc(Object::Instance(Instance {
+                    class: object,
+                    fields: HashMap::new(),
+                }));
+                let callee_slot = self.stack.len() - 1 - count;
+                self.stack[callee_slot] = Value::Object(instance);
+                match initializer {
+                    Some(initializer) => self.call_closure(initializer, count, span),
+                    None if count != 0 => Err(error(EvaluationErrorKind::WrongArity {
+                        expected: 0,
+                        got: count,
+                    })),
+                    None => Ok(()),
+                }
+            }
+            Object::BoundMethod(bound) => {
+                let method = bound.method;
+                let callee_slot = self.stack.len() - 1 - count;
+                self.stack[callee_slot] = bound.receiver.clone();
+                self.call_closure(method, count, span)
+            }
+            Object::Native(native) => {
+                let native = Rc::clone(native);
+                if native.arity != count {
+                    return Err(error(EvaluationErrorKind::WrongArity {
+                        expected: native.arity,
+                        got: count,
+                    }));
+                }
+                let arguments_start = self.stack.len() - count;
+                let arguments = self.stack[arguments_start..]
+                    .iter()
+                    .map(|argument| self.native_argument(argument))
+                    .collect::<Result<Vec<_>, _>>()
+                    .map_err(|err| native.failure(&err, span))?;
+                let result = native.call(&arguments, span)?;
+                let result = self
+                    .native_result(result)
+                    .map_err(|err| native.failure(&err, span))?;
+                self.stack.truncate(arguments_start - 1);
+                self.stack.push(result);
+                Ok(())
+            }
+            Object::Upvalue(_) | Object::Instance(_) => {
+                Err(error(EvaluationErrorKind::NotCallable))
+            }
+        }
+    }
+
+    fn call_closure(
+        &mut self,
+        closure: ObjRef,
+        count: usize,
+        span: Span,
+    ) -> Result<(), EvaluationError> {
+        let function = Rc::clone(&self.heap.closure(closure).function);
+        if function.arity != count {
+            return Err(EvaluationError::new(
+                EvaluationErrorKind::WrongArity {
+                    expected: function.arity,
+                    got: count,
+                },
+                span,
+            ));
+        }
+        if self.frames.len() == MAX_CALL_DEPTH {
+            return Err(EvaluationError::new(
+                EvaluationErrorKind::StackOverflow,
+                span,
+            ));
+        }
+        self.frames.push(CallFrame {
+            closure,
+            function,
+            ip: 0,
+            slots: self.stack.len() - 1 - count,
+        });
+        Ok(())
+    }
+
+    /// Replace the instance on top of the stack by its method bound to it.
+    fn bind_method(&mut self, class: ObjRef, name: Rc<str>) -> Result<(), EvaluationErrorKind> {
+        let Some(method) = self.heap.class(class).methods.get(&name).copied() else {
+            return Err(EvaluationErrorKind::UndefinedProperty(name));
+        };
+        let receiver = self.pop();
+        let bound = self.alloc(Object::BoundMethod(BoundMethod { receiver, method }));
+        self.stack.push(Value::Object(bound));
+        Ok(())
+    }
+
+    /// Allocate an object, collecting the garbage first if the heap has grown enough.
+    fn alloc(&mut self, object: Object) -> ObjRef {
+        if self.heap.should_collect() {
+            // The references of the new object may only be held by Rust locals.
+            let mut roots = Vec::new();
+            object.references(&mut roots);
+            roots.extend(self.stack.iter().filter_map(Value::as_object));
+            roots.extend(self.globals.values().filter_map(Value::as_object));
+            roots.extend(self.frames.iter().map(|frame| frame.closure));
+            roots.extend(&self.open_upvalues);
+            self.heap.collect(roots);
+        }
+        self.heap.alloc(object)
+    }
+
+    /// Reuse the upvalue of the slot if another closure already captured it.
+    fn capture_upvalue(&mut self, slot: usize) -> ObjRef {
+        let position = self.open_upvalues.partition_point(|upvalue| {
+            matches!(self.heap.get(*upvalue), Object::Upvalue(Upvalue::Open(other)) if *other < slot)
+        });
+        if let Some(upvalue) = self.open_upvalues.get(position) {
+            if matches!(self.heap.get(*upvalue), Object::Upvalue(Upvalue::Open(other)) if *other == slot)
+            {
+                return *upvalue;
+            }
+        }
+        let upvalue = self.alloc(Object::Upvalue(Upvalue::Open(slot)));
+        self.open_upvalues.insert(position, upvalue);
+        upvalue
+    }
+
+    /// Move the variables from `last` to the top of the stack into their upvalues.
+    fn close_upvalues(&mut self, last: usize) {
+        while let Some(upvalue) = self.open_upvalues.last().copied() {
+            let Object::Upvalue(upvalue) = self.heap.get_mut(upvalue) else {
+                unreachable!("only upvalues are open");
+            };
+            let Upvalue::Open(slot) = *upvalue else {
+                unreachable!("closed upvalues are removed from the list");
+            };
+            if slot < last {
+                break;
+            }
+            *upvalue = Upvalue::Closed(self.stack[slot].clone());
+            self.open_upvalues.pop();
+        }
+    }
+
+    /// Convert an argument of a native function. Besides the native functions, the
+    /// objects only exist in the heap of the machine, so they can't be passed.
+    fn native_argument(&self, value: &Value) -> Result<interpret::Value, ConversionError> {
+        Ok(match value {
+            Value::Nil => interpret::Value::Nil,
+            Value::Boolean(boolean) => interpret::Value::Boolean(*boolean),
+            Value::Number(number) => interpret::Value::Number(*number),
+            Value::String(string) => interpret::Value::String(Rc::clone(string)),
+            Value::Object(object) => match self.heap.get(*object) {
+                Object::Native(native) => interpret::Value::NativeFunction(Rc::clone(native)),
+                object => return Err(ConversionError::of_type(NATIVE_VALUES, object.type_name())),
+            },
+        })
+    }
+
+    /// Convert the value returned by a native function, see [`Vm::native_argument`].
+    fn native_result(&mut self, value: interpret::Value) -> Result<Value, ConversionError> {
+        Ok(match value {
+            interpret::Value::Nil => Value::Nil,
+            interpret::Value::Boolean(boolean) => Value::Boolean(boolean),
+            interpret::Value::Number(number) => Value::Number(number),
+            interpret::Value::String(string) => Value::String(string),
+            interpret::Value::NativeFunction(native) => {
+                Value::Object(self.alloc(Object::Native(native)))
+            }
+            value => return Err(ConversionError::of_type(NATIVE_VALUES, value.type_name())),
+        })
+    }
+
+    fn display<'a>(&'a self, value: &'a Value) -> Display<'a> {
+        Display {
+            heap: &self.heap,
+            value,
+        }
+    }
+}
+
+/// Formats a value like the tree-walking interpreter does.
+struct Display<'a> {
+    heap: &'a Heap,
+    value: &'a Value,
+}
+
+impl fmt::Display for Display<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.value {
+            Value::Nil => write!(f, "nil"),
+            Value::Boolean(boolean) => write!(f, "{boolean}"),
+            Value::Number(number) => write!(f, "{number}"),
+            Value::String(string) => write!(f, "{string}"),
+            Value::Object(object) => match self.heap.get(*object) {
+                Object::Closure(closure) => write!(f, "<fn {}>", closure.function.name),
+                Object::BoundMethod(bound) => {
+                    write!(f, "<fn {}>", self.heap.closure(bound.method).function.name)
+                }
+                Object::Native(_) => write!(f, "<native fn>"),
+                Object::Class(class) => write!(f, "{}", class.name),
+                Object::Instance(instance) => {
+                    write!(f, "{} instance", self.heap.class(instance.class).name)
+                }
+                Object::Upvalue(_) => write!(f, "upvalue"),
+            },
+        }
+    }
+}
+
+/// The values the native functions can exchange with the machine.
+const NATIVE_VALUES: &str = "primitive value or native function";
@@ -0,0 +1,128 @@
+//! Checks the encoding of the bytecode and its listing.
+
+use std::rc::Rc;
+
+use lox::{
+    chunk::{Chunk, Constant, Function, OpCode},
+    compile::compile_source,
+};
+
+#[test]
+fn opcodes_round_trip() {
+    let opcodes: Vec<OpCode> = (0..=u8::MAX)
+        .filter_map(|byte| OpCode::try_from(byte).ok())
+        .collect();
+    assert_eq!(opcodes.len(), usize::from(OpCode::Method as u8) + 1);
+    for op in opcodes {
+        assert_eq!(OpCode::try_from(op as u8), Ok(op), "{}", op.name());
+    }
+}
+
+#[test]
+fn constants_are_reused() {
+    let mut chunk = Chunk::default();
+    let one = chunk.add_constant(Constant::Number(1.0));
+    let name = chunk.add_constant(Constant::String("name".into()));
+    assert_eq!(chunk.add_constant(Constant::Number(1.0)), one);
+    assert_eq!(chunk.add_constant(Constant::String("name".into())), name);
+    // `0` and `-0` are different constants.
+    assert_ne!(
+        chunk.add_constant(Constant::Number(0.0)),
+        chunk.add_constant(Constant::Number(-0.0))
+    );
+    // Functions are never shared.
+    let function = Rc::new(Function::default());
+    assert_ne!(
+        chunk.add_constant(Constant::Function(Rc::clone(&function))),
+        chunk.add_constant(Constant::Function(function))
+    );
+    assert_eq!(chunk.constants.len(), 6);
+}
+
+const PROGRAM: &str = r#"fun counter() {
+  var count = 0;
+  fun increment() {
+    count = count + 1;
+    return count;
+  }
+  return increment;
+}
+
+class Point {
+  init(x) {
+    this.x = x;
+  }
+}
+
+for (var i = 0; i < 2; i = i + 1) {
+  print Point(i).x;
+}
+"#;
+
+/// The listing of [`PROGRAM`], which has a closure, a class and a loop.
+const DISASSEMBLY: &str = "\
+== <script> ==
+0000    1 OP_CLOSURE          1 <fn counter>
+0003    | OP_DEFINE_GLOBAL    0 'counter'
+0006   10 OP_CLASS            2 'Point'
+0009    | OP_DEFINE_GLOBAL    2 'Point'
+0012    | OP_GET_GLOBAL       2 'Point'
+0015   11 OP_CLOSURE          4 <fn init>
+0018    | OP_METHOD           3 'init'
+0021   10 OP_POP
+0022   16 OP_CONSTANT         5 '0'
+0025    | OP_GET_LOCAL        1
+0027    | OP_CONSTANT         6 '2'
+0030    | OP_LESS
+0031    | OP_JUMP_IF_FALSE   31 -> 58
+0034    | OP_POP
+0035   17 OP_GET_GLOBAL       2 'Point'
+0038    | OP_GET_LOCAL        1
+0040    | OP_CALL             1
+0042    | OP_GET_PROPERTY     7 'x'
+0045    | OP_PRINT
+0046   16 OP_GET_LOCAL        1
+0048    | OP_CONSTANT         8 '1'
+0051    | OP_ADD
+0052    | OP_SET_LOCAL        1
+0054    | OP_POP
+0055    | OP_LOOP            55 -> 25
+0058    | OP_POP
+0059    | OP_POP
+0060    | OP_NIL
+0061    | OP_RETURN
+
+== counter ==
+0000    2 OP_CONSTANT         0 '0'
+0003    3 OP_CLOSURE          1 <fn increment>
+0006    |                     local 1
+0008    7 OP_GET_LOCAL        2
+0010    | OP_RETURN
+0011    1 OP_NIL
+0012    | OP_RETURN
+
+== increment ==
+0000    4 OP_GET_UPVALUE      0
+0002    | OP_CONSTANT         0 '1'
+0005    | OP_ADD
+0006    | OP_SET_UPVALUE      0
+0008    | OP_POP
+0009    5 OP_GET_UPVALUE      0
+0011    | OP_RETURN
+0012    3 OP_NIL
+0013    | OP_RETURN
+
+== init ==
+0000   12 OP_GET_LOCAL        0
+0002    | OP_GET_LOCAL        1
+0004    | OP_SET_PROPERTY     0 'x'
+0007    | OP_POP
+0008   11 OP_GET_LOCAL        0
+0010    | OP_RETURN
+";
+
+#[test]
+fn disassembly() {
+    let function = compile_source(PROGRAM).unwrap();
+    assert_eq!(function.disassemble(), DISASSEMBLY);
+}
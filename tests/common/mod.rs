@@ -0,0 +1,29 @@
+//! Helpers shared by the integration tests.
+
+use std::{
+    fs,
+    path::{Path, PathBuf},
+};
+
+/// Every `.lox` file of the `tests` directory, sorted by path.
+pub fn lox_files() -> Vec<PathBuf> {
+    let mut files = Vec::new();
+    collect_lox_files(
+        Path::new(env!("CARGO_MANIFEST_DIR")).join("tests"),
+        &mut files,
+    );
+    files.sort();
+    assert!(!files.is_empty(), "no test found");
+    files
+}
+
+fn collect_lox_files(dir: PathBuf, files: &mut Vec<PathBuf>) {
+    for entry in fs::read_dir(&dir).unwrap_or_else(|err| panic!("{}: {err}", dir.display())) {
+        let path = entry.expect("readable directory entry").path();
+        if path.is_dir() {
+            collect_lox_files(path, files);
+        } else if path.extension().is_some_and(|extension| extension == "lox") {
+            files.push(path);
+        }
+    }
+}
@@ -0,0 +1,53 @@
+//! Parses every `.lox` file of the `tests` directory into a concrete syntax tree, and
+//! checks that it gives back the source, and the same syntax trees and errors as the
+//! parser used by the interpreters.
+
+mod common;
+
+use std::fs;
+
+use lox::{cst, parse_statements, Lexer, RunError};
+
+#[test]
+fn lossless_and_equivalent() {
+    for file in common::lox_files() {
+        let source = fs::read_to_string(&file).expect("readable test");
+        let parse = cst::parse(&source);
+        assert_eq!(
+            parse.syntax().to_string(),
+            source,
+            "{}: the tree doesn't give back the source",
+            file.display()
+        );
+
+        let mut lexing = Vec::new();
+        let tokens: Vec<_> = Lexer::new(&source)
+            .filter_map(|token| token.map_err(|err| lexing.push(err.to_string())).ok())
+            .collect();
+        let expected = parse_statements(&mut tokens.into_iter().peekable());
+        match (parse.statements(), expected) {
+            (Ok(statements), Ok(expected)) if lexing.is_empty() => {
+                assert_eq!(statements, expected, "{}", file.display())
+            }
+            (
+                Err(RunError::Syntax {
+                    lexing: got,
+                    parsing,
+                }),
+                expected,
+            ) => {
+                let got: Vec<_> = got.iter().map(ToString::to_string).collect();
+                assert_eq!(got, lexing, "{}: lexing errors", file.display());
+                let parsing: Vec<_> = parsing.iter().map(ToString::to_string).collect();
+                let expected: Vec<_> = expected
+                    .err()
+                    .unwrap_or_default()
+                    .iter()
+                    .map(ToString::to_string)
+                    .collect();
+                assert_eq!(parsing, expected, "{}: parsing errors", file.display());
+            }
+            (got, _) => panic!("{}: unexpected result {got:?}", file.display()),
+        }
+    }
+}
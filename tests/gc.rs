@@ -0,0 +1,95 @@
+//! Runs the garbage collectors of both backends.
+
+use std::rc::Rc;
+
+use lox::{vm::Vm, Interpreter, Value};
+
+/// Closures, instances holding themselves and bound methods, which keep the
+/// collectors busy.
+const PROGRAM: &str = r#"
+class Node {
+  init(value) { this.value = value; this.me = this; }
+  get() { return this.value; }
+}
+class Double < Node {
+  get() { return super.get() * 2; }
+}
+fun counter() {
+  var n = 0;
+  fun increment() { n = n + 1; return n; }
+  return increment;
+}
+var total = 0;
+for (var i = 0; i < 100; i = i + 1) {
+  var node = Double(i);
+  node.get = node.get;
+  var count = counter();
+  count();
+  total = total + node.get() + count();
+}
+print total;
+"#;
+
+const OUTPUT: &str = "10100\n";
+
+#[test]
+fn tree_backend_under_stress() {
+    let mut interpreter = Interpreter::with_output(Vec::new());
+    interpreter.set_gc_stress(true);
+    interpreter.run_source(PROGRAM).unwrap();
+    assert_eq!(String::from_utf8_lossy(interpreter.output()), OUTPUT);
+    let stats = interpreter.heap_stats();
+    assert!(stats.freed_objects >= 100, "{stats:?}");
+    assert!(stats.live_objects < 20, "{stats:?}");
+}
+
+#[test]
+fn vm_backend_under_stress() {
+    let mut vm = Vm::with_output(Vec::new());
+    vm.set_gc_stress(true);
+    vm.run_source(PROGRAM).unwrap();
+    assert_eq!(String::from_utf8_lossy(vm.output()), OUTPUT);
+    let stats = vm.heap_stats();
+    assert!(stats.freed_objects >= 100, "{stats:?}");
+}
+
+#[test]
+fn tree_backend_frees_its_globals_when_dropped() {
+    let mut interpreter = Interpreter::with_output(Vec::new());
+    // The function refers to the globals, which refer to it.
+    interpreter
+        .run_source("class A {} var a = A(); fun f() { return a; } a.f = f;")
+        .unwrap();
+    let globals = interpreter.globals();
+    let Some((_, Value::Function(function))) = globals.iter().find(|(name, _)| &**name == "f")
+    else {
+        panic!("f is a function");
+    };
+    let function = Rc::downgrade(function);
+    drop(globals);
+    assert!(function.upgrade().is_some());
+    drop(interpreter);
+    assert!(function.upgrade().is_none());
+}
+
+#[test]
+fn tree_backend_keeps_the_values_of_the_embedder() {
+    let mut interpreter = Interpreter::with_output(Vec::new());
+    interpreter.set_gc_stress(true);
+    interpreter
+        .run_source("class A {} var a = A(); a.self = a;")
+        .unwrap();
+    let (_, instance) = interpreter
+        .globals()
+        .into_iter()
+        .find(|(name, _)| &**name == "a")
+        .unwrap();
+    drop(interpreter);
+
+    let mut interpreter = Interpreter::with_output(Vec::new());
+    interpreter.define_native("instance", 0, move |_| Ok(instance.clone()));
+    interpreter
+        .run_source("print instance().self == instance();")
+        .unwrap();
+    assert_eq!(interpreter.output(), b"true\n");
+}
@@ -0,0 +1,160 @@
+//! Runs every `.lox` file of the `tests` directory and checks its output against the
+//! annotations it contains, in the format of the Crafting Interpreters test suite:
+//!
+//! - `// expect: output` is a line printed on stdout,
+//! - `// expect runtime error: message` is the runtime error the program ends with, on
+//!   the line of the comment,
+//! - `// Error at 'x': message` is a static error on the line of the comment, and
+//!   `// [line N] Error...` one on line `N`. The `[java line N]` variant is accepted too,
+//!   while `[c line N]` is ignored as it's specific to clox.
+//!
+//! The exit code must be 65 when static errors are expected, 70 for a runtime error and
+//! 0 otherwise.
+
+mod common;
+
+use std::{fs, path::Path, process::Command};
+
+#[test]
+fn tree_backend() {
+    run_all("tree");
+}
+
+#[test]
+fn vm_backend() {
+    run_all("vm");
+}
+
+fn run_all(backend: &str) {
+    let files = common::lox_files();
+
+    let failures: Vec<String> = files
+        .iter()
+        .filter_map(|file| {
+            let errors = run(file, backend);
+            (!errors.is_empty()).then(|| format!("{}:\n  {}", file.display(), errors.join("\n  ")))
+        })
+        .collect();
+    assert!(
+        failures.is_empty(),
+        "{} of {} tests failed with the {backend} backend:\n{}",
+        failures.len(),
+        files.len(),
+        failures.join("\n")
+    );
+}
+
+/// What a test expects, read from its comments.
+#[derive(Default)]
+struct Expectations {
+    output: Vec<String>,
+    /// The lines `[line N] Error...` expected on stderr.
+    static_errors: Vec<String>,
+    /// The message and line of the runtime error.
+    runtime_error: Option<(String, usize)>,
+}
+
+impl Expectations {
+    fn parse(source: &str) -> Self {
+        let mut expectations = Self::default();
+        for (index, line) in source.lines().enumerate() {
+            let line_number = index + 1;
+            let Some((_, comment)) = line.split_once("// ") else {
+                continue;
+            };
+            if let Some(output) = comment.strip_prefix("expect: ") {
+                expectations.output.push(output.to_string());
+            } else if let Some(message) = comment.strip_prefix("expect runtime error: ") {
+                expectations.runtime_error = Some((message.to_string(), line_number));
+            } else if comment.starts_with("Error") {
+                expectations
+                    .static_errors
+                    .push(format!("[line {line_number}] {comment}"));
+            } else if let Some(error) = comment
+                .strip_prefix("[line ")
+                .or_else(|| comment.strip_prefix("[java line "))
+            {
+                if error.contains("] Error") {
+                    expectations.static_errors.push(format!("[line {error}"));
+                }
+            }
+        }
+        expectations
+    }
+
+    fn exit_code(&self) -> i32 {
+        if !self.static_errors.is_empty() {
+            65
+        } else if self.runtime_error.is_some() {
+            70
+        } else {
+            0
+        }
+    }
+}
+
+/// Run the test and return the differences with what it expects.
+fn run(file: &Path, backend: &str) -> Vec<String> {
+    let source = fs::read_to_string(file).expect("readable test");
+    let expectations = Expectations::parse(&source);
+    let output = Command::new(env!("CARGO_BIN_EXE_codecrafters-interpreter"))
+        .arg("run")
+        .arg(file)
+        .args(["--backend", backend])
+        .output()
+        .expect("the interpreter runs");
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    let mut errors = Vec::new();
+
+    let stdout: Vec<&str> = stdout.lines().collect();
+    if stdout != expectations.output {
+        errors.push(format!(
+            "expected output {:?}, got {stdout:?}",
+            expectations.output
+        ));
+    }
+
+    // The diagnostics also show the source, only their first line is compared.
+    let static_errors: Vec<&str> = stderr
+        .lines()
+        .filter(|line| line.starts_with("[line ") && line.contains("] Error"))
+        .collect();
+    if static_errors != expectations.static_errors {
+        errors.push(format!(
+            "expected errors {:?}, got {static_errors:?}",
+            expectations.static_errors
+        ));
+    }
+
+    // The diagnostic of a runtime error is its message, then its location.
+    if let Some((message, line)) = &expectations.runtime_error {
+        let mut lines = stderr.lines();
+        let got_message = lines.next().unwrap_or_default();
+        let got_line = lines.next().and_then(location_line);
+        if (got_message, got_line) != (message.as_str(), Some(*line)) {
+            errors.push(format!(
+                "expected runtime error {message:?} on line {line}, got {got_message:?} on line {got_line:?}"
+            ));
+        }
+    }
+
+    let exit_code = output.status.code();
+    if exit_code != Some(expectations.exit_code()) {
+        errors.push(format!(
+            "expected exit code {}, got {exit_code:?}",
+            expectations.exit_code()
+        ));
+    }
+    errors
+}
+
+/// The line of a `--> file:line:column [code]` location, indented by the width of the
+/// line numbers.
+fn location_line(location: &str) -> Option<usize> {
+    let location = location.trim_start().strip_prefix("--> ")?;
+    let (location, _code) = location.rsplit_once(' ')?;
+    let mut parts = location.rsplitn(3, ':');
+    let _column = parts.next()?;
+    parts.next()?.parse().ok()
+}
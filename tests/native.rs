@@ -0,0 +1,69 @@
+//! Runs the native functions of an embedder on both backends.
+
+use std::error::Error;
+
+use lox::{vm::Vm, Interpreter, RunError, Value};
+
+/// `greet(name, times)`, which fails when `times` is negative.
+fn greet(arguments: &[Value]) -> Result<Value, Box<dyn Error>> {
+    let name = String::try_from(&arguments[0])?;
+    let times = f64::try_from(&arguments[1])?;
+    if times < 0.0 {
+        return Err("negative count".into());
+    }
+    Ok(Value::from(
+        format!("Hello, {name}!").repeat(times as usize),
+    ))
+}
+
+const PROGRAM: &str = r#"
+print greet("Lox", 2);
+print greet;
+print clock() > 0;
+"#;
+
+const OUTPUT: &str = "Hello, Lox!Hello, Lox!\n<native fn>\ntrue\n";
+
+#[test]
+fn tree_backend() {
+    let mut interpreter = Interpreter::with_output(Vec::new());
+    interpreter.define_native("greet", 2, greet);
+    interpreter.run_source(PROGRAM).unwrap();
+    assert_eq!(String::from_utf8_lossy(interpreter.output()), OUTPUT);
+
+    let err = interpreter.run_source("greet(1, 1);").unwrap_err();
+    assert_eq!(
+        first_line(&err),
+        "Error in native function 'greet': Expected a string but got a number."
+    );
+}
+
+#[test]
+fn vm_backend() {
+    let mut vm = Vm::with_output(Vec::new());
+    vm.define_native("greet", 2, greet);
+    vm.run_source(PROGRAM).unwrap();
+    assert_eq!(String::from_utf8_lossy(vm.output()), OUTPUT);
+
+    let err = vm.run_source(r#"greet("Lox", -1);"#).unwrap_err();
+    assert_eq!(
+        first_line(&err),
+        "Error in native function 'greet': negative count"
+    );
+    let err = vm.run_source("greet(1);").unwrap_err();
+    assert_eq!(first_line(&err), "Expected 2 arguments but got 1.");
+    // The objects of the heap have no equivalent outside of the machine.
+    let err = vm.run_source(r#"class A {} greet(A, 1);"#).unwrap_err();
+    assert_eq!(
+        first_line(&err),
+        "Error in native function 'greet': Expected a primitive value or native function but got a class."
+    );
+}
+
+fn first_line(err: &RunError) -> String {
+    err.to_string()
+        .lines()
+        .next()
+        .unwrap_or_default()
+        .to_string()
+}